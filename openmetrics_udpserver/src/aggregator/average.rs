@@ -1,13 +1,13 @@
-use crate::processor::ProcessorMetric;
+use crate::processor::{ProcessorMetric, SeriesKey};
 use fnv::FnvHashMap;
 
 pub struct AverageBucket {
-    pub sum: u64,
+    pub sum: f64,
     pub count: u64,
 }
 
 pub struct AggragatorAverageGauge {
-    buffer: FnvHashMap<String, AverageBucket>,
+    buffer: FnvHashMap<SeriesKey, AverageBucket>,
 }
 
 impl AggragatorAverageGauge {
@@ -20,19 +20,17 @@ impl AggragatorAverageGauge {
     pub fn handle(&mut self, metric: &ProcessorMetric) {
         let bucket: &mut AverageBucket = self
             .buffer
-            .entry(metric.name.clone())
-            .or_insert(AverageBucket { sum: 0, count: 0 });
+            .entry(metric.series_key())
+            .or_insert(AverageBucket { sum: 0.0, count: 0 });
 
-        bucket.sum += metric.count;
+        bucket.sum += metric.value;
         bucket.count += 1;
     }
 
-    pub fn reset_and_fetch(&mut self) -> FnvHashMap<String, u64> {
-        let mut buf = FnvHashMap::default();
-        for (k, v) in &self.buffer {
-            buf.insert(k.to_string(), v.sum / v.count);
-        }
-
-        buf
+    pub fn reset_and_fetch(&mut self) -> FnvHashMap<SeriesKey, f64> {
+        ::std::mem::take(&mut self.buffer)
+            .into_iter()
+            .map(|(k, v)| (k, v.sum / v.count as f64))
+            .collect()
     }
 }
@@ -1,3 +1,6 @@
 pub mod average;
 pub mod min;
 pub mod peak;
+pub mod quantile;
+pub mod timing;
+pub mod unique;
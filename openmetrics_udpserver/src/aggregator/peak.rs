@@ -1,8 +1,8 @@
-use crate::processor::ProcessorMetric;
+use crate::processor::{ProcessorMetric, SeriesKey};
 use fnv::FnvHashMap;
 
 pub struct AggragatorPeakGauge {
-    buffer: FnvHashMap<String, u64>,
+    buffer: FnvHashMap<SeriesKey, f64>,
 }
 
 impl AggragatorPeakGauge {
@@ -13,17 +13,17 @@ impl AggragatorPeakGauge {
     }
 
     pub fn handle(&mut self, metric: &ProcessorMetric) {
-        let e: &mut u64 = self
+        let e: &mut f64 = self
             .buffer
-            .entry(metric.name.clone())
-            .or_insert(metric.count);
+            .entry(metric.series_key())
+            .or_insert(metric.value);
 
-        if *e < metric.count {
-            *e = metric.count;
+        if *e < metric.value {
+            *e = metric.value;
         }
     }
 
-    pub fn reset_and_fetch(&mut self) -> FnvHashMap<String, u64> {
+    pub fn reset_and_fetch(&mut self) -> FnvHashMap<SeriesKey, f64> {
         let mut swap_map = FnvHashMap::default();
         ::std::mem::swap(&mut swap_map, &mut self.buffer);
         swap_map
@@ -0,0 +1,97 @@
+use crate::collector::format_f64;
+use crate::config::QuantilesConfig;
+use crate::ddsketch::DDSketch;
+use crate::processor::{ProcessorMetric, SeriesKey};
+use fnv::FnvHashMap;
+
+/// The quantiles of the values of a window together with their sum and count, the samples of a
+/// prometheus summary.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantileSummary {
+    pub sum: f64,
+    pub count: u64,
+    pub quantiles: Vec<(f64, f64)>,
+}
+
+impl QuantileSummary {
+    pub fn new(sketch: &DDSketch, sum: f64, count: u64, quantiles: &[f64]) -> Self {
+        Self {
+            sum,
+            count,
+            quantiles: quantiles
+                .iter()
+                .filter_map(|quantile| Some((*quantile, sketch.quantile(*quantile)?)))
+                .collect(),
+        }
+    }
+
+    /// the quantiles as series of the `<name>_quantile` gauge family, for the sinks without
+    /// summaries.
+    pub fn quantile_gauges(&self, series: &SeriesKey) -> Vec<(SeriesKey, f64)> {
+        self.quantiles
+            .iter()
+            .map(|(quantile, value)| (quantile_series(series, *quantile), *value))
+            .collect()
+    }
+}
+
+/// Keeps a sketch per series of the Average metrics matching the configured prefixes. On flush
+/// every series becomes a summary of the `<name>_quantile` family.
+pub struct AggragatorQuantileGauge {
+    config: QuantilesConfig,
+    buffer: FnvHashMap<SeriesKey, (DDSketch, f64, u64)>,
+}
+
+impl AggragatorQuantileGauge {
+    pub fn new(config: QuantilesConfig) -> Self {
+        Self {
+            config,
+            buffer: FnvHashMap::default(),
+        }
+    }
+
+    pub fn handle(&mut self, metric: &ProcessorMetric) {
+        if !self.config.is_enabled_for(&metric.name) {
+            return;
+        }
+
+        let (sketch, sum, count) = self
+            .buffer
+            .entry(metric.series_key())
+            .or_insert_with(|| (self.config.sketch(), 0.0, 0));
+        sketch.insert(metric.value);
+        *sum += metric.value;
+        *count += 1;
+    }
+
+    /// the summaries of the window, keyed by the series of the average.
+    pub fn reset_and_fetch_summaries(&mut self) -> FnvHashMap<SeriesKey, QuantileSummary> {
+        ::std::mem::take(&mut self.buffer)
+            .into_iter()
+            .map(|(series, (sketch, sum, count))| {
+                let summary = QuantileSummary::new(&sketch, sum, count, &self.config.quantiles);
+                (series, summary)
+            })
+            .collect()
+    }
+
+    pub fn reset_and_fetch(&mut self) -> FnvHashMap<SeriesKey, f64> {
+        self.reset_and_fetch_summaries()
+            .iter()
+            .flat_map(|(series, summary)| summary.quantile_gauges(series))
+            .collect()
+    }
+}
+
+/// the series of a quantile within the `<name>_quantile` family.
+pub fn quantile_series(series: &SeriesKey, quantile: f64) -> SeriesKey {
+    let mut labels = series.labels.clone();
+    labels.retain(|(key, _)| key != "quantile");
+    labels.push(("quantile".to_string(), format_f64(quantile)));
+    labels.sort();
+
+    SeriesKey {
+        name: format!("{}_quantile", series.name),
+        labels,
+    }
+}
@@ -0,0 +1,118 @@
+use crate::aggregator::quantile::QuantileSummary;
+use crate::config::QuantilesConfig;
+use crate::ddsketch::DDSketch;
+use crate::processor::{ProcessorMetric, SeriesKey};
+use fnv::FnvHashMap;
+
+/// The aggregates of the Timing samples of a window, shared by all pipelines so that the derived
+/// series carry the same suffixes everywhere.
+pub struct TimingBucket {
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    /// only kept for metrics matching a quantile prefix.
+    pub sketch: Option<DDSketch>,
+}
+
+impl TimingBucket {
+    pub fn new(sketch: Option<DDSketch>) -> Self {
+        Self {
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sketch,
+        }
+    }
+
+    pub fn insert(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+
+        if let Some(sketch) = &mut self.sketch {
+            sketch.insert(value);
+        }
+    }
+
+    /// the derived values together with the suffix of their series.
+    pub fn aggregates(&self) -> [(&'static str, f64); 5] {
+        [
+            ("count", self.count as f64),
+            ("sum", self.sum),
+            ("min", self.min),
+            ("max", self.max),
+            ("mean", self.sum / self.count as f64),
+        ]
+    }
+}
+
+/// Derives `<name>_count`, `<name>_sum`, `<name>_min`, `<name>_max` and `<name>_mean` of every
+/// window from the Timing samples, plus the summary of the `<name>_quantile` family for a quantile
+/// prefix.
+pub struct AggragatorTimingGauge {
+    quantiles: QuantilesConfig,
+    buffer: FnvHashMap<SeriesKey, TimingBucket>,
+}
+
+impl AggragatorTimingGauge {
+    pub fn new(quantiles: QuantilesConfig) -> Self {
+        Self {
+            quantiles,
+            buffer: FnvHashMap::default(),
+        }
+    }
+
+    pub fn handle(&mut self, metric: &ProcessorMetric) {
+        self.buffer
+            .entry(metric.series_key())
+            .or_insert_with(|| {
+                let sketch = self.quantiles.is_enabled_for(&metric.name);
+                TimingBucket::new(sketch.then(|| self.quantiles.sketch()))
+            })
+            .insert(metric.value);
+    }
+
+    /// the derived aggregates of the window and the summaries of the series with a sketch, keyed
+    /// by the series of the timing.
+    pub fn reset_and_fetch_summaries(
+        &mut self,
+    ) -> (
+        FnvHashMap<SeriesKey, f64>,
+        FnvHashMap<SeriesKey, QuantileSummary>,
+    ) {
+        let mut aggregates = FnvHashMap::default();
+        let mut summaries = FnvHashMap::default();
+
+        for (series, bucket) in ::std::mem::take(&mut self.buffer) {
+            for (suffix, value) in bucket.aggregates() {
+                let key = SeriesKey {
+                    name: format!("{}_{}", series.name, suffix),
+                    labels: series.labels.clone(),
+                };
+                aggregates.insert(key, value);
+            }
+
+            let Some(sketch) = &bucket.sketch else {
+                continue;
+            };
+
+            let summary =
+                QuantileSummary::new(sketch, bucket.sum, bucket.count, &self.quantiles.quantiles);
+            summaries.insert(series, summary);
+        }
+
+        (aggregates, summaries)
+    }
+
+    pub fn reset_and_fetch(&mut self) -> FnvHashMap<SeriesKey, f64> {
+        let (mut aggregates, summaries) = self.reset_and_fetch_summaries();
+        for (series, summary) in &summaries {
+            aggregates.extend(summary.quantile_gauges(series));
+        }
+
+        aggregates
+    }
+}
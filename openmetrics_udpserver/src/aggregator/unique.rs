@@ -0,0 +1,31 @@
+use crate::hyperloglog::HyperLogLog;
+use crate::processor::{ProcessorMetric, SeriesKey};
+use fnv::FnvHashMap;
+
+pub struct AggragatorUniqueGauge {
+    buffer: FnvHashMap<SeriesKey, HyperLogLog>,
+}
+
+impl AggragatorUniqueGauge {
+    pub fn new() -> Self {
+        Self {
+            buffer: FnvHashMap::default(),
+        }
+    }
+
+    pub fn handle(&mut self, metric: &ProcessorMetric) {
+        if let Some(member) = &metric.member {
+            self.buffer
+                .entry(metric.series_key())
+                .or_default()
+                .insert(member);
+        }
+    }
+
+    pub fn reset_and_fetch(&mut self) -> FnvHashMap<SeriesKey, f64> {
+        ::std::mem::take(&mut self.buffer)
+            .into_iter()
+            .map(|(k, v)| (k, v.estimate().round()))
+            .collect()
+    }
+}
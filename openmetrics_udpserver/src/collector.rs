@@ -0,0 +1,174 @@
+use crate::aggregator::quantile::QuantileSummary;
+use crate::processor::MetricLabels;
+use prometheus_client::collector::Collector;
+use prometheus_client::encoding::text::{encode_eof, encode_registry};
+use prometheus_client::encoding::{DescriptorEncoder, EncodeMetric};
+use prometheus_client::registry::Registry;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter, Write};
+use std::sync::{Arc, PoisonError, RwLock};
+
+type BoxedMetric = Box<dyn EncodeMetric + Send + Sync>;
+
+/// The summaries of a family by label set.
+pub type SummaryFamily = Arc<RwLock<BTreeMap<MetricLabels, QuantileSummary>>>;
+
+/// Holds the metric families created by the processor. Other than the append only
+/// [`prometheus_client::registry::Registry`], families can be removed again. It is registered as
+/// collector, so all families are encoded (sorted by name) on every scrape.
+///
+/// prometheus_client cannot encode summaries, they are encoded by [`encode`] after the registry.
+#[derive(Clone, Default)]
+pub struct MetricCollector {
+    families: Arc<RwLock<BTreeMap<String, BoxedMetric>>>,
+    summaries: Arc<RwLock<BTreeMap<String, SummaryFamily>>>,
+}
+
+impl MetricCollector {
+    pub fn insert<M>(&self, name: String, metric: M)
+    where
+        M: EncodeMetric + Send + Sync + 'static,
+    {
+        self.families
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name, Box::new(metric));
+    }
+
+    pub fn insert_summary(&self, name: String, family: SummaryFamily) {
+        self.summaries
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name, family);
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.families
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(name);
+        self.summaries
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(name);
+    }
+
+    /// the summaries in the text format, sorted by name.
+    fn encode_summaries(&self, writer: &mut String) -> Result<(), std::fmt::Error> {
+        let summaries = self
+            .summaries
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        for (name, family) in summaries.iter() {
+            writeln!(writer, "# HELP {} {}", name, name)?;
+            writeln!(writer, "# TYPE {} summary", name)?;
+
+            let family = family.read().unwrap_or_else(PoisonError::into_inner);
+            for (labels, summary) in family.iter() {
+                for (quantile, value) in &summary.quantiles {
+                    let mut labels = labels.clone();
+                    labels.push(("quantile".to_string(), format_f64(*quantile)));
+                    writeln!(
+                        writer,
+                        "{}{} {}",
+                        name,
+                        encode_labels(&labels),
+                        format_f64(*value)
+                    )?;
+                }
+
+                let labels = encode_labels(labels);
+                writeln!(writer, "{}_sum{} {}", name, labels, format_f64(summary.sum))?;
+                writeln!(writer, "{}_count{} {}", name, labels, summary.count)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// encodes the registry like [`prometheus_client::encoding::text::encode`], including the
+/// summaries of the collector.
+pub fn encode(
+    writer: &mut String,
+    registry: &Registry,
+    collector: &MetricCollector,
+) -> Result<(), std::fmt::Error> {
+    encode_registry(writer, registry)?;
+    collector.encode_summaries(writer)?;
+    encode_eof(writer)
+}
+
+fn encode_labels(labels: &MetricLabels) -> String {
+    let labels = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect::<Vec<_>>();
+
+    format!("{{{}}}", labels.join(","))
+}
+
+/// formats a value like prometheus_client does, e.g. `10.0` and `+Inf`.
+pub fn format_f64(value: f64) -> String {
+    match value {
+        f64::INFINITY => "+Inf".to_string(),
+        f64::NEG_INFINITY => "-Inf".to_string(),
+        _ => format!("{:?}", value),
+    }
+}
+
+impl Debug for MetricCollector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetricCollector").finish_non_exhaustive()
+    }
+}
+
+impl Collector for MetricCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        let families = self.families.read().unwrap_or_else(PoisonError::into_inner);
+        for (name, metric) in families.iter() {
+            let metric_encoder =
+                encoder.encode_descriptor(name, name, None, metric.metric_type())?;
+            metric.encode(metric_encoder)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collector::MetricCollector;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::metrics::counter::Counter;
+    use prometheus_client::registry::Registry;
+
+    #[test]
+    fn it_removes_families_from_the_exposition() {
+        let collector = MetricCollector::default();
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(collector.clone()));
+
+        let counter = Counter::<u64>::default();
+        counter.inc_by(3);
+        collector.insert("foo".to_string(), counter);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert_eq!(
+            "# HELP foo foo\n# TYPE foo counter\nfoo_total 3\n# EOF\n",
+            buffer
+        );
+
+        collector.remove("foo");
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert_eq!("# EOF\n", buffer);
+    }
+}
@@ -1,7 +1,695 @@
-#[derive(Clone, Debug)]
+use crate::ddsketch::DDSketch;
+use crate::graphite::GraphiteConfig;
+use crate::influxdb::InfluxDbConfig;
+use crate::otlp::sink::OtlpConfig;
+use crate::remote_write::sink::RemoteWriteConfig;
+use crate::serverdensity::aggregator::ServerDensityConfig;
+use anyhow::{anyhow, Context};
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// The default histogram buckets, the prometheus defaults scaled to milliseconds.
+pub const DEFAULT_HISTOGRAM_BUCKETS: [f64; 11] = [
+    5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+];
+
+/// The effective configuration of the agent. Values are taken from the config file, then from
+/// the environment and then from the command line, each one overriding the previous one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub debug: bool,
     pub udp_bind: String,
     pub http_bind: String,
-    pub disable_serverdensity: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statsd_bind: Option<String>,
+    /// seconds between two flushes of the Average, Peak and Min aggregations.
+    pub flush_interval_secs: u64,
+    /// align all flushes to multiples of their interval since the epoch.
+    pub align_flush: bool,
+    /// number of windows without a value after which an Average, Peak or Min series is stale,
+    /// 0 keeps the last value forever.
+    pub stale_windows: u32,
+    pub stale_policy: StalePolicy,
+    /// seconds without any metric after which a series is evicted, 0 keeps series forever.
+    pub series_ttl_secs: u64,
+    pub histogram_buckets: Vec<HistogramBucketsConfig>,
+    pub quantiles: QuantilesConfig,
+    pub limits: LimitsConfig,
+    pub prometheus: PrometheusConfig,
+    pub serverdensity: ServerDensityConfig,
+    pub remote_write: RemoteWriteConfig,
+    pub graphite: GraphiteConfig,
+    pub influxdb: InfluxDbConfig,
+    pub otlp: OtlpConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            debug: false,
+            udp_bind: "127.0.0.1:1113".to_string(),
+            http_bind: "127.0.0.1:1114".to_string(),
+            statsd_bind: None,
+            flush_interval_secs: 30,
+            align_flush: false,
+            stale_windows: 0,
+            stale_policy: StalePolicy::Remove,
+            series_ttl_secs: 0,
+            histogram_buckets: vec![],
+            quantiles: QuantilesConfig::default(),
+            limits: LimitsConfig::default(),
+            prometheus: PrometheusConfig::default(),
+            serverdensity: ServerDensityConfig::default(),
+            remote_write: RemoteWriteConfig::default(),
+            graphite: GraphiteConfig::default(),
+            influxdb: InfluxDbConfig::default(),
+            otlp: OtlpConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args(matches: &ArgMatches) -> Result<Self, anyhow::Error> {
+        let mut config = match matches.get_one::<String>("config-file") {
+            Some(config_file) => Self::from_file(config_file)?,
+            None => Self::default(),
+        };
+
+        config.apply_args(matches)?;
+        if config.serverdensity.enabled {
+            config.serverdensity.apply_sd_agent_config()?;
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(config_file: &str) -> Result<Self, anyhow::Error> {
+        let content = std::fs::read_to_string(config_file)
+            .with_context(|| format!("could not read config file {}", config_file))?;
+
+        // the error contains the offending key together with line and column
+        toml::from_str(&content).with_context(|| format!("invalid config file {}", config_file))
+    }
+
+    /// applies the values given as argument or environment variable, clap already prefers
+    /// arguments over environment variables.
+    fn apply_args(&mut self, matches: &ArgMatches) -> Result<(), anyhow::Error> {
+        if matches.get_flag("debug") {
+            self.debug = true;
+        }
+
+        if let Some(udp_bind) = matches.get_one::<String>("udp-bind") {
+            self.udp_bind = udp_bind.to_string();
+        }
+
+        if let Some(http_bind) = matches.get_one::<String>("http-bind") {
+            self.http_bind = http_bind.to_string();
+        }
+
+        if let Some(statsd_bind) = matches.get_one::<String>("statsd-bind") {
+            self.statsd_bind = Some(statsd_bind.to_string());
+        }
+
+        if let Some(flush_interval_secs) = matches.get_one::<u64>("flush-interval") {
+            self.flush_interval_secs = *flush_interval_secs;
+        }
+
+        if matches.get_flag("align-flush") {
+            self.align_flush = true;
+        }
+
+        if let Some(stale_windows) = matches.get_one::<u32>("stale-windows") {
+            self.stale_windows = *stale_windows;
+        }
+
+        if let Some(stale_policy) = matches.get_one::<StalePolicy>("stale-policy") {
+            self.stale_policy = *stale_policy;
+        }
+
+        if let Some(series_ttl_secs) = matches.get_one::<u64>("series-ttl") {
+            self.series_ttl_secs = *series_ttl_secs;
+        }
+
+        if let Some(histogram_buckets) = matches.get_many::<String>("histogram-buckets") {
+            self.histogram_buckets = histogram_buckets
+                .map(|value| HistogramBucketsConfig::parse(value))
+                .collect::<Result<Vec<_>, _>>()
+                .context("invalid '--histogram-buckets'")?;
+        }
+
+        if let Some(prefixes) = matches.get_many::<String>("quantile-prefix") {
+            self.quantiles.prefixes = prefixes.map(|prefix| prefix.trim().to_string()).collect();
+        }
+
+        if let Some(quantiles) = matches.get_many::<f64>("quantiles") {
+            self.quantiles.quantiles = quantiles.copied().collect();
+        }
+
+        if let Some(max_series) = matches.get_one::<usize>("max-series") {
+            self.limits.max_series = *max_series;
+        }
+
+        if let Some(series_quotas) = matches.get_many::<String>("series-quota") {
+            self.limits.quotas = series_quotas
+                .map(|value| SeriesQuotaConfig::parse(value))
+                .collect::<Result<Vec<_>, _>>()
+                .context("invalid '--series-quota'")?;
+        }
+
+        if let Some(overflow_policy) = matches.get_one::<OverflowPolicy>("overflow-policy") {
+            self.limits.overflow_policy = *overflow_policy;
+        }
+
+        self.serverdensity.apply_args(matches);
+        self.remote_write.apply_args(matches)?;
+        self.graphite.apply_args(matches);
+        self.influxdb.apply_args(matches)?;
+        self.otlp.apply_args(matches)?;
+
+        if let Some(sinks) = matches.get_many::<String>("disable-sink") {
+            for sink in sinks {
+                match sink.trim() {
+                    "prometheus" => self.prometheus.enabled = false,
+                    "serverdensity" => self.serverdensity.enabled = false,
+                    "remote_write" => self.remote_write.enabled = false,
+                    "graphite" => self.graphite.enabled = false,
+                    "influxdb" => self.influxdb.enabled = false,
+                    "otlp" => self.otlp.enabled = false,
+                    other => {
+                        return Err(anyhow!(
+                            "invalid '--disable-sink': unknown sink '{}', expected one of {:?}",
+                            other,
+                            Self::SINKS
+                        ))
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.enabled_sinks().is_empty() {
+            return Err(anyhow!(
+                "all sinks are disabled, enable at least one of {:?}",
+                Self::SINKS
+            ));
+        }
+
+        validate_socket_addr("udp_bind", &self.udp_bind)?;
+        validate_socket_addr("http_bind", &self.http_bind)?;
+        if let Some(statsd_bind) = &self.statsd_bind {
+            validate_socket_addr("statsd_bind", statsd_bind)?;
+        }
+
+        if self.flush_interval_secs == 0 {
+            return Err(anyhow!("`flush_interval_secs`: must be at least 1 second"));
+        }
+
+        for (i, histogram_buckets) in self.histogram_buckets.iter().enumerate() {
+            histogram_buckets
+                .validate()
+                .with_context(|| format!("invalid `histogram_buckets[{}]`", i))?;
+        }
+
+        self.quantiles.validate().context("invalid `quantiles`")?;
+
+        for (i, quota) in self.limits.quotas.iter().enumerate() {
+            quota
+                .validate()
+                .with_context(|| format!("invalid `limits.quotas[{}]`", i))?;
+        }
+
+        if self.serverdensity.enabled {
+            self.serverdensity
+                .validate()
+                .context("invalid `serverdensity`")?;
+        }
+
+        if self.remote_write.enabled {
+            self.remote_write
+                .validate()
+                .context("invalid `remote_write`")?;
+        }
+
+        if self.graphite.enabled {
+            self.graphite.validate().context("invalid `graphite`")?;
+        }
+
+        if self.influxdb.enabled {
+            self.influxdb.validate().context("invalid `influxdb`")?;
+        }
+
+        if self.otlp.enabled {
+            self.otlp.validate().context("invalid `otlp`")?;
+        }
+
+        Ok(())
+    }
+
+    /// the names of all sinks, each one is configured in the section of its name.
+    pub const SINKS: [&'static str; 6] = [
+        "prometheus",
+        "serverdensity",
+        "remote_write",
+        "graphite",
+        "influxdb",
+        "otlp",
+    ];
+
+    pub fn enabled_sinks(&self) -> Vec<&'static str> {
+        let enabled = [
+            self.prometheus.enabled,
+            self.serverdensity.enabled,
+            self.remote_write.enabled,
+            self.graphite.enabled,
+            self.influxdb.enabled,
+            self.otlp.enabled,
+        ];
+
+        Self::SINKS
+            .into_iter()
+            .zip(enabled)
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// the effective configuration as toml, secrets are redacted.
+    pub fn to_redacted_toml(&self) -> Result<String, anyhow::Error> {
+        let mut config = self.clone();
+        if !config.serverdensity.token.is_empty() {
+            config.serverdensity.token = "<redacted>".to_string();
+        }
+
+        for secret in [
+            &mut config.remote_write.password,
+            &mut config.remote_write.bearer_token,
+            &mut config.influxdb.token,
+        ] {
+            if secret.is_some() {
+                *secret = Some("<redacted>".to_string());
+            }
+        }
+
+        // headers usually carry the credentials of the collector
+        for value in config.otlp.headers.values_mut() {
+            *value = "<redacted>".to_string();
+        }
+
+        toml::to_string_pretty(&config).context("could not serialize config")
+    }
+
+    /// returns the buckets of the longest configured prefix matching the metric name.
+    pub fn histogram_buckets_for(&self, metric_name: &str) -> &[f64] {
+        self.histogram_buckets
+            .iter()
+            .filter(|config| matches_prefix(metric_name, &config.prefix))
+            .max_by_key(|config| config.prefix.len())
+            .map(|config| config.buckets.as_slice())
+            .unwrap_or(&DEFAULT_HISTOGRAM_BUCKETS)
+    }
+}
+
+/// The pull sink, the processor aggregates the metrics into the families served on `/metrics`.
+/// Its windows and series handling are configured by the top level settings.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrometheusConfig {
+    pub enabled: bool,
+}
+
+impl Default for PrometheusConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// What happens to a gauge series which became stale.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StalePolicy {
+    /// the series is kept with the value NaN.
+    Nan,
+    /// the series is removed from the exposition.
+    Remove,
+}
+
+impl FromStr for StalePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "nan" => Ok(Self::Nan),
+            "remove" => Ok(Self::Remove),
+            _ => Err(format!("expected 'nan' or 'remove', got '{}'", value)),
+        }
+    }
+}
+
+/// Average and Timing metrics starting with one of the prefixes additionally get a quantile sketch
+/// per flush window, so that outliers do not disappear in the average. Every sketch keeps at most
+/// `max_buckets` buckets for positive and for negative values.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuantilesConfig {
+    /// matched against the metric name, dots are matched like the underscores they become.
+    pub prefixes: Vec<String>,
+    pub quantiles: Vec<f64>,
+    /// the maximum relative error of an estimated quantile.
+    pub relative_accuracy: f64,
+    pub max_buckets: usize,
+}
+
+impl Default for QuantilesConfig {
+    fn default() -> Self {
+        Self {
+            prefixes: vec![],
+            quantiles: vec![0.5, 0.9, 0.99],
+            relative_accuracy: 0.01,
+            max_buckets: 1024,
+        }
+    }
+}
+
+impl QuantilesConfig {
+    pub fn is_enabled_for(&self, metric_name: &str) -> bool {
+        self.prefixes
+            .iter()
+            .any(|prefix| matches_prefix(metric_name, prefix))
+    }
+
+    pub fn sketch(&self) -> DDSketch {
+        DDSketch::new(self.relative_accuracy, self.max_buckets)
+    }
+
+    /// the suffix of a quantile in the plugin map of ServerDensity, 0.999 becomes `p99.9`.
+    pub fn percentile_suffix(quantile: f64) -> String {
+        format!("p{}", (quantile * 1000.0).round() / 10.0)
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.prefixes.iter().any(|prefix| prefix.is_empty()) {
+            return Err(anyhow!("`prefixes`: must not be empty"));
+        }
+
+        if self.quantiles.is_empty() {
+            return Err(anyhow!("`quantiles`: at least one quantile is required"));
+        }
+
+        if self.quantiles.iter().any(|q| !(0.0..=1.0).contains(q)) {
+            return Err(anyhow!("`quantiles`: must be between 0 and 1"));
+        }
+
+        if !(self.relative_accuracy > 0.0 && self.relative_accuracy < 1.0) {
+            return Err(anyhow!("`relative_accuracy`: must be between 0 and 1"));
+        }
+
+        if self.max_buckets == 0 {
+            return Err(anyhow!("`max_buckets`: must be at least 1"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Limits the number of series the processor creates, so that a single client cannot exhaust the
+/// memory of the agent or blow up the exposition.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// the maximum number of series over all metrics, 0 is unlimited.
+    pub max_series: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub quotas: Vec<SeriesQuotaConfig>,
+}
+
+impl LimitsConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.max_series > 0 || !self.quotas.is_empty()
+    }
+
+    /// returns the index of the longest configured quota prefix matching the metric name.
+    pub fn quota_for(&self, metric_name: &str) -> Option<usize> {
+        self.quotas
+            .iter()
+            .enumerate()
+            .filter(|(_, quota)| matches_prefix(metric_name, &quota.prefix))
+            .max_by_key(|(_, quota)| quota.prefix.len())
+            .map(|(i, _)| i)
+    }
+}
+
+/// What happens to a sample which would create a series beyond a limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// the sample is dropped.
+    #[default]
+    Drop,
+    /// the sample is counted in the `udpagent_overflow` series, labeled with its metric type.
+    Fold,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop" => Ok(Self::Drop),
+            "fold" => Ok(Self::Fold),
+            _ => Err(format!("expected 'drop' or 'fold', got '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SeriesQuotaConfig {
+    /// matched against the metric name, dots are matched like the underscores they become.
+    pub prefix: String,
+    pub max_series: usize,
+}
+
+impl SeriesQuotaConfig {
+    /// parses the `prefix=max_series` notation of the `--series-quota` argument.
+    pub fn parse(value: &str) -> Result<Self, anyhow::Error> {
+        let (prefix, max_series) = value
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected prefix=max_series got '{}'", value))?;
+
+        let config = Self {
+            prefix: prefix.trim().to_string(),
+            max_series: max_series
+                .trim()
+                .parse::<usize>()
+                .with_context(|| format!("invalid max series '{}'", max_series))?,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.prefix.is_empty() {
+            return Err(anyhow!(
+                "`prefix`: must not be empty, use `max_series` instead"
+            ));
+        }
+
+        if self.max_series == 0 {
+            return Err(anyhow!("`max_series`: must be at least 1"));
+        }
+
+        Ok(())
+    }
+}
+
+/// the host may be a host name, it is resolved when binding.
+/// prefixes are matched against the open metrics name as well as against the dotted name the flat
+/// sinks keep, dots are matched like the underscores they become.
+pub fn matches_prefix(metric_name: &str, prefix: &str) -> bool {
+    metric_name
+        .replace('.', "_")
+        .starts_with(&prefix.replace('.', "_"))
+}
+
+fn validate_socket_addr(key: &str, value: &str) -> Result<(), anyhow::Error> {
+    let Some((host, port)) = value.rsplit_once(':') else {
+        return Err(anyhow!("`{}`: expected host:port, got '{}'", key, value));
+    };
+
+    if host.is_empty() || port.parse::<u16>().is_err() {
+        return Err(anyhow!("`{}`: expected host:port, got '{}'", key, value));
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistogramBucketsConfig {
+    /// matched against the metric name, dots are matched like the underscores they become.
+    pub prefix: String,
+    pub buckets: Vec<f64>,
+}
+
+impl HistogramBucketsConfig {
+    /// parses the `prefix=bound,bound,...` notation of the `--histogram-buckets` argument.
+    pub fn parse(value: &str) -> Result<Self, anyhow::Error> {
+        let (prefix, buckets) = value
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected prefix=bound,bound,... got '{}'", value))?;
+
+        let mut buckets = buckets
+            .split(',')
+            .map(|bound| {
+                bound
+                    .trim()
+                    .parse::<f64>()
+                    .with_context(|| format!("invalid histogram bucket bound '{}'", bound))
+            })
+            .collect::<Result<Vec<f64>, _>>()?;
+
+        buckets.sort_by(f64::total_cmp);
+        buckets.dedup();
+
+        let config = Self {
+            prefix: prefix.trim().to_string(),
+            buckets,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.buckets.is_empty() {
+            return Err(anyhow!("`buckets`: at least one bound is required"));
+        }
+
+        if self.buckets.iter().any(|bound| !bound.is_finite()) {
+            return Err(anyhow!("`buckets`: bounds must be finite"));
+        }
+
+        if self.buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(anyhow!("`buckets`: bounds must be strictly ascending"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{
+        Config, HistogramBucketsConfig, QuantilesConfig, SeriesQuotaConfig,
+        DEFAULT_HISTOGRAM_BUCKETS,
+    };
+
+    #[test]
+    fn it_selects_the_longest_prefix() {
+        let config = Config {
+            histogram_buckets: vec![
+                HistogramBucketsConfig::parse("checkout.=1,2").unwrap(),
+                HistogramBucketsConfig::parse("checkout.payment=30, 10,20").unwrap(),
+            ],
+            ..Config::default()
+        };
+
+        assert_eq!(&[1.0, 2.0], config.histogram_buckets_for("checkout_cart"));
+        assert_eq!(
+            &[10.0, 20.0, 30.0],
+            config.histogram_buckets_for("checkout_payment_latency")
+        );
+        assert_eq!(
+            &DEFAULT_HISTOGRAM_BUCKETS,
+            config.histogram_buckets_for("search_latency")
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_buckets() {
+        assert!(HistogramBucketsConfig::parse("checkout").is_err());
+        assert!(HistogramBucketsConfig::parse("checkout=1,a").is_err());
+        assert!(HistogramBucketsConfig::parse("checkout=inf").is_err());
+    }
+
+    #[test]
+    fn it_parses_series_quotas() {
+        let quota = SeriesQuotaConfig::parse("checkout. = 500").unwrap();
+        assert_eq!("checkout.", quota.prefix);
+        assert_eq!(500, quota.max_series);
+
+        assert!(SeriesQuotaConfig::parse("checkout").is_err());
+        assert!(SeriesQuotaConfig::parse("checkout=-1").is_err());
+        assert!(SeriesQuotaConfig::parse("checkout=0").is_err());
+        assert!(SeriesQuotaConfig::parse("=10").is_err());
+    }
+
+    #[test]
+    fn it_matches_quantile_prefixes() {
+        let config = QuantilesConfig {
+            prefixes: vec!["checkout.".to_string()],
+            ..QuantilesConfig::default()
+        };
+
+        assert!(config.is_enabled_for("checkout.latency"));
+        assert!(config.is_enabled_for("checkout_latency"));
+        assert!(!config.is_enabled_for("search.latency"));
+        assert!(config.validate().is_ok());
+
+        let config = QuantilesConfig {
+            quantiles: vec![0.5, 1.5],
+            ..QuantilesConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn it_reads_the_config_file() {
+        let config: Config = toml::from_str(
+            r#"
+            udp_bind = "0.0.0.0:1113"
+
+            [[histogram_buckets]]
+            prefix = "checkout."
+            buckets = [10.0, 50.0]
+
+            [serverdensity]
+            enabled = false
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!("0.0.0.0:1113", config.udp_bind);
+        assert_eq!(Config::default().http_bind, config.http_bind);
+        assert_eq!(vec![10.0, 50.0], config.histogram_buckets[0].buckets);
+        assert!(!config.serverdensity.enabled);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn it_points_at_the_offending_key() {
+        let err = toml::from_str::<Config>("[serverdensity]\ntokn = \"foo\"\n").unwrap_err();
+        assert!(err.to_string().contains("tokn"), "{}", err);
+
+        let config: Config = toml::from_str("http_bind = \"localhost\"").unwrap();
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("`http_bind`"), "{}", err);
+
+        let config: Config =
+            toml::from_str("http_bind = \"localhost:1114\"\n[serverdensity]\nenabled = false")
+                .unwrap();
+        assert!(config.validate().is_ok());
+
+        let config: Config =
+            toml::from_str("[[histogram_buckets]]\nprefix = \"a\"\nbuckets = [2.0, 1.0]").unwrap();
+        let err = format!("{:#}", config.validate().unwrap_err());
+        assert!(err.contains("`histogram_buckets[0]`"), "{}", err);
+    }
 }
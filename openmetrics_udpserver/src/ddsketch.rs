@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+
+/// Estimates quantiles with a bounded relative error (DDSketch). Values are counted in buckets
+/// with exponentially growing bounds, so the memory only depends on the range of the values, and
+/// is capped by `max_buckets`: beyond it the lowest buckets are collapsed, which only affects the
+/// accuracy of the lowest quantiles.
+#[derive(Debug, Clone)]
+pub struct DDSketch {
+    gamma_ln: f64,
+    max_buckets: usize,
+    positive: BTreeMap<i32, u64>,
+    /// keyed by the index of the absolute value.
+    negative: BTreeMap<i32, u64>,
+    zero: u64,
+    count: u64,
+}
+
+/// values closer to zero are counted as zero.
+const MIN_INDEXABLE_VALUE: f64 = 1e-9;
+
+impl DDSketch {
+    pub fn new(relative_accuracy: f64, max_buckets: usize) -> Self {
+        let gamma = (1.0 + relative_accuracy) / (1.0 - relative_accuracy);
+
+        Self {
+            gamma_ln: gamma.ln(),
+            max_buckets: max_buckets.max(1),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero: 0,
+            count: 0,
+        }
+    }
+
+    pub fn insert(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+
+        self.count += 1;
+        if value.abs() < MIN_INDEXABLE_VALUE {
+            self.zero += 1;
+            return;
+        }
+
+        let index = (value.abs().ln() / self.gamma_ln).ceil() as i32;
+        let store = if value > 0.0 {
+            &mut self.positive
+        } else {
+            &mut self.negative
+        };
+
+        *store.entry(index).or_insert(0) += 1;
+        Self::collapse(store, self.max_buckets);
+    }
+
+    /// merges the lowest buckets of the store until it fits into `max_buckets`.
+    fn collapse(store: &mut BTreeMap<i32, u64>, max_buckets: usize) {
+        while store.len() > max_buckets {
+            let (_, lowest_count) = store.pop_first().expect("store is not empty");
+            let (_, next_count) = store.iter_mut().next().expect("store has more buckets");
+            *next_count += lowest_count;
+        }
+    }
+
+    /// the estimated value at the quantile (0 to 1), `None` if the sketch is empty.
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = (quantile.clamp(0.0, 1.0) * (self.count - 1) as f64).floor() as u64;
+        let mut seen = 0;
+
+        // ascending by value: the negative values with the largest absolute value come first
+        for (index, count) in self.negative.iter().rev() {
+            seen += count;
+            if seen > rank {
+                return Some(-self.value_of(*index));
+            }
+        }
+
+        seen += self.zero;
+        if seen > rank {
+            return Some(0.0);
+        }
+
+        for (index, count) in self.positive.iter() {
+            seen += count;
+            if seen > rank {
+                return Some(self.value_of(*index));
+            }
+        }
+
+        self.positive
+            .keys()
+            .next_back()
+            .map(|index| self.value_of(*index))
+    }
+
+    /// the value in the middle of the bucket, its relative distance to the bounds is the accuracy.
+    fn value_of(&self, index: i32) -> f64 {
+        let gamma = self.gamma_ln.exp();
+        2.0 * gamma.powi(index) / (gamma + 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ddsketch::DDSketch;
+
+    #[test]
+    fn it_estimates_quantiles_within_the_relative_accuracy() {
+        let mut sketch = DDSketch::new(0.01, 2048);
+        assert_eq!(None, sketch.quantile(0.5));
+
+        for value in 1..=1000 {
+            sketch.insert(value as f64);
+        }
+
+        for (quantile, expected) in [(0.5, 500.0), (0.9, 900.0), (0.99, 990.0)] {
+            let estimate = sketch.quantile(quantile).unwrap();
+            assert!(
+                (estimate - expected).abs() / expected <= 0.01,
+                "{} estimated as {}",
+                quantile,
+                estimate
+            );
+        }
+    }
+
+    #[test]
+    fn it_orders_negative_values_and_zero() {
+        let mut sketch = DDSketch::new(0.01, 2048);
+        for value in [-10.0, 0.0, 10.0] {
+            sketch.insert(value);
+        }
+
+        assert!((sketch.quantile(0.0).unwrap() + 10.0).abs() < 0.1);
+        assert_eq!(Some(0.0), sketch.quantile(0.5));
+        assert!((sketch.quantile(1.0).unwrap() - 10.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn it_bounds_the_number_of_buckets() {
+        let mut sketch = DDSketch::new(0.01, 10);
+        for value in 1..=100_000 {
+            sketch.insert(value as f64);
+        }
+
+        assert_eq!(10, sketch.positive.len());
+        assert_eq!(100_000, sketch.count);
+        let estimate = sketch.quantile(0.999).unwrap();
+        assert!((estimate - 99_900.0).abs() / 99_900.0 <= 0.01);
+    }
+}
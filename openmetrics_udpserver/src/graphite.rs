@@ -0,0 +1,273 @@
+use crate::config::QuantilesConfig;
+use crate::processor::InboundMetric;
+use crate::serverdensity::WindowedAggregation;
+use crate::sink::Sink;
+use crate::window::{unix_seconds, Window};
+use crate::METRIC_COUNTER_GRAPHITE_DROPPED_LINES;
+use anyhow::anyhow;
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GraphiteConfig {
+    pub enabled: bool,
+    /// the plaintext listener of carbon, e.g. `graphite:2003`.
+    pub address: String,
+    /// prepended to every metric name, e.g. `servers.web-1`.
+    pub prefix: String,
+    /// seconds between two flushes.
+    pub flush_interval_secs: u64,
+    /// lines which could not be sent are kept for the next flush, the oldest are dropped beyond.
+    pub max_queued_lines: usize,
+    pub timeout_secs: u64,
+}
+
+impl Default for GraphiteConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "".to_string(),
+            prefix: "".to_string(),
+            flush_interval_secs: 10,
+            max_queued_lines: 100_000,
+            timeout_secs: 5,
+        }
+    }
+}
+
+impl GraphiteConfig {
+    pub fn apply_args(&mut self, matches: &ArgMatches) {
+        if let Some(address) = matches.get_one::<String>("graphite-address") {
+            self.address = address.to_string();
+            self.enabled = true;
+        }
+
+        if let Some(prefix) = matches.get_one::<String>("graphite-prefix") {
+            self.prefix = prefix.to_string();
+        }
+
+        if let Some(flush_interval_secs) = matches.get_one::<u64>("graphite-interval") {
+            self.flush_interval_secs = *flush_interval_secs;
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        // the address may be a host name, it is resolved on every connect
+        if self.address.rsplit_once(':').is_none() {
+            return Err(anyhow!(
+                "`address`: expected host:port, got '{}'",
+                self.address
+            ));
+        }
+
+        if self.flush_interval_secs == 0 {
+            return Err(anyhow!("`flush_interval_secs`: must be at least 1 second"));
+        }
+
+        if self.max_queued_lines == 0 {
+            return Err(anyhow!("`max_queued_lines`: must be at least 1"));
+        }
+
+        if self.timeout_secs == 0 {
+            return Err(anyhow!("`timeout_secs`: must be at least 1 second"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Sends the windowed aggregation as plaintext lines (`prefix.name value timestamp`) to carbon.
+/// The connection is kept open and reestablished on the next flush after an error, lines which
+/// could not be sent are queued. Sending a line twice is harmless, carbon keeps the last value
+/// per timestamp.
+pub struct GraphiteSink {
+    config: GraphiteConfig,
+    aggregation: WindowedAggregation,
+    queue: VecDeque<String>,
+    connection: Option<TcpStream>,
+}
+
+impl GraphiteSink {
+    pub fn new(config: GraphiteConfig, quantiles: QuantilesConfig) -> Self {
+        Self {
+            config,
+            aggregation: WindowedAggregation::new(quantiles),
+            queue: VecDeque::new(),
+            connection: None,
+        }
+    }
+
+    fn enqueue(&mut self, window: Window) {
+        let timestamp = unix_seconds(window.end);
+        let mut metrics = self.aggregation.flush().into_iter().collect::<Vec<_>>();
+        metrics.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (name, value) in metrics {
+            let line = match self.config.prefix.trim_end_matches('.') {
+                "" => format!("{} {} {}\n", name, value, timestamp),
+                prefix => format!("{}.{} {} {}\n", prefix, name, value, timestamp),
+            };
+            self.queue.push_back(line);
+        }
+
+        let overflow = self
+            .queue
+            .len()
+            .saturating_sub(self.config.max_queued_lines);
+        if overflow > 0 {
+            self.queue.drain(..overflow);
+            METRIC_COUNTER_GRAPHITE_DROPPED_LINES.inc_by(overflow as u64);
+        }
+    }
+
+    async fn send_queue(&mut self) -> Result<(), String> {
+        if self.queue.is_empty() {
+            return Ok(());
+        }
+
+        let limit = Duration::from_secs(self.config.timeout_secs);
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => {
+                let connection = timeout(limit, TcpStream::connect(&self.config.address))
+                    .await
+                    .map_err(|_| format!("timeout connecting to {}", self.config.address))?
+                    .map_err(|err| {
+                        format!("could not connect to {}: {}", self.config.address, err)
+                    })?;
+                self.connection.insert(connection)
+            }
+        };
+
+        let payload = self.queue.iter().map(String::as_str).collect::<String>();
+        let result = match timeout(limit, connection.write_all(payload.as_bytes())).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => Err(format!(
+                "could not send to {}: {}",
+                self.config.address, err
+            )),
+            Err(_) => Err(format!("timeout sending to {}", self.config.address)),
+        };
+
+        match result {
+            Ok(()) => self.queue.clear(),
+            // reconnect on the next flush, the queue is sent again
+            Err(_) => self.connection = None,
+        }
+
+        result
+    }
+}
+
+impl Sink for GraphiteSink {
+    fn name(&self) -> &'static str {
+        "graphite"
+    }
+
+    fn flush_interval(&self) -> Duration {
+        Duration::from_secs(self.config.flush_interval_secs)
+    }
+
+    fn handle(&mut self, metric: InboundMetric) {
+        self.aggregation.handle(&metric);
+    }
+
+    async fn flush(&mut self, window: Window) -> Result<(), String> {
+        self.enqueue(window);
+        self.send_queue().await
+    }
+
+    /// the metrics of the unfinished window are sent as well.
+    async fn shutdown(&mut self, window: Window) -> Result<(), String> {
+        self.flush(window).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::QuantilesConfig;
+    use crate::graphite::{GraphiteConfig, GraphiteSink};
+    use crate::processor::InboundMetric;
+    use crate::sink::Sink;
+    use crate::window::Window;
+    use openmetrics_udpserver_lib::MetricType;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn window(end: u64) -> Window {
+        Window {
+            start: UNIX_EPOCH,
+            end: UNIX_EPOCH + Duration::from_secs(end),
+        }
+    }
+
+    fn metric(metric_type: MetricType, name: &str, value: f64) -> InboundMetric {
+        InboundMetric {
+            name: name.to_string(),
+            value,
+            labels: vec![],
+            metric_type,
+            member: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn it_queues_lines_until_carbon_is_reachable() {
+        // reserve a free port, nobody listens on it for the first flush
+        let address = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut sink = GraphiteSink::new(
+            GraphiteConfig {
+                enabled: true,
+                address: address.to_string(),
+                prefix: "web-1.".to_string(),
+                ..GraphiteConfig::default()
+            },
+            QuantilesConfig::default(),
+        );
+
+        sink.handle(metric(MetricType::Sum, "requests", 2.0));
+        sink.handle(metric(MetricType::Sum, "requests", 3.0));
+        assert!(sink.flush(window(100)).await.is_err());
+
+        let listener = TcpListener::bind(address).await.unwrap();
+        sink.handle(metric(MetricType::Average, "load", 0.5));
+        sink.flush(window(110)).await.unwrap();
+        drop(sink);
+
+        let (mut connection, _) = listener.accept().await.unwrap();
+        let mut received = String::new();
+        connection.read_to_string(&mut received).await.unwrap();
+
+        assert_eq!("web-1.requests 5 100\nweb-1.load 0.5 110\n", received);
+    }
+
+    #[test]
+    fn it_drops_the_oldest_lines_beyond_the_queue_limit() {
+        let mut sink = GraphiteSink::new(
+            GraphiteConfig {
+                max_queued_lines: 2,
+                ..GraphiteConfig::default()
+            },
+            QuantilesConfig::default(),
+        );
+
+        for name in ["a", "b", "c"] {
+            sink.handle(metric(MetricType::Set, name, 1.0));
+        }
+        sink.enqueue(window(100));
+
+        assert_eq!(vec!["b 1 100\n", "c 1 100\n"], Vec::from(sink.queue));
+    }
+}
@@ -1,24 +1,34 @@
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
+use axum::body::{Body, Bytes};
 use axum::extract::State;
-use axum::http::header::ACCEPT;
+use axum::http::header::{ACCEPT, CONTENT_ENCODING, CONTENT_TYPE};
 use axum::http::{HeaderMap, StatusCode};
 use axum::response::{Html, Response};
-use axum::routing::get;
-use axum::{debug_handler, Router};
-use prometheus_client::encoding::text::encode;
+use axum::routing::{get, post};
+use axum::{debug_handler, Json, Router};
+use opentelemetry_proto::tonic::collector::metrics::v1::{
+    ExportMetricsPartialSuccess, ExportMetricsServiceResponse,
+};
 use prometheus_client::registry::Registry;
+use prost::Message;
 use tokio::net::TcpListener;
+use tokio::sync::broadcast::Sender;
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
 
+use crate::collector::{self, MetricCollector};
 use crate::config::Config;
-use crate::METRIC_COUNTER_REQUESTS;
+use crate::ingest::{decode_binary, decode_json, IngestResponse};
+use crate::otlp::receiver::{self, OtlpReceiver};
+use crate::processor::{publish_metric, InboundMetric};
+use crate::{METRIC_COUNTER_ERRORS, METRIC_COUNTER_INGESTED_METRICS, METRIC_COUNTER_REQUESTS};
 
-#[derive(Clone)]
 struct HttpServerState {
     metric_registry: Arc<RwLock<Registry>>,
+    metric_collector: MetricCollector,
+    metric_sender: Sender<InboundMetric>,
+    otlp_receiver: Mutex<OtlpReceiver>,
 }
 
 async fn get_index() -> Html<String> {
@@ -46,7 +56,7 @@ async fn get_metrics(
     let registry = state.metric_registry.read().await;
     let body = {
         let mut buffer = String::new();
-        if encode(&mut buffer, &registry).is_err() {
+        if collector::encode(&mut buffer, &registry, &state.metric_collector).is_err() {
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
         buffer
@@ -62,20 +72,158 @@ async fn get_metrics(
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
+/// accepts a json array of metrics or, as `application/octet-stream`, a package or batch in the
+/// udp format. every metric is accepted or rejected on its own.
+#[debug_handler]
+async fn post_ingest(
+    headers: HeaderMap,
+    State(state): State<Arc<HttpServerState>>,
+    body: Bytes,
+) -> Result<Json<IngestResponse>, (StatusCode, String)> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/json");
+
+    let decoded = if content_type.starts_with("application/json") {
+        decode_json(&body).map_err(|err| (StatusCode::BAD_REQUEST, err))?
+    } else if content_type.starts_with("application/octet-stream") {
+        decode_binary(&body)
+    } else {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("unsupported content type '{}'", content_type),
+        ));
+    };
+
+    let mut response = IngestResponse::default();
+    for decoded in decoded {
+        // rejected and unsent metrics are counted by publish_metric
+        let result = match decoded {
+            Ok(inbound_metric) => publish_metric(&state.metric_sender, inbound_metric),
+            Err(err) => {
+                METRIC_COUNTER_ERRORS.inc();
+                Err(err)
+            }
+        };
+
+        if result.is_ok() {
+            METRIC_COUNTER_INGESTED_METRICS.inc();
+        }
+        response.push(result);
+    }
+
+    Ok(Json(response))
+}
+
+/// the OTLP/HTTP metrics endpoint, OpenTelemetry SDKs can export to the agent like to a
+/// collector. Points which could not be mapped are reported as partial success.
+#[debug_handler]
+async fn post_otlp_metrics(
+    headers: HeaderMap,
+    State(state): State<Arc<HttpServerState>>,
+    body: Bytes,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/x-protobuf");
+    let json = content_type.starts_with("application/json");
+    if !json && !content_type.starts_with("application/x-protobuf") {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("unsupported content type '{}'", content_type),
+        ));
+    }
+
+    if let Some(encoding) = headers.get(CONTENT_ENCODING) {
+        if encoding != "identity" {
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!(
+                    "unsupported content encoding {:?}, send uncompressed",
+                    encoding
+                ),
+            ));
+        }
+    }
+
+    let request = match json {
+        true => receiver::decode_json(&body),
+        false => receiver::decode_protobuf(&body),
+    }
+    .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+
+    let received = state
+        .otlp_receiver
+        .lock()
+        .expect("otlp receiver poisoned")
+        .receive(request);
+
+    let mut rejected = received.rejected_points;
+    let mut errors = received.errors;
+    for inbound_metric in received.metrics {
+        match publish_metric(&state.metric_sender, inbound_metric) {
+            Ok(()) => {
+                METRIC_COUNTER_INGESTED_METRICS.inc();
+            }
+            Err(err) => {
+                rejected += 1;
+                errors.push(err);
+            }
+        }
+    }
+
+    let response = ExportMetricsServiceResponse {
+        partial_success: (rejected > 0).then(|| ExportMetricsPartialSuccess {
+            rejected_data_points: rejected,
+            error_message: match errors.len() {
+                0 | 1 => errors.join(""),
+                n => format!("{} (and {} more)", errors[0], n - 1),
+            },
+        }),
+    };
+
+    let body = match json {
+        true => serde_json::to_vec(&response)
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?,
+        false => response.encode_to_vec(),
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(
+            CONTENT_TYPE,
+            if json {
+                "application/json"
+            } else {
+                "application/x-protobuf"
+            },
+        )
+        .body(Body::from(body))
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
 pub(crate) fn bind(
     config: &Config,
     metric_registry: Arc<RwLock<Registry>>,
+    metric_collector: MetricCollector,
+    metric_sender: Sender<InboundMetric>,
 ) -> JoinHandle<Result<(), std::io::Error>> {
-    let state = Arc::new(HttpServerState { metric_registry });
+    let state = Arc::new(HttpServerState {
+        metric_registry,
+        metric_collector,
+        metric_sender,
+        otlp_receiver: Mutex::new(OtlpReceiver::new()),
+    });
     let router = Router::new()
         .route("/", get(get_index))
         .route("/metrics", get(get_metrics))
+        .route("/ingest", post(post_ingest))
+        .route("/v1/metrics", post(post_otlp_metrics))
         .with_state(state);
 
-    let bind_addr = config
-        .http_bind
-        .parse::<SocketAddr>()
-        .expect("Unable to parse http bind address");
+    let bind_addr = config.http_bind.clone();
     tokio::spawn(async move {
         let listener = TcpListener::bind(bind_addr)
             .await
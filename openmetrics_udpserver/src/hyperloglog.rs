@@ -0,0 +1,89 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// The number of bits of the hash which select the register, 2^12 registers use 4 KiB per sketch
+/// and have a standard error of about 1.6%.
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// Estimates the number of distinct members with a fixed amount of memory, no matter how many
+/// members are added.
+#[derive(Clone)]
+pub struct HyperLogLog {
+    registers: Box<[u8; REGISTERS]>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        Self {
+            registers: Box::new([0; REGISTERS]),
+        }
+    }
+
+    pub fn insert(&mut self, member: &str) {
+        // the hasher uses fixed keys, so the same member always ends up in the same register
+        let mut hasher = DefaultHasher::new();
+        member.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // the remaining bits, with a sentinel bit so that the rank is at most 64 - PRECISION + 1
+        let rest = (hash << PRECISION) | (1 << (PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+
+        if self.registers[index] < rank {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn estimate(&self) -> f64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|register| 2f64.powi(-(*register as i32)))
+            .sum();
+        let estimate = alpha * m * m / sum;
+
+        // small cardinalities are estimated far more accurate by linear counting
+        let empty_registers = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && empty_registers > 0 {
+            return m * (m / empty_registers as f64).ln();
+        }
+
+        estimate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hyperloglog::HyperLogLog;
+
+    #[test]
+    fn it_estimates_distinct_members() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(0.0, hll.estimate());
+
+        for _ in 0..3 {
+            for i in 0..10 {
+                hll.insert(&format!("user-{}", i));
+            }
+        }
+        assert_eq!(10.0, hll.estimate().round());
+
+        let mut hll = HyperLogLog::new();
+        for i in 0..100_000 {
+            hll.insert(&format!("user-{}", i));
+        }
+        let error = (hll.estimate() - 100_000.0).abs() / 100_000.0;
+        assert!(error < 0.05, "error {}", error);
+    }
+}
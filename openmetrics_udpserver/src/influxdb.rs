@@ -0,0 +1,576 @@
+use crate::config::{LimitsConfig, QuantilesConfig};
+use crate::limits::{overflow_labels, Admission, SeriesLimiter, OVERFLOW_SERIES};
+use crate::processor::{InboundMetric, MetricLabels, SeriesKey};
+use crate::serverdensity::WindowedAggregation;
+use crate::sink::{send_request, Retry, SendError, Sink};
+use crate::window::Window;
+use crate::METRIC_COUNTER_INFLUXDB_REQUESTS;
+use anyhow::{anyhow, Context};
+use clap::ArgMatches;
+use fnv::FnvHashSet;
+use openmetrics_udpserver_lib::MetricType;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfluxDbConfig {
+    pub enabled: bool,
+    /// the write api, e.g. `http://influxdb:8086/api/v2/write?org=acme&bucket=metrics` or
+    /// `http://telegraf:8186/write`, or a udp listener, e.g. `udp://telegraf:8089`.
+    pub endpoint: String,
+    /// seconds between two flushes.
+    pub flush_interval_secs: u64,
+    /// added to every line, labels of the metric take precedence.
+    pub tags: BTreeMap<String, String>,
+    /// sent as `Authorization: Token <token>`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// retries of a write which failed with a network error, 408, 429 or 5xx.
+    pub max_retries: u32,
+    /// the delay before the first retry, doubled for every further retry.
+    pub retry_backoff_ms: u64,
+    pub timeout_secs: u64,
+    /// the maximum size of a datagram, lines are split over as many datagrams as needed.
+    pub udp_payload_bytes: usize,
+}
+
+impl Default for InfluxDbConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: "".to_string(),
+            flush_interval_secs: 10,
+            tags: BTreeMap::new(),
+            token: None,
+            max_retries: 3,
+            retry_backoff_ms: 500,
+            timeout_secs: 10,
+            udp_payload_bytes: 512,
+        }
+    }
+}
+
+impl InfluxDbConfig {
+    pub fn apply_args(&mut self, matches: &ArgMatches) -> Result<(), anyhow::Error> {
+        if let Some(endpoint) = matches.get_one::<String>("influxdb-endpoint") {
+            self.endpoint = endpoint.to_string();
+            self.enabled = true;
+        }
+
+        if let Some(flush_interval_secs) = matches.get_one::<u64>("influxdb-interval") {
+            self.flush_interval_secs = *flush_interval_secs;
+        }
+
+        if let Some(tags) = matches.get_many::<String>("influxdb-tag") {
+            for tag in tags {
+                let (key, value) = tag
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("expected key=value got '{}'", tag))
+                    .context("invalid '--influxdb-tag'")?;
+                self.tags
+                    .insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+
+        if let Some(token) = matches.get_one::<String>("influxdb-token") {
+            self.token = Some(token.to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let url = Url::parse(&self.endpoint)
+            .map_err(|err| anyhow!("`endpoint`: invalid url '{}': {}", self.endpoint, err))?;
+
+        match url.scheme() {
+            "http" | "https" => {}
+            "udp" => {
+                if url.host_str().is_none() || url.port().is_none() {
+                    return Err(anyhow!(
+                        "`endpoint`: expected udp://host:port, got '{}'",
+                        self.endpoint
+                    ));
+                }
+
+                if self.token.is_some() {
+                    return Err(anyhow!("`token` can not be used with an udp endpoint"));
+                }
+            }
+            scheme => {
+                return Err(anyhow!(
+                    "`endpoint`: unsupported scheme '{}', expected http, https or udp",
+                    scheme
+                ))
+            }
+        }
+
+        if self.flush_interval_secs == 0 {
+            return Err(anyhow!("`flush_interval_secs`: must be at least 1 second"));
+        }
+
+        if self.timeout_secs == 0 {
+            return Err(anyhow!("`timeout_secs`: must be at least 1 second"));
+        }
+
+        if self.udp_payload_bytes == 0 {
+            return Err(anyhow!("`udp_payload_bytes`: must be at least 1"));
+        }
+
+        if self
+            .tags
+            .iter()
+            .any(|(key, value)| key.is_empty() || value.is_empty())
+        {
+            return Err(anyhow!("`tags`: names and values must not be empty"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes the windowed aggregation as line protocol to InfluxDB or Telegraf. The `plugin.key`
+/// names published to ServerDensity become measurement `plugin` with the field `key`, names
+/// without a plugin go to the measurement `custom`. Labels become tags, every label set is
+/// aggregated on its own.
+///
+/// New series have to fit into the series limits. A series frees its slot at the end of a window,
+/// only delta gauges are kept across windows and keep their slot.
+pub struct InfluxDbSink {
+    config: InfluxDbConfig,
+    aggregations: BTreeMap<MetricLabels, WindowedAggregation>,
+    quantiles: QuantilesConfig,
+    series_limiter: SeriesLimiter,
+    /// the series of the window which are not kept across windows.
+    window_series: FnvHashSet<SeriesKey>,
+    http_client: Client,
+}
+
+impl InfluxDbSink {
+    pub fn new(config: InfluxDbConfig, quantiles: QuantilesConfig, limits: LimitsConfig) -> Self {
+        Self {
+            config,
+            aggregations: BTreeMap::new(),
+            series_limiter: SeriesLimiter::new(limits, quantiles.clone()),
+            quantiles,
+            window_series: FnvHashSet::default(),
+            http_client: Client::new(),
+        }
+    }
+
+    /// one line per measurement and label set, sorted by measurement and tags.
+    pub fn lines(&mut self, timestamp_ns: u128) -> Vec<String> {
+        let mut points: BTreeMap<(String, MetricLabels), BTreeMap<String, f64>> = BTreeMap::new();
+
+        for (labels, aggregation) in &mut self.aggregations {
+            let mut tags = self.config.tags.clone();
+            tags.extend(labels.iter().cloned());
+            let tags = tags
+                .into_iter()
+                .filter(|(key, value)| !key.is_empty() && !value.is_empty())
+                .collect::<Vec<_>>();
+
+            for (name, value) in aggregation.flush() {
+                // line protocol has no representation of NaN and infinity
+                if !value.is_finite() {
+                    continue;
+                }
+
+                let Some((measurement, field)) = Self::split_name(&name) else {
+                    continue;
+                };
+
+                points
+                    .entry((measurement.to_string(), tags.clone()))
+                    .or_default()
+                    .insert(field.to_string(), value);
+            }
+        }
+
+        self.aggregations
+            .retain(|_, aggregation| aggregation.has_state());
+        for series in self.window_series.drain() {
+            self.series_limiter.release(&series);
+        }
+
+        points
+            .into_iter()
+            .map(|((measurement, tags), fields)| {
+                let mut line = escape(&measurement, &[',', ' ']);
+                for (key, value) in tags {
+                    line.push(',');
+                    line.push_str(&escape(&key, &[',', '=', ' ']));
+                    line.push('=');
+                    line.push_str(&escape(&value, &[',', '=', ' ']));
+                }
+
+                let fields = fields
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", escape(key, &[',', '=', ' ']), value))
+                    .collect::<Vec<_>>()
+                    .join(",");
+
+                format!("{} {} {}", line, fields, timestamp_ns)
+            })
+            .collect()
+    }
+
+    /// the same split as `ServerDensityAggregator::create_plugin_map`.
+    fn split_name(name: &str) -> Option<(&str, &str)> {
+        match name.find('.') {
+            Some(index) if index > 0 && index + 1 != name.len() => {
+                Some((&name[..index], &name[index + 1..]))
+            }
+            _ if name.trim() != "" => Some(("custom", name)),
+            _ => None,
+        }
+    }
+
+    /// retries transient failures until `max_retries` or the deadline is reached.
+    pub async fn write(&self, lines: &[String], deadline: Instant) -> Result<(), String> {
+        let retry = Retry {
+            max_retries: self.config.max_retries,
+            backoff: Duration::from_millis(self.config.retry_backoff_ms),
+            deadline: Some(deadline),
+        };
+
+        let udp = self.config.endpoint.starts_with("udp://");
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        retry
+            .run(|| {
+                let timeout = retry.timeout(timeout);
+                async move {
+                    match udp {
+                        true => self.send_udp(lines).await,
+                        false => self.send_http(lines, timeout).await,
+                    }
+                }
+            })
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    async fn send_http(&self, lines: &[String], timeout: Duration) -> Result<(), SendError> {
+        let mut request = self
+            .http_client
+            .post(&self.config.endpoint)
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .timeout(timeout)
+            .body(lines.join("\n"));
+
+        if let Some(token) = &self.config.token {
+            request = request.header("Authorization", format!("Token {}", token));
+        }
+
+        send_request(request, &METRIC_COUNTER_INFLUXDB_REQUESTS)
+            .await
+            .map(|_| ())
+    }
+
+    async fn send_udp(&self, lines: &[String]) -> Result<(), SendError> {
+        let address = self.config.endpoint.trim_start_matches("udp://");
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .map_err(|err| SendError::Transient(err.to_string()))?;
+        socket.connect(address).await.map_err(|err| {
+            Self::count_request("error".to_string());
+            SendError::Transient(format!("could not resolve {}: {}", address, err))
+        })?;
+
+        for datagram in Self::datagrams(lines, self.config.udp_payload_bytes) {
+            if let Err(err) = socket.send(datagram.as_bytes()).await {
+                Self::count_request("error".to_string());
+                return Err(SendError::Transient(err.to_string()));
+            }
+            Self::count_request("sent".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// packs the lines into datagrams of at most `max_bytes`, a longer line is sent on its own.
+    fn datagrams(lines: &[String], max_bytes: usize) -> Vec<String> {
+        let mut datagrams: Vec<String> = vec![];
+        for line in lines {
+            match datagrams.last_mut() {
+                Some(datagram) if datagram.len() + 1 + line.len() <= max_bytes => {
+                    datagram.push('\n');
+                    datagram.push_str(line);
+                }
+                _ => datagrams.push(line.to_string()),
+            }
+        }
+
+        datagrams
+    }
+
+    fn count_request(status: String) {
+        METRIC_COUNTER_INFLUXDB_REQUESTS
+            .get_or_create(&vec![("status", status)])
+            .inc();
+    }
+}
+
+/// escapes the characters with a meaning in line protocol with a backslash.
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+impl Sink for InfluxDbSink {
+    fn name(&self) -> &'static str {
+        "influxdb"
+    }
+
+    fn flush_interval(&self) -> Duration {
+        Duration::from_secs(self.config.flush_interval_secs)
+    }
+
+    fn handle(&mut self, mut metric: InboundMetric) {
+        let Some(name) = WindowedAggregation::normalize_name(&metric.name) else {
+            return;
+        };
+
+        metric.labels.sort();
+        let series = SeriesKey {
+            name,
+            labels: metric.labels.clone(),
+        };
+
+        let sink = self.name();
+        let delta = metric.metric_type == MetricType::Delta;
+        match self
+            .series_limiter
+            .admit_series(sink, series.clone(), metric.metric_type)
+        {
+            Admission::Admitted if self.series_limiter.is_enabled() && !delta => {
+                self.window_series.insert(series);
+            }
+            Admission::Admitted => {}
+            Admission::Dropped => return,
+            Admission::Folded(rule) => {
+                metric.name = OVERFLOW_SERIES.to_string();
+                metric.labels = overflow_labels(rule, metric.metric_type);
+                metric.metric_type = MetricType::Sum;
+                metric.value = 1.0;
+                metric.member = None;
+            }
+        }
+
+        let quantiles = &self.quantiles;
+        self.aggregations
+            .entry(metric.labels.clone())
+            .or_insert_with(|| WindowedAggregation::new(quantiles.clone()))
+            .handle(&metric);
+    }
+
+    async fn flush(&mut self, window: Window) -> Result<(), String> {
+        // retries must not delay the next flush
+        let deadline = Instant::now() + self.flush_interval();
+        let timestamp_ns = window
+            .end
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+
+        let lines = self.lines(timestamp_ns);
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        self.write(&lines, deadline).await.map_err(|e| {
+            format!(
+                "influxdb write failed, dropping {} lines: {}",
+                lines.len(),
+                e
+            )
+        })
+    }
+
+    /// the metrics of the unfinished window are written as well.
+    async fn shutdown(&mut self, window: Window) -> Result<(), String> {
+        self.flush(window).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{LimitsConfig, OverflowPolicy, QuantilesConfig, SeriesQuotaConfig};
+    use crate::influxdb::{InfluxDbConfig, InfluxDbSink};
+    use crate::processor::InboundMetric;
+    use crate::sink::stub::Stub;
+    use crate::sink::Sink;
+    use axum::http::StatusCode;
+    use openmetrics_udpserver_lib::MetricType;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant};
+    use tokio::net::UdpSocket;
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(10)
+    }
+
+    fn metric(
+        metric_type: MetricType,
+        name: &str,
+        value: f64,
+        labels: &[(&str, &str)],
+    ) -> InboundMetric {
+        InboundMetric {
+            name: name.to_string(),
+            value,
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            metric_type,
+            member: None,
+        }
+    }
+
+    fn sink(endpoint: String) -> InfluxDbSink {
+        InfluxDbSink::new(
+            InfluxDbConfig {
+                enabled: true,
+                endpoint,
+                tags: BTreeMap::from([("host".to_string(), "web 1".to_string())]),
+                max_retries: 2,
+                retry_backoff_ms: 1,
+                ..InfluxDbConfig::default()
+            },
+            QuantilesConfig::default(),
+            LimitsConfig::default(),
+        )
+    }
+
+    #[test]
+    fn it_maps_plugin_keys_to_measurements_and_fields() {
+        let mut sink = sink("http://localhost:8086/write".to_string());
+        sink.handle(metric(MetricType::Sum, "nginx.requests", 2.0, &[]));
+        sink.handle(metric(MetricType::Sum, "nginx.requests", 3.0, &[]));
+        sink.handle(metric(MetricType::Set, "nginx.workers", 4.0, &[]));
+        sink.handle(metric(MetricType::Set, "uptime", 10.0, &[]));
+        sink.handle(metric(
+            MetricType::Average,
+            "nginx.latency",
+            0.5,
+            &[("route", "/a,b"), ("host", "web-2")],
+        ));
+
+        assert_eq!(
+            vec![
+                "custom,host=web\\ 1 uptime=10 1000".to_string(),
+                "nginx,host=web\\ 1 requests=5,workers=4 1000".to_string(),
+                "nginx,host=web-2,route=/a\\,b latency=0.5 1000".to_string(),
+            ],
+            sink.lines(1000)
+        );
+        assert!(sink.lines(2000).is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_retries_failed_writes_over_http() {
+        let stub = Stub::new(&[StatusCode::SERVICE_UNAVAILABLE]);
+        let mut sink = sink(stub.serve("/write").await);
+        sink.config.token = Some("secret".to_string());
+        let lines = vec!["a x=1 1".to_string(), "b y=2 1".to_string()];
+        assert_eq!(Ok(()), sink.write(&lines, deadline()).await);
+
+        let requests = stub.requests();
+        assert_eq!(2, requests.len());
+        assert_eq!("Token secret", requests[1].0["authorization"]);
+        assert_eq!("a x=1 1\nb y=2 1", String::from_utf8_lossy(&requests[1].1));
+    }
+
+    #[tokio::test]
+    async fn it_splits_lines_into_datagrams() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut sink = sink(format!("udp://{}", receiver.local_addr().unwrap()));
+        sink.config.udp_payload_bytes = 16;
+
+        let lines = vec![
+            "a x=1 1".to_string(),
+            "b y=2 1".to_string(),
+            "c z=3 1".to_string(),
+        ];
+        assert_eq!(Ok(()), sink.write(&lines, deadline()).await);
+
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(b"a x=1 1\nb y=2 1", &buf[..len]);
+        let len = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(b"c z=3 1", &buf[..len]);
+    }
+
+    #[test]
+    fn it_limits_series_and_drops_idle_label_sets() {
+        let mut sink = InfluxDbSink::new(
+            InfluxDbConfig::default(),
+            QuantilesConfig::default(),
+            LimitsConfig {
+                max_series: 2,
+                overflow_policy: OverflowPolicy::Fold,
+                quotas: vec![],
+            },
+        );
+        sink.handle(metric(MetricType::Delta, "conns", 1.0, &[("host", "a")]));
+        sink.handle(metric(MetricType::Sum, "requests", 1.0, &[("host", "b")]));
+        sink.handle(metric(MetricType::Sum, "requests", 1.0, &[("host", "c")]));
+
+        assert_eq!(
+            vec![
+                "custom,host=a conns=1 1000".to_string(),
+                "custom,host=b requests=1 1000".to_string(),
+                "custom,rule=max_series,type=sum udpagent_overflow=1 1000".to_string(),
+            ],
+            sink.lines(1000)
+        );
+        assert_eq!(1, sink.aggregations.len());
+
+        // the slot of requests{host=b} is free again
+        sink.handle(metric(MetricType::Sum, "requests", 1.0, &[("host", "c")]));
+        assert_eq!(
+            vec![
+                "custom,host=a conns=1 2000".to_string(),
+                "custom,host=c requests=1 2000".to_string(),
+            ],
+            sink.lines(2000)
+        );
+    }
+    #[test]
+    fn it_applies_the_quotas_of_dotted_prefixes() {
+        let mut sink = InfluxDbSink::new(
+            InfluxDbConfig::default(),
+            QuantilesConfig::default(),
+            LimitsConfig {
+                max_series: 0,
+                overflow_policy: OverflowPolicy::Fold,
+                quotas: vec![SeriesQuotaConfig::parse("checkout.=1").unwrap()],
+            },
+        );
+        sink.handle(metric(MetricType::Sum, "checkout.cart", 2.0, &[]));
+        sink.handle(metric(MetricType::Sum, "checkout.payment", 3.0, &[]));
+        sink.handle(metric(MetricType::Sum, "search.query", 4.0, &[]));
+
+        assert_eq!(
+            vec![
+                "checkout cart=2 1000".to_string(),
+                "custom,rule=checkout.,type=sum udpagent_overflow=1 1000".to_string(),
+                "search query=4 1000".to_string(),
+            ],
+            sink.lines(1000)
+        );
+    }
+}
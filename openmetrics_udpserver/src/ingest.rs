@@ -0,0 +1,159 @@
+use crate::processor::InboundMetric;
+use crate::udp_server::UdpServer;
+use openmetrics_udpserver_lib::MetricType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A single metric of a `POST /ingest` request.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IngestMetric {
+    #[serde(rename = "type")]
+    pub metric_type: String,
+    pub name: String,
+    /// required for all types but `unique`.
+    pub value: Option<f64>,
+    /// the member of a `unique` metric, e.g. a user id.
+    pub member: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl IngestMetric {
+    pub fn into_inbound(self) -> Result<InboundMetric, String> {
+        let metric_type = match self.metric_type.as_str() {
+            "sum" => MetricType::Sum,
+            "average" => MetricType::Average,
+            "peak" => MetricType::Peak,
+            "min" => MetricType::Min,
+            "histogram" => MetricType::Histogram,
+            "set" => MetricType::Set,
+            "delta" => MetricType::Delta,
+            "unique" => MetricType::Unique,
+            "timing" => MetricType::Timing,
+            other => return Err(format!("unsupported metric type '{}'", other)),
+        };
+
+        if self.name.is_empty() {
+            return Err("got empty metric name".to_string());
+        }
+
+        let value = match (metric_type, self.value) {
+            (MetricType::Unique, _) => 1.0,
+            (_, Some(value)) => value,
+            (_, None) => return Err("missing field `value`".to_string()),
+        };
+
+        Ok(InboundMetric {
+            name: self.name,
+            value,
+            labels: self.labels.into_iter().collect(),
+            metric_type,
+            member: self.member,
+        })
+    }
+}
+
+/// The outcome of every metric of a request, in the order of the request.
+#[derive(Debug, Default, Serialize)]
+pub struct IngestResponse {
+    pub accepted: usize,
+    pub rejected: usize,
+    pub results: Vec<IngestResult>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum IngestResult {
+    Accepted,
+    Rejected { error: String },
+}
+
+impl IngestResponse {
+    pub fn push(&mut self, result: Result<(), String>) {
+        match result {
+            Ok(()) => {
+                self.accepted += 1;
+                self.results.push(IngestResult::Accepted);
+            }
+            Err(error) => {
+                self.rejected += 1;
+                self.results.push(IngestResult::Rejected { error });
+            }
+        }
+    }
+}
+
+/// decodes a json array of metrics. an invalid metric is returned as error without rejecting the
+/// others, only a body which is not a json array is rejected as a whole.
+pub fn decode_json(body: &[u8]) -> Result<Vec<Result<InboundMetric, String>>, String> {
+    let items = serde_json::from_slice::<Vec<serde_json::Value>>(body)
+        .map_err(|err| format!("expected a json array of metrics: {}", err))?;
+
+    Ok(items
+        .into_iter()
+        .map(|item| {
+            serde_json::from_value::<IngestMetric>(item)
+                .map_err(|err| err.to_string())
+                .and_then(IngestMetric::into_inbound)
+        })
+        .collect())
+}
+
+/// decodes a single package or a batch, exactly like a datagram of the udp server.
+pub fn decode_binary(body: &[u8]) -> Vec<Result<InboundMetric, String>> {
+    UdpServer::decode_datagram(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ingest::{decode_binary, decode_json};
+    use openmetrics_udpserver_lib::{MetricType, PackageBatch};
+
+    #[test]
+    fn it_decodes_json_metrics_one_by_one() {
+        let decoded = decode_json(
+            br#"[
+                {"type": "sum", "name": "cron.runs", "value": 1, "labels": {"job": "cleanup"}},
+                {"type": "average", "name": "cron.duration", "value": 12.6},
+                {"type": "unknown", "name": "foo", "value": 1},
+                {"type": "sum", "name": "foo", "value": "1"},
+                {"type": "sum", "value": 1},
+                {"type": "unique", "name": "visitors", "member": "alice"}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(6, decoded.len());
+
+        let runs = decoded[0].as_ref().unwrap();
+        assert_eq!(MetricType::Sum, runs.metric_type);
+        assert_eq!("cron.runs", runs.name);
+        assert_eq!(
+            vec![("job".to_string(), "cleanup".to_string())],
+            runs.labels
+        );
+
+        assert_eq!(12.6, decoded[1].as_ref().unwrap().value);
+        assert!(decoded[2].as_ref().unwrap_err().contains("unsupported"));
+        assert!(decoded[3].as_ref().unwrap_err().contains("invalid type"));
+        assert!(decoded[4].as_ref().unwrap_err().contains("name"));
+        assert_eq!(
+            Some("alice".to_string()),
+            decoded[5].as_ref().unwrap().member
+        );
+
+        assert!(decode_json(br#"{"type": "sum"}"#).is_err());
+    }
+
+    #[test]
+    fn it_decodes_binary_batches() {
+        let mut batch = PackageBatch::new();
+        batch.add(MetricType::Sum, "foo", 1).unwrap();
+        batch.add(MetricType::Min, "bar", 2).unwrap();
+
+        let decoded = decode_binary(&batch.finish()[0]);
+        assert_eq!(2, decoded.len());
+        assert_eq!("bar", decoded[1].as_ref().unwrap().name);
+    }
+}
@@ -0,0 +1,234 @@
+use crate::config::{LimitsConfig, OverflowPolicy, QuantilesConfig};
+use crate::processor::{MetricLabels, ProcessorMetric, SeriesKey};
+use crate::METRIC_COUNTER_LIMITED_SAMPLES;
+use openmetrics_udpserver_lib::MetricType;
+
+/// The series folded samples are counted in, labeled with the rule and the type of the sample. It
+/// is a Sum in every sink, so it never conflicts with itself.
+pub const OVERFLOW_SERIES: &str = "udpagent_overflow";
+
+pub fn overflow_labels(rule: String, metric_type: MetricType) -> MetricLabels {
+    vec![
+        ("rule".to_string(), rule),
+        (
+            "type".to_string(),
+            format!("{:?}", metric_type).to_lowercase(),
+        ),
+    ]
+}
+
+/// What happens with the sample of a series.
+#[derive(Debug, PartialEq)]
+pub enum Admission {
+    Admitted,
+    Dropped,
+    /// recorded in the overflow series, the rule is `max_series` or the prefix of the quota.
+    Folded(String),
+}
+
+/// Keeps track of the series created by a sink and decides whether a new series fits into
+/// the global limit and the quota of its prefix. Known series are always admitted.
+pub struct SeriesLimiter {
+    config: LimitsConfig,
+    quantiles: QuantilesConfig,
+    /// all admitted series together with the index of the quota they count towards and the number
+    /// of series they create.
+    series: ::fnv::FnvHashMap<SeriesKey, (Option<usize>, usize)>,
+    series_count: usize,
+    quota_series: Vec<usize>,
+}
+
+impl SeriesLimiter {
+    pub fn new(config: LimitsConfig, quantiles: QuantilesConfig) -> Self {
+        let quota_series = vec![0; config.quotas.len()];
+
+        Self {
+            config,
+            quantiles,
+            series: ::fnv::FnvHashMap::default(),
+            series_count: 0,
+            quota_series,
+        }
+    }
+
+    /// the number of series a metric creates. Timing derives five aggregates, Average and Timing
+    /// metrics matching a quantile prefix additionally create one series per quantile.
+    pub fn series_count_of(&self, metric_type: MetricType, metric_name: &str) -> usize {
+        let quantiles = match self.quantiles.is_enabled_for(metric_name) {
+            true => self.quantiles.quantiles.len(),
+            false => 0,
+        };
+
+        match metric_type {
+            MetricType::Timing => 5 + quantiles,
+            MetricType::Average => 1 + quantiles,
+            _ => 1,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.is_enabled()
+    }
+
+    /// admits the series or returns the rule which would be exceeded, either `max_series` or the
+    /// prefix of the quota.
+    pub fn admit(&mut self, series: SeriesKey, count: usize) -> Result<(), String> {
+        if self.series.contains_key(&series) {
+            return Ok(());
+        }
+
+        if self.config.max_series > 0 && self.series_count + count > self.config.max_series {
+            return Err("max_series".to_string());
+        }
+
+        let quota = self.config.quota_for(&series.name);
+        if let Some(i) = quota {
+            if self.quota_series[i] + count > self.config.quotas[i].max_series {
+                return Err(self.config.quotas[i].prefix.clone());
+            }
+
+            self.quota_series[i] += count;
+        }
+
+        self.series_count += count;
+        self.series.insert(series, (quota, count));
+        Ok(())
+    }
+
+    /// admits the series, a sample beyond the limits is counted for the sink and either dropped or
+    /// folded into the overflow series of the returned rule.
+    pub fn admit_series(
+        &mut self,
+        sink: &'static str,
+        series: SeriesKey,
+        metric_type: MetricType,
+    ) -> Admission {
+        if !self.is_enabled() {
+            return Admission::Admitted;
+        }
+
+        let count = self.series_count_of(metric_type, &series.name);
+        let Err(rule) = self.admit(series, count) else {
+            return Admission::Admitted;
+        };
+
+        let overflow_policy = self.config.overflow_policy;
+        METRIC_COUNTER_LIMITED_SAMPLES
+            .get_or_create(&vec![
+                ("sink", sink.to_string()),
+                ("rule", rule.clone()),
+                ("policy", format!("{:?}", overflow_policy).to_lowercase()),
+            ])
+            .inc();
+
+        match overflow_policy {
+            OverflowPolicy::Drop => Admission::Dropped,
+            OverflowPolicy::Fold => Admission::Folded(rule),
+        }
+    }
+
+    /// like `admit_series`, `None` if the metric is dropped.
+    pub fn admit_metric(
+        &mut self,
+        sink: &'static str,
+        mut metric: ProcessorMetric,
+    ) -> Option<ProcessorMetric> {
+        match self.admit_series(sink, metric.series_key(), metric.metric_type) {
+            Admission::Admitted => Some(metric),
+            Admission::Dropped => None,
+            Admission::Folded(rule) => {
+                metric.fold_into_overflow(rule);
+                Some(metric)
+            }
+        }
+    }
+
+    /// frees the slot of a series which was removed from the exposition.
+    pub fn release(&mut self, series: &SeriesKey) {
+        let Some((quota, count)) = self.series.remove(series) else {
+            return;
+        };
+
+        self.series_count -= count;
+        if let Some(i) = quota {
+            self.quota_series[i] -= count;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{LimitsConfig, OverflowPolicy, QuantilesConfig, SeriesQuotaConfig};
+    use crate::limits::{Admission, SeriesLimiter};
+    use crate::processor::SeriesKey;
+    use openmetrics_udpserver_lib::MetricType;
+
+    fn series(name: &str) -> SeriesKey {
+        SeriesKey {
+            name: name.to_string(),
+            labels: vec![],
+        }
+    }
+
+    #[test]
+    fn it_enforces_the_limit_and_the_quotas() {
+        let mut limiter = SeriesLimiter::new(
+            LimitsConfig {
+                max_series: 3,
+                overflow_policy: OverflowPolicy::Drop,
+                quotas: vec![
+                    SeriesQuotaConfig::parse("checkout.=1").unwrap(),
+                    SeriesQuotaConfig::parse("checkout.payment.=2").unwrap(),
+                ],
+            },
+            QuantilesConfig::default(),
+        );
+
+        assert_eq!(Ok(()), limiter.admit(series("checkout_cart"), 1));
+        assert_eq!(Ok(()), limiter.admit(series("checkout_cart"), 1));
+        assert_eq!(
+            Err("checkout.".to_string()),
+            limiter.admit(series("checkout_shipping"), 1)
+        );
+        assert_eq!(Ok(()), limiter.admit(series("checkout_payment_paypal"), 1));
+        assert_eq!(Ok(()), limiter.admit(series("search"), 1));
+        assert_eq!(
+            Err("max_series".to_string()),
+            limiter.admit(series("checkout_payment_card"), 1)
+        );
+
+        limiter.release(&series("checkout_cart"));
+        assert_eq!(Ok(()), limiter.admit(series("checkout_shipping"), 1));
+    }
+    #[test]
+    fn it_counts_the_derived_series_of_timing() {
+        let mut limiter = SeriesLimiter::new(
+            LimitsConfig {
+                max_series: 6,
+                overflow_policy: OverflowPolicy::Drop,
+                quotas: vec![],
+            },
+            QuantilesConfig {
+                prefixes: vec!["db.".to_string()],
+                quantiles: vec![0.5],
+                ..QuantilesConfig::default()
+            },
+        );
+
+        // count, sum, min, max, mean and the median
+        assert_eq!(
+            Admission::Admitted,
+            limiter.admit_series("test", series("db_query"), MetricType::Timing)
+        );
+        assert_eq!(
+            Admission::Dropped,
+            limiter.admit_series("test", series("search"), MetricType::Sum)
+        );
+
+        limiter.release(&series("db_query"));
+        assert_eq!(
+            Admission::Admitted,
+            limiter.admit_series("test", series("search"), MetricType::Sum)
+        );
+    }
+}
@@ -1,27 +1,84 @@
 mod aggregator;
+mod collector;
 mod config;
+mod ddsketch;
+mod graphite;
 mod http_server;
+mod hyperloglog;
+mod influxdb;
+mod ingest;
+mod limits;
+mod otlp;
 mod processor;
+mod remote_write;
+mod series;
 mod serverdensity;
+mod sink;
+mod statsd_server;
 mod udp_server;
+mod window;
 
-use crate::config::Config;
+use crate::collector::MetricCollector;
+use crate::config::{Config, OverflowPolicy, StalePolicy};
+use crate::graphite::GraphiteSink;
+use crate::influxdb::InfluxDbSink;
+use crate::otlp::sink::{OtlpProtocol, OtlpSink, OtlpTemporality};
 use crate::processor::{InboundMetric, Processor};
-use crate::serverdensity::aggregator::{ServerDensityAggregator, ServerDensityConfig};
+use crate::remote_write::sink::RemoteWriteSink;
+use crate::serverdensity::aggregator::ServerDensityAggregator;
+use crate::sink::SinkTasks;
+use crate::statsd_server::StatsdServer;
 use crate::udp_server::UdpServer;
 use anyhow::{anyhow, Context};
 use clap::{Arg, ArgAction, Command};
 use once_cell::sync::Lazy;
 use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
 use prometheus_client::registry::Registry;
 use std::process::exit;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::broadcast::channel;
 use tokio::sync::RwLock;
 
 pub static METRIC_COUNTER_REQUESTS: Lazy<Counter<u64>> = Lazy::new(Default::default);
 pub static METRIC_COUNTER_ERRORS: Lazy<Counter<u64>> = Lazy::new(Default::default);
 pub static METRIC_COUNTER_UDP_PACKETS: Lazy<Counter<u64>> = Lazy::new(Default::default);
+pub static METRIC_COUNTER_UDP_METRICS: Lazy<Counter<u64>> = Lazy::new(Default::default);
+pub static METRIC_COUNTER_STATSD_METRICS: Lazy<Counter<u64>> = Lazy::new(Default::default);
+pub static METRIC_COUNTER_INGESTED_METRICS: Lazy<Counter<u64>> = Lazy::new(Default::default);
+pub static METRIC_COUNTER_EVICTED_SERIES: Lazy<Counter<u64>> = Lazy::new(Default::default);
+pub static METRIC_COUNTER_TYPE_CONFLICTS: Lazy<Counter<u64>> = Lazy::new(Default::default);
+pub static METRIC_COUNTER_REJECTED_SAMPLES: Lazy<Family<Vec<(&str, &str)>, Counter>> =
+    Lazy::new(Default::default);
+pub static METRIC_COUNTER_LIMITED_SAMPLES: Lazy<Family<Vec<(&str, String)>, Counter>> =
+    Lazy::new(Default::default);
+pub static METRIC_COUNTER_REMOTE_WRITE_REQUESTS: Lazy<Family<Vec<(&str, String)>, Counter>> =
+    Lazy::new(Default::default);
+pub static METRIC_COUNTER_GRAPHITE_DROPPED_LINES: Lazy<Counter<u64>> = Lazy::new(Default::default);
+pub static METRIC_COUNTER_INFLUXDB_REQUESTS: Lazy<Family<Vec<(&str, String)>, Counter>> =
+    Lazy::new(Default::default);
+pub static METRIC_COUNTER_OTLP_REQUESTS: Lazy<Family<Vec<(&str, String)>, Counter>> =
+    Lazy::new(Default::default);
+pub static METRIC_COUNTER_OTLP_TYPE_CONFLICTS: Lazy<Counter<u64>> = Lazy::new(Default::default);
+pub static METRIC_COUNTER_SERVERDENSITY_RETRIES: Lazy<Counter<u64>> = Lazy::new(Default::default);
+pub static METRIC_COUNTER_SERVERDENSITY_DROPPED_PAYLOADS: Lazy<Family<Vec<(&str, &str)>, Counter>> =
+    Lazy::new(Default::default);
+pub static METRIC_COUNTER_SERVERDENSITY_REQUESTS: Lazy<Family<Vec<(&str, String)>, Counter>> =
+    Lazy::new(Default::default);
+pub static METRIC_GAUGE_SERVERDENSITY_HEALTH: Lazy<Family<Vec<(&str, &str)>, Gauge>> =
+    Lazy::new(Default::default);
+pub static METRIC_GAUGE_SERVERDENSITY_QUEUE_PAYLOADS: Lazy<Gauge> = Lazy::new(Default::default);
+pub static METRIC_GAUGE_SERVERDENSITY_QUEUE_BYTES: Lazy<Gauge> = Lazy::new(Default::default);
+pub static METRIC_COUNTER_SINK_FLUSH_FAILURES: Lazy<Family<Vec<(&str, &str)>, Counter>> =
+    Lazy::new(Default::default);
+pub static METRIC_GAUGE_SINK_UP: Lazy<Family<Vec<(&str, &str)>, Gauge>> =
+    Lazy::new(Default::default);
+pub static METRIC_GAUGE_WINDOW_START: Lazy<Family<Vec<(&str, &str)>, Gauge>> =
+    Lazy::new(Default::default);
+pub static METRIC_GAUGE_WINDOW_END: Lazy<Family<Vec<(&str, &str)>, Gauge>> =
+    Lazy::new(Default::default);
 
 const VERSION: Option<&str> = option_env!("CARGO_PKG_VERSION");
 
@@ -31,18 +88,38 @@ async fn main() -> anyhow::Result<(), anyhow::Error> {
     let matches = Command::new("Prometheus UDP Monitor")
         .version(version)
         .about("UDP Sender for Prometheus")
+        .arg(
+            Arg::new("config-file")
+                .long("config-file")
+                .env("UDPAGENT_CONFIG_FILE")
+                .help("path to the TOML config file of the agent, arguments and environment variables take precedence.")
+                .required(false),
+        )
+        .arg(
+            Arg::new("print-config")
+                .long("print-config")
+                .help("print the effective configuration (secrets redacted) and exit")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("udp-bind")
                 .long("udp-bind")
-                .default_value("127.0.0.1:1113")
-                .help("UDP Server Bind Address.")
+                .env("UDPAGENT_UDP_BIND")
+                .help("UDP Server Bind Address. [default: 127.0.0.1:1113]")
                 .required(false),
         )
         .arg(
             Arg::new("http-bind")
                 .long("http-bind")
-                .default_value("127.0.0.1:1114")
-                .help("HTTP Server Bind Address.")
+                .env("UDPAGENT_HTTP_BIND")
+                .help("HTTP Server Bind Address. [default: 127.0.0.1:1114]")
+                .required(false),
+        )
+        .arg(
+            Arg::new("statsd-bind")
+                .long("statsd-bind")
+                .env("UDPAGENT_STATSD_BIND")
+                .help("StatsD Server Bind Address, the StatsD listener is disabled if not given.")
                 .required(false),
         )
         .arg(
@@ -50,57 +127,393 @@ async fn main() -> anyhow::Result<(), anyhow::Error> {
                 .short('v')
                 .help("verbose mode, just for debugging")
                 .long("debug")
+                .env("UDPAGENT_DEBUG")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("flush-interval")
+                .long("flush-interval")
+                .env("UDPAGENT_FLUSH_INTERVAL")
+                .help("Seconds between two flushes of the Average, Peak and Min metrics. [default: 30]")
+                .value_parser(clap::value_parser!(u64))
+                .required(false),
+        )
+        .arg(
+            Arg::new("align-flush")
+                .long("align-flush")
+                .env("UDPAGENT_ALIGN_FLUSH")
+                .help("Align flushes to multiples of the flush interval since the epoch, so that windows of different hosts line up")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("stale-windows")
+                .long("stale-windows")
+                .env("UDPAGENT_STALE_WINDOWS")
+                .help("Number of flush windows without a value after which an Average, Peak or Min series is stale, 0 keeps the last value forever. [default: 0]")
+                .value_parser(clap::value_parser!(u32))
+                .required(false),
+        )
+        .arg(
+            Arg::new("stale-policy")
+                .long("stale-policy")
+                .env("UDPAGENT_STALE_POLICY")
+                .help("What happens to stale series, either set to NaN or removed. [default: remove]")
+                .value_parser(clap::value_parser!(StalePolicy))
+                .required(false),
+        )
+        .arg(
+            Arg::new("series-ttl")
+                .long("series-ttl")
+                .env("UDPAGENT_SERIES_TTL")
+                .help("Seconds without any metric after which a series is removed from the exposition, 0 keeps series forever. [default: 0]")
+                .value_parser(clap::value_parser!(u64))
+                .required(false),
+        )
+        .arg(
+            Arg::new("max-series")
+                .long("max-series")
+                .env("UDPAGENT_MAX_SERIES")
+                .help("Maximum number of series over all metrics, 0 is unlimited. [default: 0]")
+                .value_parser(clap::value_parser!(usize))
+                .required(false),
+        )
+        .arg(
+            Arg::new("series-quota")
+                .long("series-quota")
+                .env("UDPAGENT_SERIES_QUOTA")
+                .help("Maximum number of series for metrics starting with a prefix, e.g. 'checkout.=500'. Can be given multiple times (or separated by ';'), the longest matching prefix wins.")
+                .action(ArgAction::Append)
+                .value_delimiter(';')
+                .required(false),
+        )
+        .arg(
+            Arg::new("overflow-policy")
+                .long("overflow-policy")
+                .env("UDPAGENT_OVERFLOW_POLICY")
+                .help("What happens to samples beyond a series limit, either dropped or counted in the udpagent_overflow series. [default: drop]")
+                .value_parser(clap::value_parser!(OverflowPolicy))
+                .required(false),
+        )
+        .arg(
+            Arg::new("histogram-buckets")
+                .long("histogram-buckets")
+                .env("UDPAGENT_HISTOGRAM_BUCKETS")
+                .help("Histogram bucket bounds for metrics starting with a prefix, e.g. 'checkout.=10,50,100'. Can be given multiple times (or separated by ';'), the longest matching prefix wins.")
+                .action(ArgAction::Append)
+                .value_delimiter(';')
+                .required(false),
+        )
+        .arg(
+            Arg::new("quantile-prefix")
+                .long("quantile-prefix")
+                .env("UDPAGENT_QUANTILE_PREFIX")
+                .help("Export quantiles of Average metrics starting with a prefix, e.g. 'checkout.'. Can be given multiple times (or separated by ';').")
+                .action(ArgAction::Append)
+                .value_delimiter(';')
+                .required(false),
+        )
+        .arg(
+            Arg::new("quantiles")
+                .long("quantiles")
+                .env("UDPAGENT_QUANTILES")
+                .help("Comma separated quantiles exported for the Average metrics of a quantile prefix. [default: 0.5,0.9,0.99]")
+                .value_parser(clap::value_parser!(f64))
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .required(false),
+        )
+        .arg(
+            Arg::new("disable-sink")
+                .long("disable-sink")
+                .env("UDPAGENT_DISABLE_SINK")
+                .help("Disable sinks by name: prometheus, serverdensity, remote_write, graphite, influxdb or otlp. Can be given multiple times (or separated by ',').")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .required(false),
+        )
+        // ---- Remote Write Args
+        .arg(
+            Arg::new("remote-write-endpoint")
+                .long("remote-write-endpoint")
+                .env("UDPAGENT_REMOTE_WRITE_ENDPOINT")
+                .help("Push metrics to this prometheus remote write endpoint, e.g. http://mimir:9009/api/v1/push. The push is disabled if not given.")
+                .required(false),
+        )
+        .arg(
+            Arg::new("remote-write-interval")
+                .long("remote-write-interval")
+                .env("UDPAGENT_REMOTE_WRITE_INTERVAL")
+                .help("Seconds between two remote write pushes. [default: 15]")
+                .value_parser(clap::value_parser!(u64))
+                .required(false),
+        )
+        .arg(
+            Arg::new("remote-write-label")
+                .long("remote-write-label")
+                .env("UDPAGENT_REMOTE_WRITE_LABEL")
+                .help("External label added to every pushed series, e.g. 'cluster=eu'. Can be given multiple times (or separated by ';').")
+                .action(ArgAction::Append)
+                .value_delimiter(';')
+                .required(false),
+        )
+        .arg(
+            Arg::new("remote-write-username")
+                .long("remote-write-username")
+                .env("UDPAGENT_REMOTE_WRITE_USERNAME")
+                .help("Basic auth username of the remote write endpoint")
+                .required(false),
+        )
+        .arg(
+            Arg::new("remote-write-password")
+                .long("remote-write-password")
+                .env("UDPAGENT_REMOTE_WRITE_PASSWORD")
+                .hide_env_values(true)
+                .help("Basic auth password of the remote write endpoint")
+                .required(false),
+        )
+        .arg(
+            Arg::new("remote-write-bearer-token")
+                .long("remote-write-bearer-token")
+                .env("UDPAGENT_REMOTE_WRITE_BEARER_TOKEN")
+                .hide_env_values(true)
+                .help("Bearer token of the remote write endpoint")
+                .required(false),
+        )
+        .arg(
+            Arg::new("remote-write-max-retries")
+                .long("remote-write-max-retries")
+                .env("UDPAGENT_REMOTE_WRITE_MAX_RETRIES")
+                .help("Retries of a push failing with a network error, 429 or 5xx. [default: 3]")
+                .value_parser(clap::value_parser!(u32))
+                .required(false),
+        )
+        // ---- Graphite Args
+        .arg(
+            Arg::new("graphite-address")
+                .long("graphite-address")
+                .env("UDPAGENT_GRAPHITE_ADDRESS")
+                .help("Send metrics to the plaintext listener of carbon, e.g. graphite:2003. The sink is disabled if not given.")
+                .required(false),
+        )
+        .arg(
+            Arg::new("graphite-prefix")
+                .long("graphite-prefix")
+                .env("UDPAGENT_GRAPHITE_PREFIX")
+                .help("Prefix of every metric sent to graphite, e.g. servers.web-1")
+                .required(false),
+        )
+        .arg(
+            Arg::new("graphite-interval")
+                .long("graphite-interval")
+                .env("UDPAGENT_GRAPHITE_INTERVAL")
+                .help("Seconds between two flushes to graphite. [default: 10]")
+                .value_parser(clap::value_parser!(u64))
+                .required(false),
+        )
+        // ---- InfluxDB Args
+        .arg(
+            Arg::new("influxdb-endpoint")
+                .long("influxdb-endpoint")
+                .env("UDPAGENT_INFLUXDB_ENDPOINT")
+                .help("Write metrics as line protocol to this endpoint, e.g. http://influxdb:8086/api/v2/write?org=acme&bucket=metrics or udp://telegraf:8089. The sink is disabled if not given.")
+                .required(false),
+        )
+        .arg(
+            Arg::new("influxdb-interval")
+                .long("influxdb-interval")
+                .env("UDPAGENT_INFLUXDB_INTERVAL")
+                .help("Seconds between two writes to influxdb. [default: 10]")
+                .value_parser(clap::value_parser!(u64))
+                .required(false),
+        )
+        .arg(
+            Arg::new("influxdb-tag")
+                .long("influxdb-tag")
+                .env("UDPAGENT_INFLUXDB_TAG")
+                .help("Tag added to every line, e.g. 'host=web-1'. Can be given multiple times (or separated by ';').")
+                .action(ArgAction::Append)
+                .value_delimiter(';')
+                .required(false),
+        )
+        .arg(
+            Arg::new("influxdb-token")
+                .long("influxdb-token")
+                .env("UDPAGENT_INFLUXDB_TOKEN")
+                .hide_env_values(true)
+                .help("API token of the influxdb endpoint")
+                .required(false),
+        )
+        // ---- OTLP Args
+        .arg(
+            Arg::new("otlp-endpoint")
+                .long("otlp-endpoint")
+                .env("UDPAGENT_OTLP_ENDPOINT")
+                .help("Export metrics via OTLP/HTTP to this endpoint, e.g. http://otel-collector:4318/v1/metrics. The export is disabled if not given.")
+                .required(false),
+        )
+        .arg(
+            Arg::new("otlp-protocol")
+                .long("otlp-protocol")
+                .env("UDPAGENT_OTLP_PROTOCOL")
+                .help("Encoding of the OTLP requests, either protobuf or json. [default: protobuf]")
+                .value_parser(clap::value_parser!(OtlpProtocol))
+                .required(false),
+        )
+        .arg(
+            Arg::new("otlp-temporality")
+                .long("otlp-temporality")
+                .env("UDPAGENT_OTLP_TEMPORALITY")
+                .help("Temporality of the exported sums and histograms, either delta or cumulative. [default: cumulative]")
+                .value_parser(clap::value_parser!(OtlpTemporality))
+                .required(false),
+        )
+        .arg(
+            Arg::new("otlp-interval")
+                .long("otlp-interval")
+                .env("UDPAGENT_OTLP_INTERVAL")
+                .help("Seconds between two OTLP exports. [default: 15]")
+                .value_parser(clap::value_parser!(u64))
+                .required(false),
+        )
+        .arg(
+            Arg::new("otlp-resource-attribute")
+                .long("otlp-resource-attribute")
+                .env("UDPAGENT_OTLP_RESOURCE_ATTRIBUTE")
+                .help("Resource attribute of the exported metrics, e.g. 'deployment.environment=prod'. Can be given multiple times (or separated by ';').")
+                .action(ArgAction::Append)
+                .value_delimiter(';')
+                .required(false),
+        )
+        .arg(
+            Arg::new("otlp-header")
+                .long("otlp-header")
+                .env("UDPAGENT_OTLP_HEADER")
+                .hide_env_values(true)
+                .help("Header sent with every OTLP request, e.g. 'authorization=Bearer ...'. Can be given multiple times (or separated by ';').")
+                .action(ArgAction::Append)
+                .value_delimiter(';')
+                .required(false),
+        )
         // ---- ServerDensity Args
         .arg(
             Arg::new("disable-serverdensity")
                 .long("disable-serverdensity")
+                .env("UDPAGENT_DISABLE_SERVERDENSITY")
                 .help("Disable ServerDensity push - only provide open metrics pull endpoint")
                 .action(ArgAction::SetTrue),
         )
         .arg(Arg::new("token")
             .help("Server Density API Token")
             .long("token")
+            .env("UDPAGENT_SERVERDENSITY_TOKEN")
+            .hide_env_values(true)
             .required(false))
         .arg(Arg::new("account-url")
             .help("Set this to your Server Density account url, e.g. example.serverdensity.io")
             .long("account-url")
+            .env("UDPAGENT_SERVERDENSITY_ACCOUNT_URL")
             .required(false))
         .arg(Arg::new("agent-key")
             .help("This is the agent key used to identify the device when payloads are processed. You can find this in the top left corner when you view a device page in your UI")
             .long("agent-key")
+            .env("UDPAGENT_SERVERDENSITY_AGENT_KEY")
             .required(false))
         .arg(Arg::new("serverdensity-endpoint")
-            .default_value("https://api.serverdensity.io")
-            .help("Serverdensity API-Endpoint")
+            .help("Serverdensity API-Endpoint [default: https://api.serverdensity.io]")
             .long("serverdensity-endpoint")
+            .env("UDPAGENT_SERVERDENSITY_ENDPOINT")
+            .required(false))
+        .arg(Arg::new("serverdensity-flush-interval")
+            .help("Seconds between two pushes to Serverdensity [default: 10]")
+            .long("serverdensity-flush-interval")
+            .env("UDPAGENT_SERVERDENSITY_FLUSH_INTERVAL")
+            .value_parser(clap::value_parser!(u64))
             .required(false))
         .arg(Arg::new("config")
             .short('c')
             .help("path to the serverdensity config file, may /etc/sd-agent/config.cfg?")
             .long("config"))
+        .arg(Arg::new("serverdensity-queue-dir")
+            .help("Directory to queue payloads in which could not be pushed to Serverdensity, they are replayed once Serverdensity is reachable again")
+            .long("serverdensity-queue-dir")
+            .env("UDPAGENT_SERVERDENSITY_QUEUE_DIR")
+            .required(false))
         // ---- ServerDensity Args
         .get_matches();
 
-    let config = Config {
-        debug: matches.get_flag("debug"),
-        udp_bind: matches
-            .get_one::<String>("udp-bind")
-            .ok_or(anyhow!("UDP bind host is missing"))?
-            .to_string(),
-        http_bind: matches
-            .get_one::<String>("http-bind")
-            .ok_or(anyhow!("HTTP bind host is missing"))?
-            .to_string(),
-        disable_serverdensity: matches.get_flag("disable-serverdensity"),
-    };
+    let config = Config::from_args(&matches).context("invalid configuration")?;
+
+    if matches.get_flag("print-config") {
+        print!("{}", config.to_redacted_toml()?);
+        return Ok(());
+    }
 
     println!("UDP Monitor for OpenMetrics");
     println!("debug: {:?}", &config.debug);
     println!("udp host: {}", &config.udp_bind);
     println!("http host: {}", &config.http_bind);
-    println!("disable serverdensity: {}", &config.disable_serverdensity);
+    println!("statsd host: {:?}", &config.statsd_bind);
+    println!("flush interval: {}s", &config.flush_interval_secs);
+    println!("align flush: {}", &config.align_flush);
+    println!(
+        "stale series: {:?} after {} windows",
+        &config.stale_policy, &config.stale_windows
+    );
+    println!("series ttl: {}s", &config.series_ttl_secs);
+    println!(
+        "max series: {}, overflow: {:?}",
+        &config.limits.max_series, &config.limits.overflow_policy
+    );
+    for quota in &config.limits.quotas {
+        println!("series quota: {}={}", &quota.prefix, &quota.max_series);
+    }
+    println!("sinks: {:?}", config.enabled_sinks());
+    if let (true, Some(queue_dir)) = (
+        config.serverdensity.enabled,
+        &config.serverdensity.queue_dir,
+    ) {
+        println!("serverdensity queue: {}", queue_dir);
+    }
+    if config.remote_write.enabled {
+        println!(
+            "remote write: {} every {}s",
+            &config.remote_write.endpoint, &config.remote_write.flush_interval_secs
+        );
+    }
+    if config.graphite.enabled {
+        println!(
+            "graphite: {} every {}s",
+            &config.graphite.address, &config.graphite.flush_interval_secs
+        );
+    }
+    if config.influxdb.enabled {
+        println!(
+            "influxdb: {} every {}s",
+            &config.influxdb.endpoint, &config.influxdb.flush_interval_secs
+        );
+    }
+    if config.otlp.enabled {
+        println!(
+            "otlp: {} every {}s ({:?}, {:?})",
+            &config.otlp.endpoint,
+            &config.otlp.flush_interval_secs,
+            &config.otlp.protocol,
+            &config.otlp.temporality
+        );
+    }
+    for histogram_buckets in &config.histogram_buckets {
+        println!(
+            "histogram buckets: {}={:?}",
+            &histogram_buckets.prefix, &histogram_buckets.buckets
+        );
+    }
+
+    if !config.quantiles.prefixes.is_empty() {
+        println!(
+            "quantiles: {:?} of {:?}",
+            &config.quantiles.quantiles, &config.quantiles.prefixes
+        );
+    }
 
     let mut registry = Registry::default();
     registry.register(
@@ -118,33 +531,170 @@ async fn main() -> anyhow::Result<(), anyhow::Error> {
         "udp packets",
         METRIC_COUNTER_UDP_PACKETS.clone(),
     );
+    registry.register(
+        "udpagent_udp_metrics",
+        "metrics decoded from udp packets, a batch contains several",
+        METRIC_COUNTER_UDP_METRICS.clone(),
+    );
+    registry.register(
+        "udpagent_statsd_metrics",
+        "metrics received by the statsd listener",
+        METRIC_COUNTER_STATSD_METRICS.clone(),
+    );
+    registry.register(
+        "udpagent_ingested_metrics",
+        "metrics received by the http ingest endpoint",
+        METRIC_COUNTER_INGESTED_METRICS.clone(),
+    );
+    registry.register(
+        "udpagent_window_start_seconds",
+        "start of the current flush window as unix timestamp",
+        METRIC_GAUGE_WINDOW_START.clone(),
+    );
+    registry.register(
+        "udpagent_window_end_seconds",
+        "end of the current flush window as unix timestamp",
+        METRIC_GAUGE_WINDOW_END.clone(),
+    );
+    registry.register(
+        "udpagent_evicted_series",
+        "series removed after not receiving metrics for the series ttl",
+        METRIC_COUNTER_EVICTED_SERIES.clone(),
+    );
+    registry.register(
+        "udpagent_rejected_samples",
+        "samples rejected by the value policy, by reason",
+        METRIC_COUNTER_REJECTED_SAMPLES.clone(),
+    );
+    registry.register(
+        "udpagent_sink_up",
+        "whether the last flush of the sink succeeded",
+        METRIC_GAUGE_SINK_UP.clone(),
+    );
+    registry.register(
+        "udpagent_sink_flush_failures",
+        "failed flushes, by sink",
+        METRIC_COUNTER_SINK_FLUSH_FAILURES.clone(),
+    );
+    registry.register(
+        "udpagent_serverdensity_requests",
+        "pushes to serverdensity, by status class",
+        METRIC_COUNTER_SERVERDENSITY_REQUESTS.clone(),
+    );
+    registry.register(
+        "udpagent_serverdensity_health",
+        "state of the last push to serverdensity, the current state is 1",
+        METRIC_GAUGE_SERVERDENSITY_HEALTH.clone(),
+    );
+    registry.register(
+        "udpagent_serverdensity_queue_payloads",
+        "payloads queued on disk after a failed push to serverdensity",
+        METRIC_GAUGE_SERVERDENSITY_QUEUE_PAYLOADS.clone(),
+    );
+    registry.register(
+        "udpagent_serverdensity_queue_bytes",
+        "size of the payloads queued on disk",
+        METRIC_GAUGE_SERVERDENSITY_QUEUE_BYTES.clone(),
+    );
+    registry.register(
+        "udpagent_serverdensity_retries",
+        "replays of queued payloads to serverdensity",
+        METRIC_COUNTER_SERVERDENSITY_RETRIES.clone(),
+    );
+    registry.register(
+        "udpagent_serverdensity_dropped_payloads",
+        "payloads dropped without being pushed, by reason",
+        METRIC_COUNTER_SERVERDENSITY_DROPPED_PAYLOADS.clone(),
+    );
+    registry.register(
+        "udpagent_remote_write_requests",
+        "requests to the remote write endpoint, by status class",
+        METRIC_COUNTER_REMOTE_WRITE_REQUESTS.clone(),
+    );
+    registry.register(
+        "udpagent_graphite_dropped_lines",
+        "lines dropped because the graphite send queue was full",
+        METRIC_COUNTER_GRAPHITE_DROPPED_LINES.clone(),
+    );
+    registry.register(
+        "udpagent_influxdb_requests",
+        "writes to the influxdb endpoint, by status class",
+        METRIC_COUNTER_INFLUXDB_REQUESTS.clone(),
+    );
+    registry.register(
+        "udpagent_otlp_requests",
+        "requests to the otlp endpoint, by status class",
+        METRIC_COUNTER_OTLP_REQUESTS.clone(),
+    );
+    registry.register(
+        "udpagent_type_conflicts",
+        "samples dropped by the /metrics endpoint, because a metric of another type has the same name",
+        METRIC_COUNTER_TYPE_CONFLICTS.clone(),
+    );
+    registry.register(
+        "udpagent_otlp_type_conflicts",
+        "otlp data points dropped, because a metric of another type has the same name",
+        METRIC_COUNTER_OTLP_TYPE_CONFLICTS.clone(),
+    );
+    registry.register(
+        "udpagent_limited_samples",
+        "samples of series beyond a series limit, by sink, rule and overflow policy",
+        METRIC_COUNTER_LIMITED_SAMPLES.clone(),
+    );
+
+    // the families created by the processor, other than the registry they can be removed again
+    let metric_collector = MetricCollector::default();
+    registry.register_collector(Box::new(metric_collector.clone()));
 
     let metric_registry = Arc::new(RwLock::new(registry));
-    let (sender, receiver) = channel::<InboundMetric>(100_000);
-
-    // server density aggregator
-    let server_density_aggregator_handle = if config.disable_serverdensity {
-        None
-    } else {
-        let server_density_config =
-            ServerDensityConfig::from_args(matches).context("serverdensity args")?;
-        let server_density_aggregator_receiver = sender.subscribe();
-        Some(tokio::spawn(async move {
-            let server_density_aggregator = ServerDensityAggregator::new(server_density_config);
-            server_density_aggregator
-                .run(server_density_aggregator_receiver)
-                .await;
-        }))
-    };
-
-    let processor_config = config.clone();
-    let processor_registry = metric_registry.clone();
-    let processor_receiver = receiver;
-    let processor_handle = tokio::spawn(async move {
-        let mut processor = Processor::new(processor_config, processor_registry);
-        processor.run(processor_receiver).await;
+    let (sender, _) = channel::<InboundMetric>(100_000);
+
+    // every sink runs in its own task on its own subscription of the channel
+    let mut sinks = SinkTasks::new(config.align_flush);
+    if config.prometheus.enabled {
+        let processor = Processor::new(config.clone(), metric_collector.clone());
+        sinks.spawn(processor, 100, &sender);
+    }
+
+    if config.serverdensity.enabled {
+        let server_density_aggregator =
+            ServerDensityAggregator::new(config.serverdensity.clone(), config.quantiles.clone())
+                .await
+                .map_err(|err| anyhow!("could not open the serverdensity queue: {}", err))?;
+        sinks.spawn(server_density_aggregator, 102, &sender);
+    }
+
+    if config.remote_write.enabled {
+        sinks.spawn(RemoteWriteSink::new(config.clone()), 105, &sender);
+    }
+
+    if config.graphite.enabled {
+        let graphite_sink = GraphiteSink::new(config.graphite.clone(), config.quantiles.clone());
+        sinks.spawn(graphite_sink, 106, &sender);
+    }
+
+    if config.influxdb.enabled {
+        let influxdb_sink = InfluxDbSink::new(
+            config.influxdb.clone(),
+            config.quantiles.clone(),
+            config.limits.clone(),
+        );
+        sinks.spawn(influxdb_sink, 107, &sender);
+    }
+
+    if config.otlp.enabled {
+        sinks.spawn(OtlpSink::new(config.clone()), 108, &sender);
+    }
+
+    let statsd_server_handle = config.statsd_bind.clone().map(|statsd_bind| {
+        let statsd_server_sender = sender.clone();
+        tokio::spawn(async move {
+            let statsd_server = StatsdServer::new(statsd_bind, statsd_server_sender);
+            statsd_server.run().await;
+        })
     });
 
+    let http_server_sender = sender.clone();
     let udp_server_config = config.clone();
     let udp_server_handle = tokio::spawn(async move {
         let udp_server = UdpServer::new(udp_server_config, sender);
@@ -153,32 +703,41 @@ async fn main() -> anyhow::Result<(), anyhow::Error> {
 
     // bind the http server to serve open metrics requests
     let http_server_registry = metric_registry.clone();
-    let http_server_handle = http_server::bind(&config, http_server_registry);
+    let http_server_handle = http_server::bind(
+        &config,
+        http_server_registry,
+        metric_collector,
+        http_server_sender,
+    );
 
     // waits for one tasks to fail or interrupt, returns the status code to identity the issue
     let exit_code = tokio::spawn(async move {
-        tokio::select! {
-            _ = processor_handle => {
-                eprintln!("Metrics processor failed");
-                100
+        let exit_code = tokio::select! {
+            Some((sink, exit_code)) = sinks.failed(), if !sinks.is_empty() => {
+                eprintln!("Sink {} failed", sink);
+                exit_code
             }
             _ = udp_server_handle => {
                 eprintln!("UDP server failed");
                 101
             }
-            _ = async { server_density_aggregator_handle.expect("must be given").await }, if server_density_aggregator_handle.is_some() => {
-                eprintln!("Serverdensity aggregator failed");
-                102
-            }
             _ = http_server_handle => {
                 eprintln!("Http server failed");
                 103
             }
+            _ = async { statsd_server_handle.expect("must be given").await }, if statsd_server_handle.is_some() => {
+                eprintln!("StatsD server failed");
+                104
+            }
             _ = tokio::signal::ctrl_c() => {
                 println!("Quit signal detected, exiting...");
                 0
             }
-        }
+        };
+
+        // the remaining sinks flush the unfinished window
+        sinks.shutdown(Duration::from_secs(5)).await;
+        exit_code
     })
     .await
     .context("Error running main monitor loop")?;
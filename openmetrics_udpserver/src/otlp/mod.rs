@@ -0,0 +1,361 @@
+use crate::config::Config;
+use crate::otlp::sink::OtlpTemporality;
+use crate::processor::{InboundMetric, MetricLabels};
+use crate::series::SeriesState;
+use crate::window::Window;
+use crate::METRIC_COUNTER_OTLP_TYPE_CONFLICTS;
+use fnv::FnvHashSet;
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::{
+    metric, number_data_point, AggregationTemporality, Gauge, Histogram, HistogramDataPoint,
+    Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics, Sum,
+};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod receiver;
+pub mod sink;
+
+/// The series of the OTLP sink. Sum becomes a monotonic OTLP Sum and Histogram an OTLP Histogram,
+/// both either per window (delta) or since the series was first seen (cumulative). Set and Delta
+/// are gauges with their current value, Average, Peak, Min, Unique and Timing are gauges
+/// aggregated per window and only exported for windows with samples.
+///
+/// An OTLP metric has a single type, if metrics of different types share a name only the first
+/// type (gauges, sums, histograms) is exported, the other data points are counted as conflicts.
+pub struct OtlpState {
+    temporality: OtlpTemporality,
+    resource: Resource,
+    series: SeriesState,
+    /// the names with a type conflict which were already logged.
+    conflicts: FnvHashSet<String>,
+}
+
+impl OtlpState {
+    pub fn new(config: Config) -> Self {
+        Self {
+            temporality: config.otlp.temporality,
+            resource: Resource {
+                attributes: config
+                    .otlp
+                    .resource_attributes()
+                    .iter()
+                    .map(|(key, value)| key_value(key, value))
+                    .collect(),
+                dropped_attributes_count: 0,
+            },
+            series: SeriesState::new(config, "otlp"),
+            conflicts: FnvHashSet::default(),
+        }
+    }
+
+    pub fn handle(&mut self, inbound_metric: InboundMetric) {
+        self.series.handle(inbound_metric);
+    }
+
+    /// ends the window and returns all data points of the window.
+    pub fn flush(&mut self, window: Window) -> ExportMetricsServiceRequest {
+        self.series.evict_idle_series();
+
+        let start = unix_nanos(window.start);
+        let end = unix_nanos(window.end);
+        let delta = self.temporality == OtlpTemporality::Delta;
+        let temporality = match delta {
+            true => AggregationTemporality::Delta,
+            false => AggregationTemporality::Cumulative,
+        } as i32;
+
+        // the points of every metric, sorted by name
+        let mut metrics: BTreeMap<String, metric::Data> = BTreeMap::new();
+
+        let mut conflicts = vec![];
+
+        let windowed = self.series.windowed();
+        let gauges = windowed.iter().flatten().chain(self.series.gauges.iter());
+        for (series, value) in gauges {
+            let data = metrics
+                .entry(series.name.to_string())
+                .or_insert_with(|| metric::Data::Gauge(Gauge::default()));
+            match data {
+                metric::Data::Gauge(gauge) => {
+                    gauge
+                        .data_points
+                        .push(number_data_point(&series.labels, start, end, *value))
+                }
+                _ => conflicts.push(&series.name),
+            }
+        }
+
+        for (series, sum) in &self.series.sums {
+            let start = if delta { start } else { unix_nanos(sum.start) };
+            let data = metrics.entry(series.name.to_string()).or_insert_with(|| {
+                metric::Data::Sum(Sum {
+                    data_points: vec![],
+                    aggregation_temporality: temporality,
+                    is_monotonic: true,
+                })
+            });
+            match data {
+                metric::Data::Sum(data) => {
+                    data.data_points
+                        .push(number_data_point(&series.labels, start, end, sum.value))
+                }
+                _ => conflicts.push(&series.name),
+            }
+        }
+
+        for (series, histogram) in &self.series.histograms {
+            let start = if delta {
+                start
+            } else {
+                unix_nanos(histogram.start)
+            };
+            let data = metrics.entry(series.name.to_string()).or_insert_with(|| {
+                metric::Data::Histogram(Histogram {
+                    data_points: vec![],
+                    aggregation_temporality: temporality,
+                })
+            });
+            let metric::Data::Histogram(data) = data else {
+                conflicts.push(&series.name);
+                continue;
+            };
+
+            data.data_points.push(HistogramDataPoint {
+                attributes: attributes(&series.labels),
+                start_time_unix_nano: start,
+                time_unix_nano: end,
+                count: histogram.count,
+                sum: Some(histogram.sum),
+                bucket_counts: histogram.bucket_counts.clone(),
+                explicit_bounds: histogram.bounds.clone(),
+                exemplars: vec![],
+                flags: 0,
+                min: Some(histogram.min),
+                max: Some(histogram.max),
+            });
+        }
+
+        METRIC_COUNTER_OTLP_TYPE_CONFLICTS.inc_by(conflicts.len() as u64);
+        for name in conflicts {
+            if self.conflicts.insert(name.to_string()) {
+                eprintln!(
+                    "otlp: metrics of different types are named {}, only the {} is exported",
+                    name,
+                    type_name(&metrics[name])
+                );
+            }
+        }
+
+        // a delta series starts over in every window and is only exported with samples
+        if delta {
+            self.series.start_over();
+        }
+
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(self.resource.clone()),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(InstrumentationScope {
+                        name: "openmetrics_udpserver".to_string(),
+                        version: option_env!("CARGO_PKG_VERSION")
+                            .unwrap_or("DEV")
+                            .to_string(),
+                        attributes: vec![],
+                        dropped_attributes_count: 0,
+                    }),
+                    metrics: metrics
+                        .into_iter()
+                        .map(|(name, data)| Metric {
+                            name,
+                            description: "".to_string(),
+                            unit: "".to_string(),
+                            metadata: vec![],
+                            data: Some(data),
+                        })
+                        .collect(),
+                    schema_url: "".to_string(),
+                }],
+                schema_url: "".to_string(),
+            }],
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+fn type_name(data: &metric::Data) -> &'static str {
+    match data {
+        metric::Data::Gauge(_) => "gauge",
+        metric::Data::Sum(_) => "sum",
+        metric::Data::Histogram(_) => "histogram",
+        metric::Data::ExponentialHistogram(_) => "exponential histogram",
+        metric::Data::Summary(_) => "summary",
+    }
+}
+
+fn key_value(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::StringValue(value.to_string())),
+        }),
+    }
+}
+
+fn attributes(labels: &MetricLabels) -> Vec<KeyValue> {
+    labels
+        .iter()
+        .map(|(key, value)| key_value(key, value))
+        .collect()
+}
+
+fn number_data_point(labels: &MetricLabels, start: u64, end: u64, value: f64) -> NumberDataPoint {
+    NumberDataPoint {
+        attributes: attributes(labels),
+        start_time_unix_nano: start,
+        time_unix_nano: end,
+        exemplars: vec![],
+        flags: 0,
+        value: Some(number_data_point::Value::AsDouble(value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, HistogramBucketsConfig};
+    use crate::otlp::sink::{OtlpConfig, OtlpTemporality};
+    use crate::otlp::OtlpState;
+    use crate::processor::InboundMetric;
+    use crate::window::Window;
+    use crate::METRIC_COUNTER_OTLP_TYPE_CONFLICTS;
+    use openmetrics_udpserver_lib::MetricType;
+    use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+    use opentelemetry_proto::tonic::metrics::v1::{metric, number_data_point};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn metric(metric_type: MetricType, name: &str, value: f64) -> InboundMetric {
+        InboundMetric {
+            name: name.to_string(),
+            value,
+            labels: vec![("host".to_string(), "web-1".to_string())],
+            metric_type,
+            member: None,
+        }
+    }
+
+    fn window(start: u64, end: u64) -> Window {
+        Window {
+            start: UNIX_EPOCH + Duration::from_secs(start),
+            end: UNIX_EPOCH + Duration::from_secs(end),
+        }
+    }
+
+    fn state(temporality: OtlpTemporality) -> OtlpState {
+        OtlpState::new(Config {
+            histogram_buckets: vec![HistogramBucketsConfig::parse("latency=10,100").unwrap()],
+            otlp: OtlpConfig {
+                temporality,
+                ..OtlpConfig::default()
+            },
+            ..Config::default()
+        })
+    }
+
+    fn data<'a>(request: &'a ExportMetricsServiceRequest, name: &str) -> Option<&'a metric::Data> {
+        request.resource_metrics[0].scope_metrics[0]
+            .metrics
+            .iter()
+            .find(|metric| metric.name == name)
+            .and_then(|metric| metric.data.as_ref())
+    }
+
+    fn sum_value(request: &ExportMetricsServiceRequest, name: &str) -> Option<(u64, f64)> {
+        match data(request, name)? {
+            metric::Data::Sum(sum) => match sum.data_points[0].value {
+                Some(number_data_point::Value::AsDouble(value)) => {
+                    Some((sum.data_points[0].start_time_unix_nano, value))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn it_exports_sums_per_window_with_delta_temporality() {
+        let mut state = state(OtlpTemporality::Delta);
+        state.handle(metric(MetricType::Sum, "requests", 2.0));
+        state.handle(metric(MetricType::Sum, "requests", 3.0));
+        state.handle(metric(MetricType::Average, "load", 1.0));
+        state.handle(metric(MetricType::Average, "load", 3.0));
+        let request = state.flush(window(10, 20));
+
+        assert_eq!(Some((10_000_000_000, 5.0)), sum_value(&request, "requests"));
+        let Some(metric::Data::Gauge(load)) = data(&request, "load") else {
+            panic!("load is not a gauge");
+        };
+        assert_eq!(
+            Some(number_data_point::Value::AsDouble(2.0)),
+            load.data_points[0].value
+        );
+        assert_eq!(20_000_000_000, load.data_points[0].time_unix_nano);
+        assert_eq!("host", load.data_points[0].attributes[0].key);
+
+        state.handle(metric(MetricType::Sum, "requests", 1.0));
+        let request = state.flush(window(20, 30));
+        assert_eq!(Some((20_000_000_000, 1.0)), sum_value(&request, "requests"));
+        assert!(data(&request, "load").is_none());
+
+        let request = state.flush(window(30, 40));
+        assert!(data(&request, "requests").is_none());
+    }
+
+    #[test]
+    fn it_exports_cumulative_sums_and_histograms() {
+        let mut state = state(OtlpTemporality::Cumulative);
+        state.handle(metric(MetricType::Sum, "requests", 2.0));
+        for value in [5.0, 50.0, 500.0, 50.0] {
+            state.handle(metric(MetricType::Histogram, "latency", value));
+        }
+        let first = state.flush(window(10, 20));
+
+        state.handle(metric(MetricType::Sum, "requests", 1.0));
+        let request = state.flush(window(20, 30));
+
+        let (start, value) = sum_value(&request, "requests").unwrap();
+        assert_eq!(3.0, value);
+        assert_eq!(sum_value(&first, "requests").unwrap().0, start);
+
+        let Some(metric::Data::Histogram(histogram)) = data(&request, "latency") else {
+            panic!("latency is not a histogram");
+        };
+        let point = &histogram.data_points[0];
+        assert_eq!(vec![10.0, 100.0], point.explicit_bounds);
+        assert_eq!(vec![1, 2, 1], point.bucket_counts);
+        assert_eq!(4, point.count);
+        assert_eq!(Some(605.0), point.sum);
+        assert_eq!((Some(5.0), Some(500.0)), (point.min, point.max));
+    }
+
+    #[test]
+    fn it_counts_metrics_of_different_types_with_the_same_name() {
+        let mut state = state(OtlpTemporality::Cumulative);
+        state.handle(metric(MetricType::Set, "requests", 7.0));
+        state.handle(metric(MetricType::Sum, "requests", 2.0));
+        let conflicts = METRIC_COUNTER_OTLP_TYPE_CONFLICTS.get();
+        let request = state.flush(window(10, 20));
+
+        let Some(metric::Data::Gauge(gauge)) = data(&request, "requests") else {
+            panic!("requests is not a gauge");
+        };
+        assert_eq!(1, gauge.data_points.len());
+        assert!(METRIC_COUNTER_OTLP_TYPE_CONFLICTS.get() > conflicts);
+    }
+}
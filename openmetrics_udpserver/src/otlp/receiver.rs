@@ -0,0 +1,542 @@
+use crate::processor::{InboundMetric, MetricLabels};
+use openmetrics_udpserver_lib::MetricType;
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::{any_value, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::{
+    metric, number_data_point, AggregationTemporality, DataPointFlags, HistogramDataPoint,
+    NumberDataPoint,
+};
+use prost::Message;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// histogram points are expanded into one sample per observation, points beyond this many
+/// observations per request are rejected instead of flooding the channel.
+pub const MAX_HISTOGRAM_SAMPLES: u64 = 10_000;
+
+/// the number of cumulative series whose last point is kept, points of further series are
+/// rejected until idle series are forgotten.
+pub const MAX_CUMULATIVE_SERIES: usize = 100_000;
+
+/// cumulative series which did not send a point for this long are forgotten.
+const CUMULATIVE_SERIES_TTL: Duration = Duration::from_secs(3600);
+
+pub fn decode_protobuf(body: &[u8]) -> Result<ExportMetricsServiceRequest, String> {
+    ExportMetricsServiceRequest::decode(body).map_err(|err| format!("invalid protobuf: {}", err))
+}
+
+pub fn decode_json(body: &[u8]) -> Result<ExportMetricsServiceRequest, String> {
+    let mut value: Value =
+        serde_json::from_slice(body).map_err(|err| format!("invalid json: {}", err))?;
+    normalize_int64(&mut value);
+
+    serde_json::from_value(value).map_err(|err| format!("invalid json: {}", err))
+}
+
+/// OTLP/JSON encodes 64 bit integers as strings and accepts numbers as well, the generated types
+/// only understand strings for the timestamps and only numbers for the other integers.
+fn normalize_int64(value: &mut Value) {
+    fn to_number(value: &mut Value) {
+        if let Value::String(s) = value {
+            if let Ok(number) = s.parse::<i64>() {
+                *value = Value::from(number);
+            } else if let Ok(number) = s.parse::<u64>() {
+                *value = Value::from(number);
+            }
+        }
+    }
+
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match key.as_str() {
+                    "startTimeUnixNano" | "timeUnixNano" => {
+                        if let Value::Number(number) = value {
+                            *value = Value::String(number.to_string());
+                        }
+                    }
+                    "asInt" | "count" | "zeroCount" => to_number(value),
+                    "bucketCounts" => {
+                        if let Value::Array(counts) = value {
+                            counts.iter_mut().for_each(to_number);
+                        }
+                    }
+                    _ => normalize_int64(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(normalize_int64),
+        _ => {}
+    }
+}
+
+/// The metrics of an export request, the points which could not be mapped are counted.
+#[derive(Debug, Default)]
+pub struct ReceivedMetrics {
+    pub metrics: Vec<InboundMetric>,
+    pub rejected_points: i64,
+    pub errors: Vec<String>,
+    /// the histogram samples of the request so far.
+    histogram_samples: u64,
+}
+
+impl ReceivedMetrics {
+    fn reject(&mut self, error: String) {
+        self.rejected_points += 1;
+        self.errors.push(error);
+    }
+}
+
+/// The last value of every cumulative series, the agent only accepts deltas. The first point of a
+/// series which started before the agent only sets the baseline, a point with a new start time or
+/// a lower value is a reset and counted in full.
+struct CumulativePoint {
+    start_time_unix_nano: u64,
+    value: f64,
+    buckets: Vec<u64>,
+    last_seen: Instant,
+}
+
+/// Maps OTLP metrics onto inbound metrics:
+/// - a monotonic Sum becomes Sum, cumulative points are converted to the delta since the last point
+/// - a non monotonic Sum becomes Delta (delta temporality) or Set (cumulative temporality)
+/// - a Gauge becomes Set
+/// - a Histogram becomes Histogram, with one sample per observation at the upper bound of its bucket
+///
+/// Exponential histograms and summaries are rejected. The attributes of a point become labels.
+pub struct OtlpReceiver {
+    started_unix_nano: u64,
+    cumulative: HashMap<(String, MetricLabels), CumulativePoint>,
+    last_eviction: Instant,
+    max_histogram_samples: u64,
+    max_cumulative_series: usize,
+}
+
+impl OtlpReceiver {
+    pub fn new() -> Self {
+        Self {
+            started_unix_nano: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+            cumulative: HashMap::new(),
+            last_eviction: Instant::now(),
+            max_histogram_samples: MAX_HISTOGRAM_SAMPLES,
+            max_cumulative_series: MAX_CUMULATIVE_SERIES,
+        }
+    }
+
+    pub fn receive(&mut self, request: ExportMetricsServiceRequest) -> ReceivedMetrics {
+        self.evict_idle_series();
+
+        let mut received = ReceivedMetrics::default();
+        let metrics = request
+            .resource_metrics
+            .into_iter()
+            .flat_map(|resource| resource.scope_metrics)
+            .flat_map(|scope| scope.metrics);
+
+        for metric in metrics {
+            let name = metric.name;
+            match metric.data {
+                Some(metric::Data::Sum(sum)) => {
+                    let cumulative =
+                        sum.aggregation_temporality == AggregationTemporality::Cumulative as i32;
+                    for point in sum.data_points {
+                        match (sum.is_monotonic, cumulative) {
+                            (true, true) => self.cumulative_sum(&name, point, &mut received),
+                            (true, false) => number(&name, MetricType::Sum, point, &mut received),
+                            (false, true) => number(&name, MetricType::Set, point, &mut received),
+                            (false, false) => {
+                                number(&name, MetricType::Delta, point, &mut received)
+                            }
+                        }
+                    }
+                }
+                Some(metric::Data::Gauge(gauge)) => {
+                    for point in gauge.data_points {
+                        number(&name, MetricType::Set, point, &mut received);
+                    }
+                }
+                Some(metric::Data::Histogram(histogram)) => {
+                    let cumulative = histogram.aggregation_temporality
+                        == AggregationTemporality::Cumulative as i32;
+                    for point in histogram.data_points {
+                        self.histogram(&name, point, cumulative, &mut received);
+                    }
+                }
+                Some(metric::Data::ExponentialHistogram(histogram)) => {
+                    for _ in histogram.data_points {
+                        received.reject(format!(
+                            "{}: exponential histograms are not supported",
+                            name
+                        ));
+                    }
+                }
+                Some(metric::Data::Summary(summary)) => {
+                    for _ in summary.data_points {
+                        received.reject(format!("{}: summaries are not supported", name));
+                    }
+                }
+                None => received.reject(format!("{}: missing or unsupported data", name)),
+            }
+        }
+
+        received
+    }
+
+    fn cumulative_sum(
+        &mut self,
+        name: &str,
+        point: NumberDataPoint,
+        received: &mut ReceivedMetrics,
+    ) {
+        if no_recorded_value(point.flags) {
+            return;
+        }
+
+        let Some(value) = number_value(&point) else {
+            received.reject(format!("{}: missing value", name));
+            return;
+        };
+
+        let labels = labels(&point.attributes);
+        let key = (name.to_string(), labels.clone());
+        if let Err(err) = self.can_track(&key) {
+            received.reject(err);
+            return;
+        }
+
+        let delta = match self.cumulative.get(&key) {
+            Some(last)
+                if last.start_time_unix_nano == point.start_time_unix_nano
+                    && last.value <= value =>
+            {
+                value - last.value
+            }
+            Some(_) => value,
+            None if self.started_before_agent(point.start_time_unix_nano) => 0.0,
+            None => value,
+        };
+
+        self.cumulative.insert(
+            key,
+            CumulativePoint {
+                start_time_unix_nano: point.start_time_unix_nano,
+                value,
+                buckets: vec![],
+                last_seen: Instant::now(),
+            },
+        );
+
+        if delta > 0.0 {
+            received
+                .metrics
+                .push(inbound(name, MetricType::Sum, delta, labels));
+        }
+    }
+
+    fn histogram(
+        &mut self,
+        name: &str,
+        point: HistogramDataPoint,
+        cumulative: bool,
+        received: &mut ReceivedMetrics,
+    ) {
+        if no_recorded_value(point.flags) {
+            return;
+        }
+
+        if point.bucket_counts.len() != point.explicit_bounds.len() + 1 {
+            received.reject(format!(
+                "{}: expected {} bucket counts, got {}",
+                name,
+                point.explicit_bounds.len() + 1,
+                point.bucket_counts.len()
+            ));
+            return;
+        }
+
+        let labels = labels(&point.attributes);
+        let bucket_counts = if cumulative {
+            let key = (name.to_string(), labels.clone());
+            if let Err(err) = self.can_track(&key) {
+                received.reject(err);
+                return;
+            }
+
+            let delta = match self.cumulative.get(&key) {
+                Some(last)
+                    if last.start_time_unix_nano == point.start_time_unix_nano
+                        && last.buckets.len() == point.bucket_counts.len()
+                        && last
+                            .buckets
+                            .iter()
+                            .zip(&point.bucket_counts)
+                            .all(|(last, count)| last <= count) =>
+                {
+                    point
+                        .bucket_counts
+                        .iter()
+                        .zip(&last.buckets)
+                        .map(|(count, last)| count - last)
+                        .collect()
+                }
+                Some(_) => point.bucket_counts.clone(),
+                None if self.started_before_agent(point.start_time_unix_nano) => {
+                    vec![0; point.bucket_counts.len()]
+                }
+                None => point.bucket_counts.clone(),
+            };
+
+            self.cumulative.insert(
+                key,
+                CumulativePoint {
+                    start_time_unix_nano: point.start_time_unix_nano,
+                    value: point.count as f64,
+                    buckets: point.bucket_counts.clone(),
+                    last_seen: Instant::now(),
+                },
+            );
+            delta
+        } else {
+            point.bucket_counts.clone()
+        };
+
+        let samples = bucket_counts.iter().sum::<u64>();
+        if received.histogram_samples + samples > self.max_histogram_samples {
+            received.reject(format!(
+                "{}: {} observations exceed the limit of {} per request",
+                name, samples, self.max_histogram_samples
+            ));
+            return;
+        }
+        received.histogram_samples += samples;
+
+        for (i, count) in bucket_counts.iter().enumerate() {
+            let value = match point.explicit_bounds.get(i) {
+                Some(bound) => *bound,
+                // the overflow bucket, its observations are above the highest bound
+                None => match (point.max, point.explicit_bounds.last()) {
+                    (Some(max), _) => max,
+                    (None, Some(bound)) => bound.next_up(),
+                    (None, None) => point.sum.unwrap_or(0.0) / point.count.max(1) as f64,
+                },
+            };
+
+            for _ in 0..*count {
+                received
+                    .metrics
+                    .push(inbound(name, MetricType::Histogram, value, labels.clone()));
+            }
+        }
+    }
+
+    /// a known series or a new one while there is room for it.
+    fn can_track(&mut self, key: &(String, MetricLabels)) -> Result<(), String> {
+        if self.cumulative.len() < self.max_cumulative_series || self.cumulative.contains_key(key) {
+            return Ok(());
+        }
+
+        self.last_eviction = Instant::now();
+        self.cumulative
+            .retain(|_, point| point.last_seen.elapsed() < CUMULATIVE_SERIES_TTL);
+        if self.cumulative.len() < self.max_cumulative_series {
+            return Ok(());
+        }
+
+        Err(format!(
+            "{}: more than {} cumulative series",
+            key.0, self.max_cumulative_series
+        ))
+    }
+
+    fn started_before_agent(&self, start_time_unix_nano: u64) -> bool {
+        start_time_unix_nano < self.started_unix_nano
+    }
+
+    fn evict_idle_series(&mut self) {
+        if self.last_eviction.elapsed() < Duration::from_secs(60) {
+            return;
+        }
+
+        self.last_eviction = Instant::now();
+        self.cumulative
+            .retain(|_, point| point.last_seen.elapsed() < CUMULATIVE_SERIES_TTL);
+    }
+}
+
+fn number(
+    name: &str,
+    metric_type: MetricType,
+    point: NumberDataPoint,
+    received: &mut ReceivedMetrics,
+) {
+    if no_recorded_value(point.flags) {
+        return;
+    }
+
+    match number_value(&point) {
+        Some(value) => {
+            received
+                .metrics
+                .push(inbound(name, metric_type, value, labels(&point.attributes)))
+        }
+        None => received.reject(format!("{}: missing value", name)),
+    }
+}
+
+fn number_value(point: &NumberDataPoint) -> Option<f64> {
+    match point.value? {
+        number_data_point::Value::AsDouble(value) => Some(value),
+        number_data_point::Value::AsInt(value) => Some(value as f64),
+    }
+}
+
+fn no_recorded_value(flags: u32) -> bool {
+    flags & DataPointFlags::NoRecordedValueMask as u32 != 0
+}
+
+/// scalar attributes become labels, arrays, maps and bytes are dropped.
+fn labels(attributes: &[KeyValue]) -> MetricLabels {
+    let mut labels = attributes
+        .iter()
+        .filter_map(|attribute| {
+            let value = match attribute.value.as_ref()?.value.as_ref()? {
+                any_value::Value::StringValue(value) => value.to_string(),
+                any_value::Value::BoolValue(value) => value.to_string(),
+                any_value::Value::IntValue(value) => value.to_string(),
+                any_value::Value::DoubleValue(value) => value.to_string(),
+                _ => return None,
+            };
+
+            Some((attribute.key.to_string(), value))
+        })
+        .collect::<MetricLabels>();
+    labels.sort();
+    labels
+}
+
+fn inbound(name: &str, metric_type: MetricType, value: f64, labels: MetricLabels) -> InboundMetric {
+    InboundMetric {
+        name: name.to_string(),
+        value,
+        labels,
+        metric_type,
+        member: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::otlp::receiver::{decode_json, OtlpReceiver};
+    use crate::processor::InboundMetric;
+    use openmetrics_udpserver_lib::MetricType;
+
+    fn received(metrics: &[InboundMetric]) -> Vec<(MetricType, &str, f64)> {
+        metrics
+            .iter()
+            .map(|metric| (metric.metric_type, metric.name.as_str(), metric.value))
+            .collect()
+    }
+
+    /// a cumulative counter in the json encoding of OTLP/HTTP.
+    fn counter(start: u64, value: i64) -> Vec<u8> {
+        format!(
+            r#"{{"resourceMetrics":[{{"scopeMetrics":[{{"metrics":[{{
+                "name":"http.requests",
+                "sum":{{"aggregationTemporality":2,"isMonotonic":true,"dataPoints":[{{
+                    "attributes":[{{"key":"route","value":{{"stringValue":"/"}}}}],
+                    "startTimeUnixNano":"{}","timeUnixNano":"{}","asInt":"{}"
+                }}]}}
+            }}]}}]}}]}}"#,
+            start,
+            start + 1,
+            value
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn it_converts_cumulative_sums_to_deltas() {
+        let mut receiver = OtlpReceiver::new();
+        let before_agent = receiver.started_unix_nano - 1;
+        let after_agent = receiver.started_unix_nano + 1;
+
+        // the first point of a series older than the agent is only the baseline
+        let first = receiver.receive(decode_json(&counter(before_agent, 10)).unwrap());
+        assert!(first.metrics.is_empty());
+
+        let second = receiver.receive(decode_json(&counter(before_agent, 15)).unwrap());
+        assert_eq!(
+            vec![(MetricType::Sum, "http.requests", 5.0)],
+            received(&second.metrics)
+        );
+        assert_eq!(
+            vec![("route".to_string(), "/".to_string())],
+            second.metrics[0].labels
+        );
+
+        // the process restarted, everything since the new start is counted
+        let reset = receiver.receive(decode_json(&counter(after_agent, 3)).unwrap());
+        assert_eq!(
+            vec![(MetricType::Sum, "http.requests", 3.0)],
+            received(&reset.metrics)
+        );
+    }
+
+    #[test]
+    fn it_maps_gauges_and_histograms() {
+        let body = br#"{"resourceMetrics":[{"scopeMetrics":[{"metrics":[
+            {"name":"queue.size","gauge":{"dataPoints":[{"asDouble":4.5}]}},
+            {"name":"connections","sum":{"aggregationTemporality":1,"isMonotonic":false,"dataPoints":[{"asInt":"-2"}]}},
+            {"name":"latency","histogram":{"aggregationTemporality":1,"dataPoints":[{
+                "count":"3","sum":215,"bucketCounts":["1","0","2"],"explicitBounds":[10,100],"max":110
+            }]}},
+            {"name":"size","exponentialHistogram":{"aggregationTemporality":1,"dataPoints":[{"count":"1"}]}}
+        ]}]}]}"#;
+
+        let received_metrics = OtlpReceiver::new().receive(decode_json(body).unwrap());
+        assert_eq!(
+            vec![
+                (MetricType::Set, "queue.size", 4.5),
+                (MetricType::Delta, "connections", -2.0),
+                (MetricType::Histogram, "latency", 10.0),
+                (MetricType::Histogram, "latency", 110.0),
+                (MetricType::Histogram, "latency", 110.0),
+            ],
+            received(&received_metrics.metrics)
+        );
+        assert_eq!(1, received_metrics.rejected_points);
+    }
+
+    #[test]
+    fn it_limits_the_histogram_samples_and_cumulative_series_of_a_request() {
+        let body = br#"{"resourceMetrics":[{"scopeMetrics":[{"metrics":[
+            {"name":"latency","histogram":{"aggregationTemporality":1,"dataPoints":[
+                {"count":"2","bucketCounts":["2"],"explicitBounds":[]},
+                {"count":"2","bucketCounts":["2"],"explicitBounds":[]},
+                {"count":"1","bucketCounts":["1"],"explicitBounds":[]}
+            ]}}
+        ]}]}]}"#;
+
+        let mut receiver = OtlpReceiver::new();
+        receiver.max_histogram_samples = 3;
+        let received_metrics = receiver.receive(decode_json(body).unwrap());
+        assert_eq!(3, received_metrics.metrics.len());
+        assert_eq!(1, received_metrics.rejected_points);
+
+        receiver.max_cumulative_series = 1;
+        let after_agent = receiver.started_unix_nano + 1;
+        let first = receiver.receive(decode_json(&counter(after_agent, 1)).unwrap());
+        assert_eq!(0, first.rejected_points);
+
+        let body = String::from_utf8(counter(after_agent, 1))
+            .unwrap()
+            .replace("http.requests", "http.errors");
+        let second = receiver.receive(decode_json(body.as_bytes()).unwrap());
+        assert_eq!(1, second.rejected_points);
+        assert!(second.metrics.is_empty());
+    }
+}
@@ -0,0 +1,372 @@
+use crate::config::Config;
+use crate::otlp::OtlpState;
+use crate::processor::InboundMetric;
+use crate::sink::{send_request, Retry, SendError, Sink};
+use crate::window::Window;
+use crate::METRIC_COUNTER_OTLP_REQUESTS;
+use anyhow::{anyhow, Context};
+use clap::ArgMatches;
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use prost::Message;
+use reqwest::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// The encoding of the export requests.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtlpProtocol {
+    #[default]
+    Protobuf,
+    Json,
+}
+
+impl FromStr for OtlpProtocol {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "protobuf" => Ok(Self::Protobuf),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("expected 'protobuf' or 'json', got '{}'", value)),
+        }
+    }
+}
+
+/// Whether Sum and Histogram points cover a single window or everything since the series was
+/// first seen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtlpTemporality {
+    Delta,
+    #[default]
+    Cumulative,
+}
+
+impl FromStr for OtlpTemporality {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "delta" => Ok(Self::Delta),
+            "cumulative" => Ok(Self::Cumulative),
+            _ => Err(format!("expected 'delta' or 'cumulative', got '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtlpConfig {
+    pub enabled: bool,
+    /// e.g. `http://otel-collector:4318/v1/metrics`.
+    pub endpoint: String,
+    pub protocol: OtlpProtocol,
+    pub temporality: OtlpTemporality,
+    /// seconds between two exports.
+    pub flush_interval_secs: u64,
+    /// added to the resource, `host.name` and `service.name` are set unless given.
+    pub resource_attributes: BTreeMap<String, String>,
+    /// sent with every request, e.g. an `authorization` header.
+    pub headers: BTreeMap<String, String>,
+    /// retries of an export which failed with a network error, 408, 429 or 5xx.
+    pub max_retries: u32,
+    /// the delay before the first retry, doubled for every further retry.
+    pub retry_backoff_ms: u64,
+    pub timeout_secs: u64,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: "".to_string(),
+            protocol: OtlpProtocol::default(),
+            temporality: OtlpTemporality::default(),
+            flush_interval_secs: 15,
+            resource_attributes: BTreeMap::new(),
+            headers: BTreeMap::new(),
+            max_retries: 3,
+            retry_backoff_ms: 500,
+            timeout_secs: 10,
+        }
+    }
+}
+
+impl OtlpConfig {
+    pub fn apply_args(&mut self, matches: &ArgMatches) -> Result<(), anyhow::Error> {
+        if let Some(endpoint) = matches.get_one::<String>("otlp-endpoint") {
+            self.endpoint = endpoint.to_string();
+            self.enabled = true;
+        }
+
+        if let Some(protocol) = matches.get_one::<OtlpProtocol>("otlp-protocol") {
+            self.protocol = *protocol;
+        }
+
+        if let Some(temporality) = matches.get_one::<OtlpTemporality>("otlp-temporality") {
+            self.temporality = *temporality;
+        }
+
+        if let Some(flush_interval_secs) = matches.get_one::<u64>("otlp-interval") {
+            self.flush_interval_secs = *flush_interval_secs;
+        }
+
+        if let Some(attributes) = matches.get_many::<String>("otlp-resource-attribute") {
+            for attribute in attributes {
+                let (key, value) =
+                    parse_key_value(attribute).context("invalid '--otlp-resource-attribute'")?;
+                self.resource_attributes.insert(key, value);
+            }
+        }
+
+        if let Some(headers) = matches.get_many::<String>("otlp-header") {
+            for header in headers {
+                let (key, value) = parse_key_value(header).context("invalid '--otlp-header'")?;
+                self.headers.insert(key, value);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        Url::parse(&self.endpoint)
+            .map_err(|err| anyhow!("`endpoint`: invalid url '{}': {}", self.endpoint, err))?;
+
+        if self.flush_interval_secs == 0 {
+            return Err(anyhow!("`flush_interval_secs`: must be at least 1 second"));
+        }
+
+        if self.timeout_secs == 0 {
+            return Err(anyhow!("`timeout_secs`: must be at least 1 second"));
+        }
+
+        if self.resource_attributes.keys().any(|key| key.is_empty()) {
+            return Err(anyhow!("`resource_attributes`: names must not be empty"));
+        }
+
+        for (name, value) in &self.headers {
+            HeaderName::from_str(name)
+                .map_err(|_| anyhow!("`headers`: invalid header name '{}'", name))?;
+            HeaderValue::from_str(value)
+                .map_err(|_| anyhow!("`headers`: invalid value of header '{}'", name))?;
+        }
+
+        Ok(())
+    }
+
+    /// the configured resource attributes with the defaults for `host.name` and `service.name`.
+    pub fn resource_attributes(&self) -> BTreeMap<String, String> {
+        let mut attributes = BTreeMap::from([
+            (
+                "host.name".to_string(),
+                gethostname::gethostname().to_string_lossy().to_string(),
+            ),
+            (
+                "service.name".to_string(),
+                "openmetrics_udpserver".to_string(),
+            ),
+        ]);
+        attributes.extend(self.resource_attributes.clone());
+        attributes
+    }
+}
+
+fn parse_key_value(value: &str) -> Result<(String, String), anyhow::Error> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected key=value got '{}'", value))?;
+
+    Ok((key.trim().to_string(), value.trim().to_string()))
+}
+
+/// Exports the metrics to an OpenTelemetry collector via OTLP/HTTP.
+pub struct OtlpSink {
+    config: OtlpConfig,
+    state: OtlpState,
+    http_client: Client,
+}
+
+impl OtlpSink {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config: config.otlp.clone(),
+            state: OtlpState::new(config),
+            http_client: Client::new(),
+        }
+    }
+
+    /// retries transient failures until `max_retries` or the deadline is reached.
+    pub async fn export(
+        &self,
+        request: &ExportMetricsServiceRequest,
+        deadline: Instant,
+    ) -> Result<(), String> {
+        let (content_type, body) = match self.config.protocol {
+            OtlpProtocol::Protobuf => ("application/x-protobuf", request.encode_to_vec()),
+            OtlpProtocol::Json => (
+                "application/json",
+                serde_json::to_vec(request)
+                    .map_err(|err| format!("could not encode the request: {}", err))?,
+            ),
+        };
+
+        let retry = Retry {
+            max_retries: self.config.max_retries,
+            backoff: Duration::from_millis(self.config.retry_backoff_ms),
+            deadline: Some(deadline),
+        };
+
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        retry
+            .run(|| self.send(content_type, body.clone(), retry.timeout(timeout)))
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    async fn send(
+        &self,
+        content_type: &str,
+        body: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<(), SendError> {
+        let mut request = self
+            .http_client
+            .post(&self.config.endpoint)
+            .header(CONTENT_TYPE, content_type)
+            .timeout(timeout)
+            .body(body);
+
+        for (name, value) in &self.config.headers {
+            request = request.header(name, value);
+        }
+
+        send_request(request, &METRIC_COUNTER_OTLP_REQUESTS)
+            .await
+            .map(|_| ())
+    }
+}
+
+impl Sink for OtlpSink {
+    fn name(&self) -> &'static str {
+        "otlp"
+    }
+
+    fn flush_interval(&self) -> Duration {
+        Duration::from_secs(self.config.flush_interval_secs)
+    }
+
+    fn handle(&mut self, metric: InboundMetric) {
+        self.state.handle(metric);
+    }
+
+    async fn flush(&mut self, window: Window) -> Result<(), String> {
+        // retries must not delay the next flush
+        let deadline = Instant::now() + self.flush_interval();
+        let request = self.state.flush(window);
+        let metrics = request
+            .resource_metrics
+            .iter()
+            .flat_map(|resource| &resource.scope_metrics)
+            .map(|scope| scope.metrics.len())
+            .sum::<usize>();
+        if metrics == 0 {
+            return Ok(());
+        }
+
+        self.export(&request, deadline)
+            .await
+            .map_err(|e| format!("otlp export failed, dropping {} metrics: {}", metrics, e))
+    }
+
+    /// the metrics of the unfinished window are exported as well.
+    async fn shutdown(&mut self, window: Window) -> Result<(), String> {
+        self.flush(window).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::otlp::sink::{OtlpConfig, OtlpProtocol, OtlpSink};
+    use crate::processor::InboundMetric;
+    use crate::sink::stub::Stub;
+    use crate::window::Window;
+    use axum::http::StatusCode;
+    use openmetrics_udpserver_lib::MetricType;
+    use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(10)
+    }
+
+    async fn sink(stub: &Stub) -> OtlpSink {
+        OtlpSink::new(Config {
+            otlp: OtlpConfig {
+                enabled: true,
+                endpoint: stub.serve("/v1/metrics").await,
+                protocol: OtlpProtocol::Json,
+                resource_attributes: BTreeMap::from([(
+                    "host.name".to_string(),
+                    "web-1".to_string(),
+                )]),
+                headers: BTreeMap::from([(
+                    "authorization".to_string(),
+                    "Bearer secret".to_string(),
+                )]),
+                retry_backoff_ms: 1,
+                ..OtlpConfig::default()
+            },
+            ..Config::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn it_exports_json_and_retries_unavailable_collectors() {
+        let stub = Stub::new(&[StatusCode::SERVICE_UNAVAILABLE]);
+        let mut sink = sink(&stub).await;
+
+        sink.state.handle(InboundMetric {
+            name: "requests".to_string(),
+            value: 1.0,
+            labels: vec![],
+            metric_type: MetricType::Sum,
+            member: None,
+        });
+        let request = sink.state.flush(Window {
+            start: UNIX_EPOCH,
+            end: UNIX_EPOCH + Duration::from_secs(10),
+        });
+        assert_eq!(Ok(()), sink.export(&request, deadline()).await);
+
+        let requests = stub.requests();
+        assert_eq!(2, requests.len());
+        let (headers, body) = &requests[1];
+        let body = std::str::from_utf8(body).unwrap();
+        assert_eq!("application/json", headers["content-type"]);
+        assert_eq!("Bearer secret", headers["authorization"]);
+
+        let decoded: ExportMetricsServiceRequest = serde_json::from_str(body).unwrap();
+        assert_eq!(request, decoded);
+        assert!(body.contains(r#""stringValue":"web-1""#), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn it_does_not_retry_rejected_exports() {
+        let stub = Stub::new(&[StatusCode::BAD_REQUEST]);
+        let sink = sink(&stub).await;
+
+        let err = sink
+            .export(&ExportMetricsServiceRequest::default(), deadline())
+            .await
+            .unwrap_err();
+        assert!(err.contains("400"), "{}", err);
+        assert_eq!(1, stub.requests().len());
+    }
+}
@@ -1,45 +1,216 @@
 use crate::aggregator::average::AggragatorAverageGauge;
 use crate::aggregator::min::AggragatorMinGauge;
 use crate::aggregator::peak::AggragatorPeakGauge;
-use crate::config::Config;
-use crate::METRIC_COUNTER_ERRORS;
+use crate::aggregator::quantile::{AggragatorQuantileGauge, QuantileSummary};
+use crate::aggregator::timing::AggragatorTimingGauge;
+use crate::aggregator::unique::AggragatorUniqueGauge;
+use crate::collector::{MetricCollector, SummaryFamily};
+use crate::config::{Config, StalePolicy};
+use crate::limits::{overflow_labels, SeriesLimiter, OVERFLOW_SERIES};
+use crate::sink::Sink;
+use crate::window::{unix_seconds, Window};
+use crate::{
+    METRIC_COUNTER_ERRORS, METRIC_COUNTER_EVICTED_SERIES, METRIC_COUNTER_REJECTED_SAMPLES,
+    METRIC_COUNTER_TYPE_CONFLICTS,
+};
 use openmetrics_udpserver_lib::MetricType;
 use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::{Family, MetricConstructor};
 use prometheus_client::metrics::gauge::Gauge;
-use prometheus_client::registry::Registry;
+use prometheus_client::metrics::histogram::Histogram;
 use regex::Regex;
 use std::collections::hash_map::Entry;
-use std::sync::atomic::{AtomicI64, AtomicU64};
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::broadcast::Receiver;
-use tokio::sync::RwLock;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::AtomicU64;
+use std::sync::PoisonError;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::Sender;
+
+pub type MetricLabels = Vec<(String, String)>;
 
 #[derive(Debug, Clone)]
 pub struct InboundMetric {
     pub name: String,
-    pub count: i32,
+    pub value: f64,
+    pub labels: MetricLabels,
     pub metric_type: MetricType,
+    /// the member of a Unique metric, e.g. a user id.
+    pub member: Option<String>,
+}
+
+/// Why a metric was rejected before reaching the pipelines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// counters only go up, prometheus would take a decrease for a reset.
+    NegativeSum,
+    NonFiniteValue,
+    /// a Unique metric without member or a member for any other type.
+    InvalidMember,
+}
+
+impl RejectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NegativeSum => "negative_sum",
+            Self::NonFiniteValue => "non_finite_value",
+            Self::InvalidMember => "invalid_member",
+        }
+    }
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl InboundMetric {
+    /// the value policy, shared by all sources so that every pipeline sees the same metrics.
+    /// All types but Sum accept signed values.
+    pub fn validate(&self) -> Result<(), RejectReason> {
+        if !self.value.is_finite() {
+            return Err(RejectReason::NonFiniteValue);
+        }
+
+        if self.metric_type == MetricType::Sum && self.value < 0.0 {
+            return Err(RejectReason::NegativeSum);
+        }
+
+        if (self.metric_type == MetricType::Unique) != self.member.is_some() {
+            return Err(RejectReason::InvalidMember);
+        }
+
+        Ok(())
+    }
+}
+
+/// validates the metric and sends it to all pipelines, rejected metrics are counted by reason.
+/// Names are normalized by each pipeline, label values only lose the characters the open metrics
+/// encoder does not escape.
+pub fn publish_metric(
+    sender: &Sender<InboundMetric>,
+    mut metric: InboundMetric,
+) -> Result<(), String> {
+    for (_, value) in metric.labels.iter_mut() {
+        if value.contains(['"', '\\', '\n']) {
+            *value = value.replace(['"', '\\', '\n'], "");
+        }
+    }
+
+    if let Err(reason) = metric.validate() {
+        METRIC_COUNTER_REJECTED_SAMPLES
+            .get_or_create(&vec![("reason", reason.as_str())])
+            .inc();
+
+        return Err(format!("rejected metric {}: {}", metric.name, reason));
+    }
+
+    sender.send(metric).map(|_| ()).map_err(|err| {
+        METRIC_COUNTER_ERRORS.inc();
+        format!("Unable to process inbound metric: {}", err)
+    })
 }
 
+/// Identifies a single series, a metric name together with its (sorted) labels.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SeriesKey {
+    pub name: String,
+    pub labels: MetricLabels,
+}
+
+/// Sum and Histogram are cumulative and updated on every metric. Average, Peak, Min, Unique and
+/// Timing are aggregated per flush window, each aggregator starts empty in every window. On flush the result
+/// of the window is written to a gauge which keeps it until the next flush.
+///
+/// A gauge series which did not receive a value for `stale_windows` windows is stale and, depending
+/// on the `stale_policy`, either set to NaN or removed from the exposition.
+///
+/// Average and Timing metrics matching a quantile prefix additionally write the quantiles, sum and
+/// count of the window to the `<name>_quantile` summary, it stales like the average. Timing writes
+/// its derived aggregates to `<name>_count`, `<name>_sum`, `<name>_min`, `<name>_max` and
+/// `<name>_mean`.
+///
+/// Set and Delta write directly to a gauge, the last value wins or the value is added. Like counters
+/// they are not aggregated per window and never stale.
+///
+/// Independent of their type, series which did not receive any metric for `series_ttl_secs` are
+/// evicted. Families without any series left are removed from the exposition.
+///
+/// A name belongs to the kind of family (counter, gauge, histogram or summary) it was created with, samples
+/// of a metric type with another kind of family are dropped and counted as type conflicts.
+///
+/// New series have to fit into the configured limits, samples of series beyond them are either
+/// dropped or counted in the `udpagent_overflow` series. Timing counts all of its derived series.
+/// Removed series free their slot again.
 pub struct Processor {
     config: Config,
-    counters: ::fnv::FnvHashMap<String, Counter>,
-    gauges: ::fnv::FnvHashMap<String, Gauge>,
+    counters: ::fnv::FnvHashMap<String, Family<MetricLabels, Counter<f64, AtomicU64>>>,
+    gauges: ::fnv::FnvHashMap<String, Family<MetricLabels, Gauge<f64, AtomicU64>>>,
+    histograms: ::fnv::FnvHashMap<String, Family<MetricLabels, Histogram, HistogramConstructor>>,
+    summaries: ::fnv::FnvHashMap<String, SummaryFamily>,
     aggregator_peak_gauge: AggragatorPeakGauge,
     aggregator_min_gauge: AggragatorMinGauge,
     aggregator_average_gauge: AggragatorAverageGauge,
-    metric_registry: Arc<RwLock<Registry>>,
+    aggregator_quantile_gauge: AggragatorQuantileGauge,
+    aggregator_unique_gauge: AggragatorUniqueGauge,
+    aggregator_timing_gauge: AggragatorTimingGauge,
+    /// the number of flushed windows, the windows are numbered starting with 1.
+    flushed_windows: u64,
+    /// the window a gauge series was updated the last time.
+    gauge_updates: ::fnv::FnvHashMap<SeriesKey, u64>,
+    /// the last time a series received a metric, only tracked if a series ttl is configured.
+    series_last_seen: ::fnv::FnvHashMap<SeriesKey, Instant>,
+    series_limiter: SeriesLimiter,
+    metric_collector: MetricCollector,
+    regex_allowed_chars: Regex,
+    /// the names of the gauge families with Set or Delta series, they are never stale.
+    direct_gauge_names: ::fnv::FnvHashSet<String>,
+    /// the kind of family of every name in the exposition.
+    family_kinds: ::fnv::FnvHashMap<String, FamilyKind>,
+    /// the names with a type conflict which were already logged.
+    conflicts: ::fnv::FnvHashSet<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FamilyKind {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
+}
+
+impl FamilyKind {
+    fn of(metric_type: MetricType) -> Self {
+        match metric_type {
+            MetricType::Sum => FamilyKind::Counter,
+            MetricType::Histogram => FamilyKind::Histogram,
+            _ => FamilyKind::Gauge,
+        }
+    }
+}
+
+/// Creates the histograms of a family, all series of a metric name share the same buckets.
+#[derive(Debug, Clone)]
+pub struct HistogramConstructor {
+    buckets: Vec<f64>,
+}
+
+impl MetricConstructor<Histogram> for HistogramConstructor {
+    fn new_metric(&self) -> Histogram {
+        Histogram::new(self.buckets.iter().copied())
+    }
 }
 
 pub struct ProcessorMetric {
     pub name: String,
-    pub count: u64,
+    pub value: f64,
+    pub labels: MetricLabels,
     pub metric_type: MetricType,
+    pub member: Option<String>,
 }
 
 impl ProcessorMetric {
-    pub fn from_inbound(name: String, inbound_metric: InboundMetric) -> Self {
+    pub fn from_inbound(name: String, labels: MetricLabels, inbound_metric: InboundMetric) -> Self {
         let name = match inbound_metric.metric_type {
             // this is some kind of legacy. we would end up with _total_total because the application is already sending _total and the client is also appending _total
             MetricType::Sum => name.trim_end_matches("_total").to_string(),
@@ -48,125 +219,890 @@ impl ProcessorMetric {
 
         Self {
             name,
-            count: inbound_metric.count as u64,
+            value: inbound_metric.value,
+            labels,
             metric_type: inbound_metric.metric_type,
+            member: inbound_metric.member,
+        }
+    }
+
+    /// normalizes name and labels to the open metrics naming rules, `None` if nothing is left of
+    /// the name.
+    pub fn normalize(regex_allowed_chars: &Regex, inbound_metric: InboundMetric) -> Option<Self> {
+        let metric_name = regex_allowed_chars
+            .replace_all(&inbound_metric.name.replace('.', "_"), "")
+            .trim()
+            .to_string();
+
+        if metric_name.is_empty() {
+            return None;
+        }
+
+        let labels = Self::normalize_labels(regex_allowed_chars, &inbound_metric.labels);
+        Some(Self::from_inbound(metric_name, labels, inbound_metric))
+    }
+
+    /// label keys follow the same naming rules as metric names. the labels are sorted, so that the
+    /// same set of labels always ends up in the same series, duplicated keys keep the last value.
+    fn normalize_labels(regex_allowed_chars: &Regex, labels: &MetricLabels) -> MetricLabels {
+        let mut normalized: MetricLabels = Vec::with_capacity(labels.len());
+        for (key, value) in labels {
+            let key = regex_allowed_chars
+                .replace_all(&key.replace('.', "_"), "")
+                .trim()
+                .to_string();
+
+            if key.is_empty() {
+                continue;
+            }
+
+            normalized.retain(|(k, _)| k != &key);
+            normalized.push((key, value.to_string()));
+        }
+
+        normalized.sort();
+        normalized
+    }
+
+    /// the sample is counted in the overflow series, which is not limited.
+    pub fn fold_into_overflow(&mut self, rule: String) {
+        self.name = OVERFLOW_SERIES.to_string();
+        self.labels = overflow_labels(rule, self.metric_type);
+        self.metric_type = MetricType::Sum;
+        self.value = 1.0;
+        self.member = None;
+    }
+
+    pub fn series_key(&self) -> SeriesKey {
+        SeriesKey {
+            name: self.name.clone(),
+            labels: self.labels.clone(),
         }
     }
 }
 
 impl Processor {
-    pub fn new(config: Config, metric_registry: Arc<RwLock<Registry>>) -> Self {
+    pub fn new(config: Config, metric_collector: MetricCollector) -> Self {
         Processor {
-            config,
+            series_limiter: SeriesLimiter::new(config.limits.clone(), config.quantiles.clone()),
             counters: ::fnv::FnvHashMap::default(),
             gauges: ::fnv::FnvHashMap::default(),
+            histograms: ::fnv::FnvHashMap::default(),
+            summaries: ::fnv::FnvHashMap::default(),
             aggregator_peak_gauge: AggragatorPeakGauge::new(),
             aggregator_min_gauge: AggragatorMinGauge::new(),
             aggregator_average_gauge: AggragatorAverageGauge::new(),
-            metric_registry,
+            aggregator_quantile_gauge: AggragatorQuantileGauge::new(config.quantiles.clone()),
+            aggregator_unique_gauge: AggragatorUniqueGauge::new(),
+            aggregator_timing_gauge: AggragatorTimingGauge::new(config.quantiles.clone()),
+            flushed_windows: 0,
+            gauge_updates: ::fnv::FnvHashMap::default(),
+            series_last_seen: ::fnv::FnvHashMap::default(),
+            metric_collector,
+            regex_allowed_chars: Self::regex_allowed_chars(),
+            direct_gauge_names: ::fnv::FnvHashSet::default(),
+            family_kinds: ::fnv::FnvHashMap::default(),
+            conflicts: ::fnv::FnvHashSet::default(),
+            config,
+        }
+    }
+
+    pub fn regex_allowed_chars() -> Regex {
+        Regex::new(r"^[^a-zA-Z_:]|[^a-zA-Z0-9_:]")
+            .expect("Unable to compile metrics naming regex, should not happen")
+    }
+
+    fn handle_aggragation_flush(&mut self) {
+        self.flushed_windows += 1;
+
+        for (k, v) in self.aggregator_average_gauge.reset_and_fetch().into_iter() {
+            self.handle_gauge(k, v)
+        }
+
+        let summaries = self.aggregator_quantile_gauge.reset_and_fetch_summaries();
+        self.handle_summaries(summaries);
+
+        let (timings, summaries) = self.aggregator_timing_gauge.reset_and_fetch_summaries();
+        self.handle_derived_gauges(timings);
+        self.handle_summaries(summaries);
+
+        for (k, v) in self.aggregator_min_gauge.reset_and_fetch().into_iter() {
+            self.handle_gauge(k, v)
+        }
+
+        for (k, v) in self.aggregator_peak_gauge.reset_and_fetch().into_iter() {
+            self.handle_gauge(k, v)
+        }
+
+        for (k, v) in self.aggregator_unique_gauge.reset_and_fetch().into_iter() {
+            self.handle_gauge(k, v)
+        }
+
+        self.handle_stale_gauges();
+    }
+
+    /// derived series only exist on flush, they are idle once the series they derive from is.
+    fn handle_derived_gauges(&mut self, gauges: ::fnv::FnvHashMap<SeriesKey, f64>) {
+        for (k, v) in gauges.into_iter() {
+            if self.config.series_ttl_secs > 0 {
+                self.series_last_seen.insert(k.clone(), Instant::now());
+            }
+            self.handle_gauge(k, v)
+        }
+    }
+
+    /// the summaries of the `<name>_quantile` families, they are idle and stale like derived gauges.
+    fn handle_summaries(&mut self, summaries: ::fnv::FnvHashMap<SeriesKey, QuantileSummary>) {
+        for (series, summary) in summaries {
+            let series = SeriesKey {
+                name: format!("{}_quantile", series.name),
+                labels: series.labels,
+            };
+            if !self.claim_name(&series.name, FamilyKind::Summary) {
+                continue;
+            }
+
+            if self.config.series_ttl_secs > 0 {
+                self.series_last_seen.insert(series.clone(), Instant::now());
+            }
+
+            let family = match self.summaries.entry(series.name.clone()) {
+                Entry::Occupied(v) => v.into_mut(),
+                Entry::Vacant(vacant) => {
+                    let family = SummaryFamily::default();
+                    self.metric_collector
+                        .insert_summary(series.name.clone(), family.clone());
+
+                    vacant.insert(family)
+                }
+            };
+
+            family
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(series.labels.clone(), summary);
+            self.gauge_updates.insert(series, self.flushed_windows);
         }
     }
 
-    pub async fn run(&mut self, mut receiver: Receiver<InboundMetric>) {
-        let regex_allowed_chars = Regex::new(r"^[^a-zA-Z_:]|[^a-zA-Z0-9_:]")
-            .expect("Unable to compile metrics naming regex, should not happen");
+    fn handle_stale_gauges(&mut self) {
+        if self.config.stale_windows == 0 {
+            return;
+        }
 
-        let mut aggregation_interval = ::tokio::time::interval(Duration::from_secs(30));
+        let flushed_windows = self.flushed_windows;
+        let stale_windows = self.config.stale_windows as u64;
+        let stale_policy = self.config.stale_policy;
+        let gauges = &self.gauges;
+        let summaries = &self.summaries;
+        let series_limiter = &mut self.series_limiter;
+        let mut removed_names = ::fnv::FnvHashSet::default();
 
-        loop {
-            ::tokio::select! {
-                _ = aggregation_interval.tick() => {
-                    self.handle_aggragation_flush().await
-                },
-                msg = receiver.recv() => {
-                    match msg {
-                        Ok(inbound_metric) => self.handle_metric(&regex_allowed_chars, inbound_metric).await,
-                        Err(e) => {
-                            METRIC_COUNTER_ERRORS.inc();
-                            eprintln!("processor recv error {:#?}, investigate!", e);
-                            ::tokio::time::sleep(Duration::from_millis(300)).await;
+        self.gauge_updates.retain(|series, last_update| {
+            if flushed_windows - *last_update < stale_windows {
+                return true;
+            }
+
+            match (stale_policy, gauges.get(&series.name)) {
+                (StalePolicy::Nan, Some(family)) => {
+                    family.get_or_create(&series.labels).set(f64::NAN);
+                    return true;
+                }
+                (StalePolicy::Remove, Some(family)) => {
+                    family.remove(&series.labels);
+                }
+                (_, None) => {
+                    let Some(family) = summaries.get(&series.name) else {
+                        return false;
+                    };
+
+                    let mut family = family.write().unwrap_or_else(PoisonError::into_inner);
+                    if stale_policy == StalePolicy::Nan {
+                        if let Some(summary) = family.get_mut(&series.labels) {
+                            summary
+                                .quantiles
+                                .iter_mut()
+                                .for_each(|(_, v)| *v = f64::NAN);
                         }
+                        return true;
                     }
+
+                    family.remove(&series.labels);
                 }
             }
+
+            series_limiter.release(series);
+            removed_names.insert(series.name.clone());
+            false
+        });
+
+        if removed_names.is_empty() {
+            return;
+        }
+
+        for series in self.gauge_updates.keys() {
+            removed_names.remove(&series.name);
+        }
+
+        for name in removed_names {
+            if self.direct_gauge_names.contains(&name) {
+                continue;
+            }
+
+            self.gauges.remove(&name);
+            self.summaries.remove(&name);
+            self.family_kinds.remove(&name);
+            self.metric_collector.remove(&name);
         }
     }
 
-    async fn handle_aggragation_flush(&mut self) {
-        for (k, v) in self.aggregator_average_gauge.reset_and_fetch().into_iter() {
-            self.handle_gauge(k, v).await
+    fn evict_idle_series(&mut self) {
+        if self.config.series_ttl_secs == 0 {
+            return;
         }
 
-        for (k, v) in self.aggregator_min_gauge.reset_and_fetch().into_iter() {
-            self.handle_gauge(k, v).await
+        let series_ttl = Duration::from_secs(self.config.series_ttl_secs);
+        let now = Instant::now();
+        let mut evicted = vec![];
+        self.series_last_seen.retain(|series, last_seen| {
+            if now.duration_since(*last_seen) < series_ttl {
+                return true;
+            }
+
+            evicted.push(series.clone());
+            false
+        });
+
+        if evicted.is_empty() {
+            return;
         }
 
-        for (k, v) in self.aggregator_peak_gauge.reset_and_fetch().into_iter() {
-            self.handle_gauge(k, v).await
+        METRIC_COUNTER_EVICTED_SERIES.inc_by(evicted.len() as u64);
+
+        for series in &evicted {
+            if let Some(family) = self.counters.get(&series.name) {
+                family.remove(&series.labels);
+            }
+
+            if let Some(family) = self.gauges.get(&series.name) {
+                family.remove(&series.labels);
+            }
+
+            if let Some(family) = self.histograms.get(&series.name) {
+                family.remove(&series.labels);
+            }
+
+            if let Some(family) = self.summaries.get(&series.name) {
+                family
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .remove(&series.labels);
+            }
+
+            self.gauge_updates.remove(series);
+            self.series_limiter.release(series);
         }
-    }
 
-    async fn handle_metric(&mut self, regex_allowed_chars: &Regex, inbound_metric: InboundMetric) {
-        let metric_name = regex_allowed_chars
-            .replace_all(&inbound_metric.name.replace('.', "_"), "")
-            .trim()
-            .to_string();
+        let alive_names = self
+            .series_last_seen
+            .keys()
+            .map(|series| series.name.as_str())
+            .collect::<::fnv::FnvHashSet<&str>>();
 
-        if metric_name.is_empty() {
+        for series in &evicted {
+            if alive_names.contains(series.name.as_str()) {
+                continue;
+            }
+
+            self.counters.remove(&series.name);
+            self.gauges.remove(&series.name);
+            self.histograms.remove(&series.name);
+            self.summaries.remove(&series.name);
+            self.direct_gauge_names.remove(&series.name);
+            self.family_kinds.remove(&series.name);
+            self.metric_collector.remove(&series.name);
+        }
+    }
+
+    fn handle_metric(&mut self, inbound_metric: InboundMetric) {
+        let Some(processor_metric) =
+            ProcessorMetric::normalize(&self.regex_allowed_chars, inbound_metric)
+        else {
             eprintln!("got empty metric name");
             return;
+        };
+
+        // the derived series of timings are claimed on flush
+        let claims_name = |metric: &ProcessorMetric| metric.metric_type != MetricType::Timing;
+        let kind = FamilyKind::of(processor_metric.metric_type);
+        if claims_name(&processor_metric) && !self.can_claim_name(&processor_metric.name, kind) {
+            return;
         }
 
-        let processor_metric = ProcessorMetric::from_inbound(metric_name, inbound_metric);
+        let Some(processor_metric) = self
+            .series_limiter
+            .admit_metric(self.name(), processor_metric)
+        else {
+            return;
+        };
+
+        // folded samples claim the overflow series
+        let kind = FamilyKind::of(processor_metric.metric_type);
+        if claims_name(&processor_metric) && !self.claim_name(&processor_metric.name, kind) {
+            return;
+        }
 
         if self.config.debug {
             println!(
-                "got metric [type={:?}, name={}, count={}]",
-                &processor_metric.metric_type, &processor_metric.name, &processor_metric.count
+                "got metric [type={:?}, name={}, value={}, labels={:?}]",
+                &processor_metric.metric_type,
+                &processor_metric.name,
+                &processor_metric.value,
+                &processor_metric.labels
             );
         }
 
+        if self.config.series_ttl_secs > 0 {
+            self.series_last_seen
+                .insert(processor_metric.series_key(), Instant::now());
+        }
+
         match processor_metric.metric_type {
             MetricType::Peak => self.aggregator_peak_gauge.handle(&processor_metric),
             MetricType::Min => self.aggregator_min_gauge.handle(&processor_metric),
-            MetricType::Average => self.aggregator_average_gauge.handle(&processor_metric),
-            MetricType::Sum => self.handle_counter(&processor_metric).await,
+            MetricType::Average => {
+                self.aggregator_average_gauge.handle(&processor_metric);
+                self.aggregator_quantile_gauge.handle(&processor_metric);
+            }
+            MetricType::Sum => self.handle_counter(&processor_metric),
+            MetricType::Histogram => self.handle_histogram(&processor_metric),
+            MetricType::Set => self.handle_set(&processor_metric),
+            MetricType::Delta => self.handle_delta(&processor_metric),
+            MetricType::Unique => self.aggregator_unique_gauge.handle(&processor_metric),
+            MetricType::Timing => self.aggregator_timing_gauge.handle(&processor_metric),
         }
     }
 
-    async fn handle_counter(&mut self, metric: &ProcessorMetric) {
-        match self.counters.entry(metric.name.clone()) {
-            Entry::Occupied(mut v) => {
-                v.get_mut().inc_by(metric.count);
+    fn handle_counter(&mut self, metric: &ProcessorMetric) {
+        let family = match self.counters.entry(metric.name.clone()) {
+            Entry::Occupied(v) => v.into_mut(),
+            Entry::Vacant(vacant) => {
+                let family = Family::<MetricLabels, Counter<f64, AtomicU64>>::default();
+                self.metric_collector
+                    .insert(metric.name.clone(), family.clone());
+
+                vacant.insert(family)
             }
+        };
+
+        family.get_or_create(&metric.labels).inc_by(metric.value);
+    }
+
+    fn handle_histogram(&mut self, metric: &ProcessorMetric) {
+        let family = match self.histograms.entry(metric.name.clone()) {
+            Entry::Occupied(v) => v.into_mut(),
             Entry::Vacant(vacant) => {
-                let counter = Counter::<u64, AtomicU64>::default();
-                counter.inc_by(metric.count);
-                vacant.insert(counter.clone());
+                let family = Family::new_with_constructor(HistogramConstructor {
+                    buckets: self.config.histogram_buckets_for(&metric.name).to_vec(),
+                });
+                self.metric_collector
+                    .insert(metric.name.clone(), family.clone());
 
-                {
-                    let mut registry = self.metric_registry.write().await;
-                    registry.register(metric.name.clone(), metric.name.clone(), counter)
-                }
+                vacant.insert(family)
             }
+        };
+
+        family.get_or_create(&metric.labels).observe(metric.value);
+    }
+
+    fn handle_gauge(&mut self, series: SeriesKey, metric_value: f64) {
+        if !self.claim_name(&series.name, FamilyKind::Gauge) {
+            return;
         }
+
+        self.gauge_family(&series.name)
+            .get_or_create(&series.labels)
+            .set(metric_value);
+        self.gauge_updates.insert(series, self.flushed_windows);
     }
 
-    async fn handle_gauge(&mut self, metric_name: String, metric_count: u64) {
-        match self.gauges.entry(metric_name.clone()) {
-            Entry::Occupied(mut v) => {
-                v.get_mut().set(metric_count as i64);
-            }
+    fn handle_set(&mut self, metric: &ProcessorMetric) {
+        self.track_direct_gauge(&metric.name);
+        self.gauge_family(&metric.name)
+            .get_or_create(&metric.labels)
+            .set(metric.value);
+    }
+
+    fn handle_delta(&mut self, metric: &ProcessorMetric) {
+        self.track_direct_gauge(&metric.name);
+        self.gauge_family(&metric.name)
+            .get_or_create(&metric.labels)
+            .inc_by(metric.value);
+    }
+
+    fn track_direct_gauge(&mut self, name: &str) {
+        if !self.direct_gauge_names.contains(name) {
+            self.direct_gauge_names.insert(name.to_string());
+        }
+    }
+
+    /// whether the name can be used for a family of the kind, a conflict is counted.
+    fn can_claim_name(&mut self, name: &str, kind: FamilyKind) -> bool {
+        let Some(claimed) = self.family_kinds.get(name).copied() else {
+            return true;
+        };
+
+        if claimed == kind {
+            return true;
+        }
+
+        METRIC_COUNTER_TYPE_CONFLICTS.inc();
+        if self.conflicts.insert(name.to_string()) {
+            eprintln!(
+                "metrics of different types are named {}, only the {:?} is exposed",
+                name, claimed
+            );
+        }
+
+        false
+    }
+
+    /// like `can_claim_name`, the first kind claims the name.
+    fn claim_name(&mut self, name: &str, kind: FamilyKind) -> bool {
+        if !self.can_claim_name(name, kind) {
+            return false;
+        }
+
+        self.family_kinds.entry(name.to_string()).or_insert(kind);
+        true
+    }
+
+    fn gauge_family(&mut self, name: &str) -> &Family<MetricLabels, Gauge<f64, AtomicU64>> {
+        match self.gauges.entry(name.to_string()) {
+            Entry::Occupied(v) => v.into_mut(),
             Entry::Vacant(vacant) => {
-                let gauge = Gauge::<i64, AtomicI64>::default();
-                gauge.set(metric_count as i64);
-                vacant.insert(gauge.clone());
+                let family = Family::<MetricLabels, Gauge<f64, AtomicU64>>::default();
+                self.metric_collector
+                    .insert(name.to_string(), family.clone());
 
-                {
-                    let mut registry = self.metric_registry.write().await;
-                    registry.register(metric_name.clone(), metric_name.clone(), gauge)
-                }
+                vacant.insert(family)
             }
         }
     }
 }
+
+/// The prometheus pull sink, the families are served by the http server on `/metrics`.
+impl Sink for Processor {
+    fn name(&self) -> &'static str {
+        "prometheus"
+    }
+
+    fn flush_interval(&self) -> Duration {
+        Duration::from_secs(self.config.flush_interval_secs)
+    }
+
+    fn start(&mut self, window: Window) {
+        if self.config.debug {
+            println!(
+                "first aggregation window {}-{}",
+                unix_seconds(window.start),
+                unix_seconds(window.end)
+            );
+        }
+    }
+
+    fn handle(&mut self, metric: InboundMetric) {
+        self.handle_metric(metric);
+    }
+
+    async fn flush(&mut self, window: Window) -> Result<(), String> {
+        if self.config.debug {
+            println!(
+                "flushing aggregations of window {}-{}",
+                unix_seconds(window.start),
+                unix_seconds(window.end)
+            );
+        }
+
+        self.handle_aggragation_flush();
+        self.evict_idle_series();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collector::{encode, MetricCollector};
+    use crate::config::{Config, LimitsConfig, OverflowPolicy, QuantilesConfig, StalePolicy};
+    use crate::processor::{publish_metric, InboundMetric, Processor, RejectReason};
+    use crate::METRIC_COUNTER_TYPE_CONFLICTS;
+    use openmetrics_udpserver_lib::MetricType;
+    use prometheus_client::registry::Registry;
+    use std::time::{Duration, Instant};
+
+    fn processor(config: Config) -> (Processor, (Registry, MetricCollector)) {
+        let metric_collector = MetricCollector::default();
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(metric_collector.clone()));
+
+        let processor = Processor::new(config, metric_collector.clone());
+        (processor, (registry, metric_collector))
+    }
+
+    fn send(processor: &mut Processor, metric_type: MetricType, name: &str, value: f64) {
+        let inbound_metric = InboundMetric {
+            name: name.to_string(),
+            value,
+            labels: vec![],
+            metric_type,
+            member: None,
+        };
+
+        processor.handle_metric(inbound_metric);
+    }
+
+    fn exposition((registry, metric_collector): &(Registry, MetricCollector)) -> String {
+        let mut buffer = String::new();
+        encode(&mut buffer, registry, metric_collector).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn it_resets_the_aggregations_every_window() {
+        let (mut processor, registry) = processor(Config::default());
+
+        send(&mut processor, MetricType::Average, "avg", 10.0);
+        send(&mut processor, MetricType::Average, "avg", 20.0);
+        send(&mut processor, MetricType::Peak, "peak", 10.0);
+        processor.handle_aggragation_flush();
+        assert!(exposition(&registry).contains("avg{} 15.0\n"));
+
+        send(&mut processor, MetricType::Average, "avg", 2.0);
+        send(&mut processor, MetricType::Peak, "peak", 3.0);
+        processor.handle_aggragation_flush();
+        let exposition = exposition(&registry);
+        assert!(exposition.contains("avg{} 2.0\n"), "{}", exposition);
+        assert!(exposition.contains("peak{} 3.0\n"), "{}", exposition);
+    }
+
+    #[test]
+    fn it_exports_quantiles_of_averages() {
+        let (mut processor, registry) = processor(Config {
+            quantiles: QuantilesConfig {
+                prefixes: vec!["checkout.".to_string()],
+                quantiles: vec![0.5, 0.99],
+                ..QuantilesConfig::default()
+            },
+            stale_windows: 1,
+            ..Config::default()
+        });
+
+        for value in 1..=98 {
+            send(&mut processor, MetricType::Average, "checkout.latency", 1.0);
+            send(
+                &mut processor,
+                MetricType::Average,
+                "search.latency",
+                value as f64,
+            );
+        }
+        send(
+            &mut processor,
+            MetricType::Average,
+            "checkout.latency",
+            5000.0,
+        );
+        send(
+            &mut processor,
+            MetricType::Average,
+            "checkout.latency",
+            5000.0,
+        );
+        processor.handle_aggragation_flush();
+
+        let text = exposition(&registry);
+        let quantile = |quantile: &str| -> f64 {
+            let series = format!("checkout_latency_quantile{{quantile=\"{}\"}} ", quantile);
+            let line = text.lines().find(|line| line.starts_with(&series));
+            line.expect(&text)[series.len()..].parse().unwrap()
+        };
+        // within the relative accuracy of 1%, the outliers do not disappear
+        assert!((quantile("0.5") - 1.0).abs() <= 0.01);
+        assert!((quantile("0.99") - 5000.0).abs() <= 50.0);
+        assert!(
+            text.contains("# TYPE checkout_latency_quantile summary\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("checkout_latency_quantile_sum{} 10098.0\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("checkout_latency_quantile_count{} 100\n"),
+            "{}",
+            text
+        );
+        assert!(!text.contains("search_latency_quantile"), "{}", text);
+
+        // stale like the average itself
+        processor.handle_aggragation_flush();
+        assert!(!exposition(&registry).contains("checkout_latency_quantile{"));
+    }
+
+    #[test]
+    fn it_derives_aggregates_from_timings() {
+        let (mut processor, registry) = processor(Config::default());
+
+        for value in [30.0, 10.0, 20.0] {
+            send(&mut processor, MetricType::Timing, "db.query", value);
+        }
+        processor.handle_aggragation_flush();
+
+        let exposition = exposition(&registry);
+        for series in [
+            "db_query_count{} 3.0\n",
+            "db_query_sum{} 60.0\n",
+            "db_query_min{} 10.0\n",
+            "db_query_max{} 30.0\n",
+            "db_query_mean{} 20.0\n",
+        ] {
+            assert!(exposition.contains(series), "{}", exposition);
+        }
+        assert!(!exposition.contains("db_query_quantile"), "{}", exposition);
+    }
+
+    #[test]
+    fn it_removes_stale_series() {
+        let config = Config {
+            stale_windows: 2,
+            stale_policy: StalePolicy::Remove,
+            ..Config::default()
+        };
+        let (mut processor, registry) = processor(config);
+
+        send(&mut processor, MetricType::Min, "min", 5.0);
+        processor.handle_aggragation_flush();
+        processor.handle_aggragation_flush();
+        assert!(exposition(&registry).contains("min{} 5.0\n"));
+
+        processor.handle_aggragation_flush();
+        let exposition_without_min = exposition(&registry);
+        assert!(
+            !exposition_without_min.contains("min"),
+            "{}",
+            exposition_without_min
+        );
+
+        send(&mut processor, MetricType::Min, "min", 7.0);
+        processor.handle_aggragation_flush();
+        assert!(exposition(&registry).contains("min{} 7.0\n"));
+    }
+
+    #[test]
+    fn it_marks_stale_series_as_nan() {
+        let config = Config {
+            stale_windows: 1,
+            stale_policy: StalePolicy::Nan,
+            ..Config::default()
+        };
+        let (mut processor, registry) = processor(config);
+
+        send(&mut processor, MetricType::Peak, "peak", 5.0);
+        send(&mut processor, MetricType::Sum, "sum", 5.0);
+        processor.handle_aggragation_flush();
+        assert!(exposition(&registry).contains("peak{} 5.0\n"));
+
+        processor.handle_aggragation_flush();
+        let exposition = exposition(&registry);
+        assert!(exposition.contains("peak{} NaN\n"), "{}", exposition);
+        // counters are cumulative and never stale
+        assert!(exposition.contains("sum_total{} 5.0\n"), "{}", exposition);
+    }
+
+    #[test]
+    fn it_evicts_idle_series() {
+        let (mut processor, registry) = processor(Config {
+            series_ttl_secs: 60,
+            ..Config::default()
+        });
+
+        send(&mut processor, MetricType::Sum, "requests", 1.0);
+        send(&mut processor, MetricType::Sum, "errors", 1.0);
+        send(&mut processor, MetricType::Histogram, "latency", 1.0);
+        processor.evict_idle_series();
+        assert!(exposition(&registry).contains("requests_total{} 1.0\n"));
+
+        let idle = Instant::now() - Duration::from_secs(61);
+        for (series, last_seen) in processor.series_last_seen.iter_mut() {
+            if series.name != "errors" {
+                *last_seen = idle;
+            }
+        }
+        processor.evict_idle_series();
+
+        let exposition = exposition(&registry);
+        assert!(!exposition.contains("requests"), "{}", exposition);
+        assert!(!exposition.contains("latency"), "{}", exposition);
+        assert!(
+            exposition.contains("errors_total{} 1.0\n"),
+            "{}",
+            exposition
+        );
+        assert!(!processor.counters.contains_key("requests"));
+        assert!(!processor.histograms.contains_key("latency"));
+    }
+
+    #[test]
+    fn it_drops_metrics_of_another_type_with_the_same_name() {
+        let (mut processor, registry) = processor(Config::default());
+        let conflicts = METRIC_COUNTER_TYPE_CONFLICTS.get();
+
+        send(&mut processor, MetricType::Sum, "requests", 1.0);
+        send(&mut processor, MetricType::Set, "requests", 3.0);
+        send(&mut processor, MetricType::Peak, "latency", 5.0);
+        send(&mut processor, MetricType::Sum, "latency", 1.0);
+        processor.handle_aggragation_flush();
+
+        let exposition = exposition(&registry);
+        assert!(
+            exposition.contains("# TYPE requests counter\nrequests_total{} 1.0\n"),
+            "{}",
+            exposition
+        );
+        assert!(
+            exposition.contains("# TYPE latency gauge\nlatency{} 5.0\n"),
+            "{}",
+            exposition
+        );
+        assert!(!exposition.contains("latency_total"), "{}", exposition);
+        assert!(METRIC_COUNTER_TYPE_CONFLICTS.get() >= conflicts + 2);
+    }
+
+    #[test]
+    fn it_folds_series_beyond_the_limit() {
+        let (mut processor, registry) = processor(Config {
+            limits: LimitsConfig {
+                max_series: 1,
+                overflow_policy: OverflowPolicy::Fold,
+                quotas: vec![],
+            },
+            ..Config::default()
+        });
+
+        send(&mut processor, MetricType::Sum, "requests", 1.0);
+        send(&mut processor, MetricType::Sum, "request_1", 2.0);
+        send(&mut processor, MetricType::Sum, "request_2", 3.0);
+        send(&mut processor, MetricType::Peak, "request_peak", 4.0);
+
+        let exposition = exposition(&registry);
+        assert!(
+            exposition.contains("requests_total{} 1.0\n"),
+            "{}",
+            exposition
+        );
+        assert!(!exposition.contains("request_1"), "{}", exposition);
+        assert!(
+            exposition.contains("udpagent_overflow_total{rule=\"max_series\",type=\"sum\"} 2.0\n"),
+            "{}",
+            exposition
+        );
+        assert!(
+            exposition.contains("udpagent_overflow_total{rule=\"max_series\",type=\"peak\"} 1.0\n"),
+            "{}",
+            exposition
+        );
+    }
+
+    #[test]
+    fn it_rejects_negative_sums() {
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(10);
+        let metric = |metric_type: MetricType, value: f64| InboundMetric {
+            name: "foo".to_string(),
+            value,
+            labels: vec![],
+            metric_type,
+            member: None,
+        };
+
+        assert_eq!(
+            Err(RejectReason::NegativeSum),
+            metric(MetricType::Sum, -1.0).validate()
+        );
+        assert_eq!(
+            Err(RejectReason::NonFiniteValue),
+            metric(MetricType::Average, f64::INFINITY).validate()
+        );
+        assert!(publish_metric(&sender, metric(MetricType::Sum, -1.0)).is_err());
+
+        for metric_type in [MetricType::Average, MetricType::Peak, MetricType::Min] {
+            assert!(publish_metric(&sender, metric(metric_type, -1.0)).is_ok());
+        }
+        assert_eq!(
+            MetricType::Average,
+            receiver.try_recv().unwrap().metric_type
+        );
+    }
+
+    #[test]
+    fn it_strips_unescaped_characters_from_label_values() {
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(10);
+        let metric = InboundMetric {
+            name: "foo\"bar".to_string(),
+            value: 1.0,
+            labels: vec![("tenant".to_string(), "a\"b\\c\nd".to_string())],
+            metric_type: MetricType::Sum,
+            member: None,
+        };
+
+        publish_metric(&sender, metric).unwrap();
+        let published = receiver.try_recv().unwrap();
+        // names are normalized by the pipelines
+        assert_eq!("foo\"bar", published.name);
+        assert_eq!(
+            vec![("tenant".to_string(), "abcd".to_string())],
+            published.labels
+        );
+    }
+
+    #[test]
+    fn it_sets_and_adds_to_gauges() {
+        let (mut processor, registry) = processor(Config::default());
+
+        send(&mut processor, MetricType::Set, "queue", 5.0);
+        send(&mut processor, MetricType::Set, "queue", 3.0);
+        send(&mut processor, MetricType::Delta, "connections", 2.0);
+        send(&mut processor, MetricType::Delta, "connections", -1.5);
+        assert!(exposition(&registry).contains("queue{} 3.0\n"));
+        assert!(exposition(&registry).contains("connections{} 0.5\n"));
+
+        // not aggregated per window and never stale
+        processor.handle_aggragation_flush();
+        processor.handle_aggragation_flush();
+        send(&mut processor, MetricType::Delta, "connections", 1.0);
+        assert!(exposition(&registry).contains("queue{} 3.0\n"));
+        assert!(exposition(&registry).contains("connections{} 1.5\n"));
+    }
+
+    #[test]
+    fn it_counts_unique_members_per_window() {
+        let (mut processor, registry) = processor(Config::default());
+        let mut send_member = |member: &str| {
+            let inbound_metric = InboundMetric {
+                name: "visitors".to_string(),
+                value: 1.0,
+                labels: vec![],
+                metric_type: MetricType::Unique,
+                member: Some(member.to_string()),
+            };
+            processor.handle_metric(inbound_metric);
+        };
+
+        for member in ["alice", "bob", "alice", "carol"] {
+            send_member(member);
+        }
+        processor.handle_aggragation_flush();
+        assert!(exposition(&registry).contains("visitors{} 3.0\n"));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::collector::format_f64;
+use crate::config::Config;
+use crate::processor::{InboundMetric, MetricLabels};
+use crate::remote_write::proto::{Label, Sample, TimeSeries, WriteRequest};
+use crate::series::SeriesState;
+use std::collections::BTreeMap;
+
+pub mod proto;
+pub mod sink;
+
+/// The series of the remote write sink, written like the exposition of the processor: Sum, Set,
+/// Delta and Histogram series on every flush, Average, Peak, Min, Unique and Timing only for
+/// windows with samples.
+pub struct RemoteWriteState {
+    external_labels: BTreeMap<String, String>,
+    series: SeriesState,
+}
+
+impl RemoteWriteState {
+    pub fn new(config: Config) -> Self {
+        Self {
+            external_labels: config.remote_write.external_labels.clone(),
+            series: SeriesState::new(config, "remote_write"),
+        }
+    }
+
+    pub fn handle(&mut self, inbound_metric: InboundMetric) {
+        self.series.handle(inbound_metric);
+    }
+
+    /// ends the window and returns all series with a sample at the timestamp (milliseconds). The
+    /// bounds are formatted like in the exposition, so the `le` label matches a scrape.
+    pub fn flush(&mut self, timestamp: i64) -> WriteRequest {
+        self.series.evict_idle_series();
+        let windowed = self.series.windowed();
+
+        let mut request = WriteRequest::default();
+        let mut push = |name: &str, labels: &MetricLabels, value: f64| {
+            request
+                .timeseries
+                .push(self.time_series(name, labels, value, timestamp));
+        };
+
+        for (series, value) in windowed.iter().flatten() {
+            push(&series.name, &series.labels, *value);
+        }
+
+        for (series, sum) in &self.series.sums {
+            push(&format!("{}_total", series.name), &series.labels, sum.value);
+        }
+
+        for (series, value) in &self.series.gauges {
+            push(&series.name, &series.labels, *value);
+        }
+
+        for (series, histogram) in &self.series.histograms {
+            let bucket_name = format!("{}_bucket", series.name);
+            let buckets = histogram.cumulative_counts();
+            for (bound, bucket) in histogram.bounds.iter().zip(buckets.iter()) {
+                let mut labels = series.labels.clone();
+                labels.push(("le".to_string(), format_f64(*bound)));
+                push(&bucket_name, &labels, *bucket as f64);
+            }
+
+            let mut labels = series.labels.clone();
+            labels.push(("le".to_string(), format_f64(f64::INFINITY)));
+            push(&bucket_name, &labels, histogram.count as f64);
+            push(
+                &format!("{}_sum", series.name),
+                &series.labels,
+                histogram.sum,
+            );
+            push(
+                &format!("{}_count", series.name),
+                &series.labels,
+                histogram.count as f64,
+            );
+        }
+
+        request
+    }
+
+    /// the labels are sorted by name as required by the protocol, the external labels do not
+    /// override labels of the series.
+    fn time_series(
+        &self,
+        name: &str,
+        labels: &MetricLabels,
+        value: f64,
+        timestamp: i64,
+    ) -> TimeSeries {
+        let mut all_labels = BTreeMap::new();
+        for (key, value) in &self.external_labels {
+            all_labels.insert(key.as_str(), value.as_str());
+        }
+        for (key, value) in labels {
+            all_labels.insert(key.as_str(), value.as_str());
+        }
+        all_labels.insert("__name__", name);
+
+        TimeSeries {
+            labels: all_labels
+                .into_iter()
+                .map(|(name, value)| Label {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            samples: vec![Sample { value, timestamp }],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, HistogramBucketsConfig};
+    use crate::processor::InboundMetric;
+    use crate::remote_write::proto::{TimeSeries, WriteRequest};
+    use crate::remote_write::RemoteWriteState;
+    use openmetrics_udpserver_lib::MetricType;
+
+    fn metric(metric_type: MetricType, name: &str, value: f64) -> InboundMetric {
+        InboundMetric {
+            name: name.to_string(),
+            value,
+            labels: vec![("host".to_string(), "web-1".to_string())],
+            metric_type,
+            member: None,
+        }
+    }
+
+    fn find<'a>(request: &'a WriteRequest, name: &str, le: Option<&str>) -> Option<&'a TimeSeries> {
+        request.timeseries.iter().find(|series| {
+            let label = |key: &str| {
+                series
+                    .labels
+                    .iter()
+                    .find(|label| label.name == key)
+                    .map(|label| label.value.as_str())
+            };
+            label("__name__") == Some(name) && label("le") == le
+        })
+    }
+
+    #[test]
+    fn it_keeps_the_semantics_of_the_exposition() {
+        let mut config = Config {
+            histogram_buckets: vec![HistogramBucketsConfig::parse("latency=10,100").unwrap()],
+            ..Config::default()
+        };
+        config
+            .remote_write
+            .external_labels
+            .insert("cluster".to_string(), "eu".to_string());
+        let mut state = RemoteWriteState::new(config);
+
+        state.handle(metric(MetricType::Sum, "requests", 2.0));
+        state.handle(metric(MetricType::Average, "load", 1.0));
+        state.handle(metric(MetricType::Average, "load", 3.0));
+        state.handle(metric(MetricType::Histogram, "latency", 50.0));
+        let request = state.flush(1_000);
+
+        let requests = find(&request, "requests_total", None).unwrap();
+        assert_eq!(2.0, requests.samples[0].value);
+        assert_eq!(1_000, requests.samples[0].timestamp);
+        let label_names = requests
+            .labels
+            .iter()
+            .map(|label| label.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["__name__", "cluster", "host"], label_names);
+
+        assert_eq!(2.0, find(&request, "load", None).unwrap().samples[0].value);
+        let bucket = |le| find(&request, "latency_bucket", Some(le)).unwrap().samples[0].value;
+        assert_eq!(0.0, bucket("10.0"));
+        assert_eq!(1.0, bucket("100.0"));
+        assert_eq!(1.0, bucket("+Inf"));
+
+        // counters are cumulative, averages only exist in windows with samples
+        state.handle(metric(MetricType::Sum, "requests", 1.0));
+        let request = state.flush(2_000);
+        assert_eq!(
+            3.0,
+            find(&request, "requests_total", None).unwrap().samples[0].value
+        );
+        assert!(find(&request, "load", None).is_none());
+    }
+}
@@ -0,0 +1,298 @@
+use crate::config::Config;
+use crate::processor::InboundMetric;
+use crate::remote_write::proto::WriteRequest;
+use crate::remote_write::RemoteWriteState;
+use crate::sink::{send_request, Retry, SendError, Sink};
+use crate::window::Window;
+use crate::METRIC_COUNTER_REMOTE_WRITE_REQUESTS;
+use anyhow::{anyhow, Context};
+use clap::ArgMatches;
+use prost::Message;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteWriteConfig {
+    pub enabled: bool,
+    /// e.g. `http://mimir:9009/api/v1/push`.
+    pub endpoint: String,
+    /// seconds between two pushes.
+    pub flush_interval_secs: u64,
+    /// added to every series, labels of the series take precedence.
+    pub external_labels: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bearer_token: Option<String>,
+    /// retries of a push which failed with a network error, 408, 429 or 5xx.
+    pub max_retries: u32,
+    /// the delay before the first retry, doubled for every further retry.
+    pub retry_backoff_ms: u64,
+    pub timeout_secs: u64,
+}
+
+impl Default for RemoteWriteConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: "".to_string(),
+            flush_interval_secs: 15,
+            external_labels: BTreeMap::new(),
+            username: None,
+            password: None,
+            bearer_token: None,
+            max_retries: 3,
+            retry_backoff_ms: 500,
+            timeout_secs: 10,
+        }
+    }
+}
+
+impl RemoteWriteConfig {
+    pub fn apply_args(&mut self, matches: &ArgMatches) -> Result<(), anyhow::Error> {
+        if let Some(endpoint) = matches.get_one::<String>("remote-write-endpoint") {
+            self.endpoint = endpoint.to_string();
+            self.enabled = true;
+        }
+
+        if let Some(flush_interval_secs) = matches.get_one::<u64>("remote-write-interval") {
+            self.flush_interval_secs = *flush_interval_secs;
+        }
+
+        if let Some(labels) = matches.get_many::<String>("remote-write-label") {
+            for label in labels {
+                let (key, value) = label
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("expected key=value got '{}'", label))
+                    .context("invalid '--remote-write-label'")?;
+                self.external_labels
+                    .insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+
+        if let Some(username) = matches.get_one::<String>("remote-write-username") {
+            self.username = Some(username.to_string());
+        }
+
+        if let Some(password) = matches.get_one::<String>("remote-write-password") {
+            self.password = Some(password.to_string());
+        }
+
+        if let Some(bearer_token) = matches.get_one::<String>("remote-write-bearer-token") {
+            self.bearer_token = Some(bearer_token.to_string());
+        }
+
+        if let Some(max_retries) = matches.get_one::<u32>("remote-write-max-retries") {
+            self.max_retries = *max_retries;
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        Url::parse(&self.endpoint)
+            .map_err(|err| anyhow!("`endpoint`: invalid url '{}': {}", self.endpoint, err))?;
+
+        if self.flush_interval_secs == 0 {
+            return Err(anyhow!("`flush_interval_secs`: must be at least 1 second"));
+        }
+
+        if self.timeout_secs == 0 {
+            return Err(anyhow!("`timeout_secs`: must be at least 1 second"));
+        }
+
+        if self.external_labels.keys().any(|key| key.is_empty()) {
+            return Err(anyhow!("`external_labels`: names must not be empty"));
+        }
+
+        if self.bearer_token.is_some() && self.username.is_some() {
+            return Err(anyhow!(
+                "`bearer_token` and `username` can not be used together"
+            ));
+        }
+
+        if self.password.is_some() && self.username.is_none() {
+            return Err(anyhow!("`password` requires a `username`"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Pushes the metrics to a prometheus remote write endpoint (snappy compressed protobuf), e.g.
+/// a central Prometheus, Mimir or VictoriaMetrics, instead of being scraped.
+pub struct RemoteWriteSink {
+    config: RemoteWriteConfig,
+    state: RemoteWriteState,
+    http_client: Client,
+}
+
+impl RemoteWriteSink {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config: config.remote_write.clone(),
+            state: RemoteWriteState::new(config),
+            http_client: Client::new(),
+        }
+    }
+
+    /// retries transient failures until `max_retries` or the deadline is reached.
+    pub async fn push(&self, request: &WriteRequest, deadline: Instant) -> Result<(), String> {
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .map_err(|err| format!("could not compress the request: {}", err))?;
+
+        let retry = Retry {
+            max_retries: self.config.max_retries,
+            backoff: Duration::from_millis(self.config.retry_backoff_ms),
+            deadline: Some(deadline),
+        };
+
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        retry
+            .run(|| self.send(body.clone(), retry.timeout(timeout)))
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    async fn send(&self, body: Vec<u8>, timeout: Duration) -> Result<(), SendError> {
+        let mut request = self
+            .http_client
+            .post(&self.config.endpoint)
+            .header(CONTENT_ENCODING, "snappy")
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .timeout(timeout)
+            .body(body);
+
+        if let Some(username) = &self.config.username {
+            request = request.basic_auth(username, self.config.password.as_ref());
+        }
+
+        if let Some(bearer_token) = &self.config.bearer_token {
+            request = request.bearer_auth(bearer_token);
+        }
+
+        send_request(request, &METRIC_COUNTER_REMOTE_WRITE_REQUESTS)
+            .await
+            .map(|_| ())
+    }
+}
+
+impl Sink for RemoteWriteSink {
+    fn name(&self) -> &'static str {
+        "remote_write"
+    }
+
+    fn flush_interval(&self) -> Duration {
+        Duration::from_secs(self.config.flush_interval_secs)
+    }
+
+    fn handle(&mut self, metric: InboundMetric) {
+        self.state.handle(metric);
+    }
+
+    async fn flush(&mut self, window: Window) -> Result<(), String> {
+        // retries must not delay the next flush
+        let deadline = Instant::now() + self.flush_interval();
+        let timestamp = window
+            .end
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+
+        let request = self.state.flush(timestamp);
+        if request.timeseries.is_empty() {
+            return Ok(());
+        }
+
+        self.push(&request, deadline).await.map_err(|e| {
+            format!(
+                "remote write failed, dropping {} series: {}",
+                request.timeseries.len(),
+                e
+            )
+        })
+    }
+
+    /// the metrics of the unfinished window are pushed as well.
+    async fn shutdown(&mut self, window: Window) -> Result<(), String> {
+        self.flush(window).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::processor::InboundMetric;
+    use crate::remote_write::proto::WriteRequest;
+    use crate::remote_write::sink::{RemoteWriteConfig, RemoteWriteSink};
+    use crate::sink::stub::Stub;
+    use axum::http::StatusCode;
+    use openmetrics_udpserver_lib::MetricType;
+    use prost::Message;
+    use std::time::{Duration, Instant};
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(10)
+    }
+
+    fn sink(endpoint: String) -> RemoteWriteSink {
+        RemoteWriteSink::new(Config {
+            remote_write: RemoteWriteConfig {
+                enabled: true,
+                endpoint,
+                bearer_token: Some("secret".to_string()),
+                max_retries: 2,
+                retry_backoff_ms: 1,
+                ..RemoteWriteConfig::default()
+            },
+            ..Config::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn it_pushes_snappy_compressed_protobuf() {
+        let stub = Stub::new(&[StatusCode::SERVICE_UNAVAILABLE]);
+        let mut sink = sink(stub.serve("/api/v1/push").await);
+
+        sink.state.handle(InboundMetric {
+            name: "requests".to_string(),
+            value: 1.0,
+            labels: vec![],
+            metric_type: MetricType::Sum,
+            member: None,
+        });
+        let request = sink.state.flush(1_000);
+        assert_eq!(Ok(()), sink.push(&request, deadline()).await);
+
+        // the 503 is retried
+        let requests = stub.requests();
+        assert_eq!(2, requests.len());
+
+        let (headers, body) = &requests[1];
+        assert_eq!("snappy", headers["content-encoding"]);
+        assert_eq!("Bearer secret", headers["authorization"]);
+        let decoded = snap::raw::Decoder::new().decompress_vec(body).unwrap();
+        assert_eq!(request, WriteRequest::decode(decoded.as_slice()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn it_does_not_retry_permanent_failures() {
+        let stub = Stub::new(&[StatusCode::UNAUTHORIZED]);
+        let sink = sink(stub.serve("/api/v1/push").await);
+
+        let err = sink
+            .push(&WriteRequest::default(), deadline())
+            .await
+            .unwrap_err();
+        assert!(err.contains("401"), "{}", err);
+        assert_eq!(1, stub.requests().len());
+    }
+}
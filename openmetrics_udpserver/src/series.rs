@@ -0,0 +1,271 @@
+use crate::aggregator::average::AggragatorAverageGauge;
+use crate::aggregator::min::AggragatorMinGauge;
+use crate::aggregator::peak::AggragatorPeakGauge;
+use crate::aggregator::quantile::AggragatorQuantileGauge;
+use crate::aggregator::timing::AggragatorTimingGauge;
+use crate::aggregator::unique::AggragatorUniqueGauge;
+use crate::config::Config;
+use crate::limits::SeriesLimiter;
+use crate::processor::{InboundMetric, Processor, ProcessorMetric, SeriesKey};
+use fnv::FnvHashMap;
+use openmetrics_udpserver_lib::MetricType;
+use regex::Regex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A Sum series, `start` is the time the series was first seen.
+pub struct SumState {
+    pub start: SystemTime,
+    pub value: f64,
+}
+
+/// The buckets of a histogram series, every bucket only counts the values between its lower and
+/// upper bound. The last bucket counts the values above all bounds.
+pub struct HistogramState {
+    pub start: SystemTime,
+    pub bounds: Vec<f64>,
+    pub bucket_counts: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl HistogramState {
+    fn new(bounds: &[f64]) -> Self {
+        Self {
+            start: SystemTime::now(),
+            bounds: bounds.to_vec(),
+            bucket_counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.bucket_counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// the number of values up to each bound, like the buckets of a prometheus histogram.
+    pub fn cumulative_counts(&self) -> Vec<u64> {
+        self.bucket_counts[..self.bounds.len()]
+            .iter()
+            .scan(0, |count, bucket| {
+                *count += bucket;
+                Some(*count)
+            })
+            .collect()
+    }
+}
+
+/// The series of a sink with the same semantics as the exposition of the processor: Sum, Set,
+/// Delta and Histogram series are cumulative, Average, Peak, Min, Unique and Timing are aggregated
+/// per window. New series have to fit into the series limits, series without any metric for
+/// `series_ttl_secs` are evicted and free their slot again.
+pub struct SeriesState {
+    config: Config,
+    sink: &'static str,
+    regex_allowed_chars: Regex,
+    series_limiter: SeriesLimiter,
+    pub sums: FnvHashMap<SeriesKey, SumState>,
+    pub gauges: FnvHashMap<SeriesKey, f64>,
+    pub histograms: FnvHashMap<SeriesKey, HistogramState>,
+    aggregator_peak_gauge: AggragatorPeakGauge,
+    aggregator_min_gauge: AggragatorMinGauge,
+    aggregator_average_gauge: AggragatorAverageGauge,
+    aggregator_quantile_gauge: AggragatorQuantileGauge,
+    aggregator_unique_gauge: AggragatorUniqueGauge,
+    aggregator_timing_gauge: AggragatorTimingGauge,
+    series_last_seen: FnvHashMap<SeriesKey, Instant>,
+}
+
+impl SeriesState {
+    pub fn new(config: Config, sink: &'static str) -> Self {
+        Self {
+            sink,
+            regex_allowed_chars: Processor::regex_allowed_chars(),
+            series_limiter: SeriesLimiter::new(config.limits.clone(), config.quantiles.clone()),
+            sums: FnvHashMap::default(),
+            gauges: FnvHashMap::default(),
+            histograms: FnvHashMap::default(),
+            aggregator_peak_gauge: AggragatorPeakGauge::new(),
+            aggregator_min_gauge: AggragatorMinGauge::new(),
+            aggregator_average_gauge: AggragatorAverageGauge::new(),
+            aggregator_quantile_gauge: AggragatorQuantileGauge::new(config.quantiles.clone()),
+            aggregator_unique_gauge: AggragatorUniqueGauge::new(),
+            aggregator_timing_gauge: AggragatorTimingGauge::new(config.quantiles.clone()),
+            series_last_seen: FnvHashMap::default(),
+            config,
+        }
+    }
+
+    pub fn handle(&mut self, inbound_metric: InboundMetric) {
+        let Some(metric) = ProcessorMetric::normalize(&self.regex_allowed_chars, inbound_metric)
+        else {
+            return;
+        };
+
+        let Some(metric) = self.series_limiter.admit_metric(self.sink, metric) else {
+            return;
+        };
+
+        if self.config.series_ttl_secs > 0 {
+            self.series_last_seen
+                .insert(metric.series_key(), Instant::now());
+        }
+
+        match metric.metric_type {
+            MetricType::Sum => {
+                self.sums
+                    .entry(metric.series_key())
+                    .or_insert_with(|| SumState {
+                        start: SystemTime::now(),
+                        value: 0.0,
+                    })
+                    .value += metric.value;
+            }
+            MetricType::Set => {
+                self.gauges.insert(metric.series_key(), metric.value);
+            }
+            MetricType::Delta => {
+                *self.gauges.entry(metric.series_key()).or_insert(0.0) += metric.value;
+            }
+            MetricType::Histogram => {
+                let bounds = self.config.histogram_buckets_for(&metric.name);
+                self.histograms
+                    .entry(metric.series_key())
+                    .or_insert_with(|| HistogramState::new(bounds))
+                    .observe(metric.value);
+            }
+            MetricType::Average => {
+                self.aggregator_average_gauge.handle(&metric);
+                self.aggregator_quantile_gauge.handle(&metric);
+            }
+            MetricType::Peak => self.aggregator_peak_gauge.handle(&metric),
+            MetricType::Min => self.aggregator_min_gauge.handle(&metric),
+            MetricType::Unique => self.aggregator_unique_gauge.handle(&metric),
+            MetricType::Timing => self.aggregator_timing_gauge.handle(&metric),
+        }
+    }
+
+    /// ends the window of the aggregated types and returns their series, including the derived
+    /// quantile and timing series.
+    pub fn windowed(&mut self) -> [FnvHashMap<SeriesKey, f64>; 6] {
+        [
+            self.aggregator_average_gauge.reset_and_fetch(),
+            self.aggregator_quantile_gauge.reset_and_fetch(),
+            self.aggregator_min_gauge.reset_and_fetch(),
+            self.aggregator_peak_gauge.reset_and_fetch(),
+            self.aggregator_unique_gauge.reset_and_fetch(),
+            self.aggregator_timing_gauge.reset_and_fetch(),
+        ]
+    }
+
+    /// sums and histograms start over, e.g. after a window with delta temporality. The series keep
+    /// their slot until they are evicted.
+    pub fn start_over(&mut self) {
+        self.sums.clear();
+        self.histograms.clear();
+    }
+
+    pub fn evict_idle_series(&mut self) {
+        if self.config.series_ttl_secs == 0 {
+            return;
+        }
+
+        let series_ttl = Duration::from_secs(self.config.series_ttl_secs);
+        let now = Instant::now();
+        let mut evicted = vec![];
+        self.series_last_seen.retain(|series, last_seen| {
+            if now.duration_since(*last_seen) < series_ttl {
+                return true;
+            }
+
+            evicted.push(series.clone());
+            false
+        });
+
+        for series in &evicted {
+            self.sums.remove(series);
+            self.gauges.remove(series);
+            self.histograms.remove(series);
+            self.series_limiter.release(series);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, LimitsConfig, OverflowPolicy};
+    use crate::processor::{InboundMetric, SeriesKey};
+    use crate::series::SeriesState;
+    use openmetrics_udpserver_lib::MetricType;
+
+    fn metric(name: &str, host: &str) -> InboundMetric {
+        InboundMetric {
+            name: name.to_string(),
+            value: 1.0,
+            labels: vec![("host".to_string(), host.to_string())],
+            metric_type: MetricType::Sum,
+            member: None,
+        }
+    }
+
+    #[test]
+    fn it_folds_series_beyond_the_limits() {
+        let mut state = SeriesState::new(
+            Config {
+                limits: LimitsConfig {
+                    max_series: 1,
+                    overflow_policy: OverflowPolicy::Fold,
+                    quotas: vec![],
+                },
+                ..Config::default()
+            },
+            "test",
+        );
+
+        state.handle(metric("requests", "web-1"));
+        state.handle(metric("requests", "web-2"));
+        state.handle(metric("requests", "web-3"));
+
+        let mut sums = state
+            .sums
+            .into_iter()
+            .map(|(series, sum)| (series, sum.value))
+            .collect::<Vec<_>>();
+        sums.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        assert_eq!(
+            vec![
+                (
+                    SeriesKey {
+                        name: "requests".to_string(),
+                        labels: vec![("host".to_string(), "web-1".to_string())],
+                    },
+                    1.0
+                ),
+                (
+                    SeriesKey {
+                        name: "udpagent_overflow".to_string(),
+                        labels: vec![
+                            ("rule".to_string(), "max_series".to_string()),
+                            ("type".to_string(), "sum".to_string()),
+                        ],
+                    },
+                    2.0
+                ),
+            ],
+            sums
+        );
+    }
+}
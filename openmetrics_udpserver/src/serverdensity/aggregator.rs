@@ -1,50 +1,109 @@
+use crate::config::QuantilesConfig;
 use crate::processor::InboundMetric;
-use crate::serverdensity::{AverageHandler, MinHandler, PeakHandler, SumHandler};
-use crate::METRIC_COUNTER_ERRORS;
-use anyhow::{anyhow, Context};
+use crate::serverdensity::queue::DiskQueue;
+use crate::serverdensity::WindowedAggregation;
+use crate::sink::{self, send_request, Retry, SendError, Sink};
+use crate::window::{unix_seconds, Window};
+use crate::{
+    METRIC_COUNTER_ERRORS, METRIC_COUNTER_SERVERDENSITY_DROPPED_PAYLOADS,
+    METRIC_COUNTER_SERVERDENSITY_REQUESTS, METRIC_COUNTER_SERVERDENSITY_RETRIES,
+    METRIC_GAUGE_SERVERDENSITY_HEALTH,
+};
+use anyhow::anyhow;
 use clap::ArgMatches;
-use openmetrics_udpserver_lib::MetricType;
-use regex::Regex;
-use reqwest::Client;
-use std::collections::HashMap;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::{BufReader, Read};
-use std::time::{Duration, SystemTime};
-use tokio::sync::broadcast::Receiver;
+use std::time::{Duration, Instant, SystemTime};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ServerDensityConfig {
+    pub enabled: bool,
     pub token: String,
     pub account_url: String,
     pub agent_key: String,
     pub serverdensity_endpoint: String,
+    /// seconds between two pushes.
+    pub flush_interval_secs: u64,
+    /// the config file of the sd-agent, agent key and account url are read from it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sd_agent_config: Option<String>,
+    /// payloads which could not be pushed are stored in this directory and replayed, without
+    /// a directory they are dropped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_dir: Option<String>,
+    /// the oldest payloads are dropped if the queue grows beyond.
+    pub queue_max_bytes: u64,
+    /// older payloads are dropped instead of being replayed.
+    pub queue_max_age_secs: u64,
+    /// retries of a push within the flush interval, network errors, 408, 429 and 5xx are retried.
+    pub max_retries: u32,
+    /// delay before the first retry or replay, doubled after every failure and jittered.
+    pub retry_backoff_ms: u64,
+    pub max_retry_backoff_secs: u64,
+}
+
+impl Default for ServerDensityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            token: "".to_string(),
+            account_url: "".to_string(),
+            agent_key: "".to_string(),
+            serverdensity_endpoint: "https://api.serverdensity.io".to_string(),
+            flush_interval_secs: 10,
+            sd_agent_config: None,
+            queue_dir: None,
+            queue_max_bytes: 64 * 1024 * 1024,
+            queue_max_age_secs: 3600,
+            max_retries: 3,
+            retry_backoff_ms: 1000,
+            max_retry_backoff_secs: 300,
+        }
+    }
 }
 
 impl ServerDensityConfig {
-    pub fn from_args(matches: ArgMatches) -> Result<Self, ::anyhow::Error> {
-        let token_option = matches.get_one::<String>("token");
-
-        let mut base_config = ServerDensityConfig {
-            token: token_option
-                .context("'--token' has to be provided, if server density is not disabled with '--disable-serverdensity'")?
-                .to_string(),
-            account_url: matches
-                .get_one::<String>("account-url")
-                .unwrap_or(&"".to_string())
-                .to_string(),
-            agent_key: matches
-                .get_one::<String>("agent-key")
-                .unwrap_or(&"".to_string())
-                .to_string(),
-            serverdensity_endpoint: matches
-                .get_one::<String>("serverdensity-endpoint")
-                .context("'--serverdensity-endpoint' has to be provided, if server density is not disabled with '--disable-serverdensity'")?
-                .to_string(),
-        };
+    pub fn apply_args(&mut self, matches: &ArgMatches) {
+        if matches.get_flag("disable-serverdensity") {
+            self.enabled = false;
+        }
+
+        if let Some(token) = matches.get_one::<String>("token") {
+            self.token = token.to_string();
+        }
+
+        if let Some(account_url) = matches.get_one::<String>("account-url") {
+            self.account_url = account_url.to_string();
+        }
+
+        if let Some(agent_key) = matches.get_one::<String>("agent-key") {
+            self.agent_key = agent_key.to_string();
+        }
+
+        if let Some(serverdensity_endpoint) = matches.get_one::<String>("serverdensity-endpoint") {
+            self.serverdensity_endpoint = serverdensity_endpoint.to_string();
+        }
+
+        if let Some(flush_interval_secs) = matches.get_one::<u64>("serverdensity-flush-interval") {
+            self.flush_interval_secs = *flush_interval_secs;
+        }
+
+        if let Some(sd_agent_config) = matches.get_one::<String>("config") {
+            self.sd_agent_config = Some(sd_agent_config.to_string());
+        }
+
+        if let Some(queue_dir) = matches.get_one::<String>("serverdensity-queue-dir") {
+            self.queue_dir = Some(queue_dir.to_string());
+        }
+    }
 
-        if matches.get_one::<String>("config").is_some() {
-            let config_file = matches.get_one::<String>("config").unwrap().to_string();
-            match base_config.apply_config_file(&config_file) {
+    pub fn apply_sd_agent_config(&mut self) -> Result<(), ::anyhow::Error> {
+        if let Some(config_file) = self.sd_agent_config.clone() {
+            match self.apply_config_file(&config_file) {
                 Ok(_) => println!("successfully read config_file: {}", &config_file),
                 Err(_) => {
                     return Err(anyhow!("could not read config_file: {}", &config_file));
@@ -52,11 +111,45 @@ impl ServerDensityConfig {
             };
         }
 
-        if base_config.agent_key.trim() == "" || base_config.account_url.trim() == "" {
-            return Err(anyhow!("agent-key or account-url not given."));
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ::anyhow::Error> {
+        if self.token.trim() == "" {
+            return Err(anyhow!("`token` has to be provided, if server density is not disabled with '--disable-serverdensity'"));
+        }
+
+        if self.serverdensity_endpoint.trim() == "" {
+            return Err(anyhow!("`serverdensity_endpoint` has to be provided, if server density is not disabled with '--disable-serverdensity'"));
+        }
+
+        if self.flush_interval_secs == 0 {
+            return Err(anyhow!("`flush_interval_secs`: must be at least 1 second"));
+        }
+
+        if self.agent_key.trim() == "" || self.account_url.trim() == "" {
+            return Err(anyhow!("`agent_key` or `account_url` not given."));
+        }
+
+        if self.queue_max_bytes == 0 {
+            return Err(anyhow!("`queue_max_bytes`: must be at least 1"));
+        }
+
+        if self.queue_max_age_secs == 0 {
+            return Err(anyhow!("`queue_max_age_secs`: must be at least 1 second"));
+        }
+
+        if self.retry_backoff_ms == 0 {
+            return Err(anyhow!("`retry_backoff_ms`: must be at least 1ms"));
         }
 
-        Ok(base_config)
+        if self.max_retry_backoff_secs * 1000 < self.retry_backoff_ms {
+            return Err(anyhow!(
+                "`max_retry_backoff_secs`: must not be below `retry_backoff_ms`"
+            ));
+        }
+
+        Ok(())
     }
 
     fn line_value(&self, line: &str) -> String {
@@ -102,82 +195,113 @@ impl ServerDensityConfig {
     }
 }
 
+/// upper limit of a single request, it is shortened to the time left of the flush interval.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// plugins and keys are sorted, the same metrics always result in the same payload (and hash).
+pub type PluginMap = BTreeMap<String, BTreeMap<String, String>>;
+
+/// The payload of a postback, ServerDensity expects the values as strings.
+#[derive(Debug, Serialize)]
+pub struct Payload<'a> {
+    #[serde(rename = "agentKey")]
+    pub agent_key: &'a str,
+    pub plugins: PluginMap,
+}
+
+/// The state of the last push, exposed as `udpagent_serverdensity_health{state}`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServerDensityHealth {
+    Ok,
+    /// network errors, 408, 429 or 5xx after all retries.
+    Unreachable,
+    /// 401 or 403, the token is wrong. Retrying will not help until the config is fixed.
+    Unauthorized,
+    /// any other 4xx, e.g. an unknown agent key or a payload ServerDensity does not accept.
+    Rejected,
+}
+
+impl ServerDensityHealth {
+    const ALL: [ServerDensityHealth; 4] = [
+        ServerDensityHealth::Ok,
+        ServerDensityHealth::Unreachable,
+        ServerDensityHealth::Unauthorized,
+        ServerDensityHealth::Rejected,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServerDensityHealth::Ok => "ok",
+            ServerDensityHealth::Unreachable => "unreachable",
+            ServerDensityHealth::Unauthorized => "unauthorized",
+            ServerDensityHealth::Rejected => "rejected",
+        }
+    }
+}
+
+impl From<&SendError> for ServerDensityHealth {
+    fn from(err: &SendError) -> Self {
+        match err {
+            SendError::Transient(_) => ServerDensityHealth::Unreachable,
+            SendError::Permanent(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN, _) => {
+                ServerDensityHealth::Unauthorized
+            }
+            SendError::Permanent(..) => ServerDensityHealth::Rejected,
+        }
+    }
+}
+
+/// Aggregates the metrics by name and pushes them to ServerDensity once per window. Transient
+/// failures are retried with a jittered exponential backoff as long as the flush interval allows.
+/// With a `queue_dir` payloads which could not be pushed are queued on disk and replayed in
+/// order on the next flushes, the delay between replays doubles after every failure.
 pub struct ServerDensityAggregator {
     config: ServerDensityConfig,
     http_client: Client,
     api_postback_uri: String,
+    aggregation: WindowedAggregation,
+    queue: Option<DiskQueue>,
+    retry_backoff: Duration,
+    next_retry: Option<Instant>,
+    health: Option<ServerDensityHealth>,
 }
 
 impl ServerDensityAggregator {
-    pub fn new(config: ServerDensityConfig) -> ServerDensityAggregator {
-        ServerDensityAggregator {
+    pub async fn new(
+        config: ServerDensityConfig,
+        quantiles: QuantilesConfig,
+    ) -> Result<ServerDensityAggregator, String> {
+        let queue = match &config.queue_dir {
+            Some(queue_dir) => Some(
+                DiskQueue::open(
+                    queue_dir,
+                    config.queue_max_bytes,
+                    Duration::from_secs(config.queue_max_age_secs),
+                )
+                .await?,
+            ),
+            None => None,
+        };
+
+        Ok(ServerDensityAggregator {
             config: config.clone(),
             http_client: Client::new(),
             api_postback_uri: format!(
                 "{}/alerts/postbacks?token={}",
                 &config.serverdensity_endpoint, &config.token
             ),
-        }
-    }
-
-    pub async fn run(&self, mut receiver: Receiver<InboundMetric>) {
-        let regex = Regex::new(r"[^0-9a-zA-ZäöüÄÖÜß\-()._]*").expect("failed to compile regex");
-
-        let mut metricmap = HashMap::new();
-
-        let handler_sum = SumHandler::new();
-        let mut handler_avg = AverageHandler::new();
-        let handler_peak = PeakHandler::new();
-        let handler_min = MinHandler::new();
-        let mut flush_interval = ::tokio::time::interval(Duration::from_secs(10));
-
-        loop {
-            ::tokio::select! {
-                _ = flush_interval.tick() => {
-                    handler_sum.flush(&mut metricmap);
-                    handler_avg.flush(&mut metricmap);
-                    handler_peak.flush(&mut metricmap);
-                    handler_min.flush(&mut metricmap);
-                    self.push_to_serverdensity(&mut metricmap).await;
-                },
-                msg = receiver.recv() => {
-                    match msg {
-                        Ok(metric) => {
-                            let metric_name = regex.replace_all(&metric.name, "").trim().to_string();
-
-                            if metric_name.is_empty() {
-                                println!("got empty metric name.");
-                                continue;
-                            }
-
-                            match metric.metric_type {
-                                MetricType::Sum => {
-                                    handler_sum.handle(&metric_name, &metric, &mut metricmap);
-                                }
-                                MetricType::Average => {
-                                    handler_avg.handle(&metric_name, &metric, &mut metricmap);
-                                }
-                                MetricType::Peak => {
-                                    handler_peak.handle(&metric_name, &metric, &mut metricmap);
-                                }
-                                MetricType::Min => {
-                                    handler_min.handle(&metric_name, &metric, &mut metricmap);
-                                }
-                            };
-                        }
-                        Err(e) => {
-                            METRIC_COUNTER_ERRORS.inc();
-                            eprintln!("aggregator recv error {:#?}, investigate!", e);
-                            ::tokio::time::sleep(Duration::from_millis(300)).await;
-                        }
-                    };
-                }
-            }
-        }
+            aggregation: WindowedAggregation::new(quantiles),
+            queue,
+            retry_backoff: Duration::from_millis(config.retry_backoff_ms),
+            next_retry: None,
+            health: None,
+        })
     }
 
-    pub fn create_plugin_map(map: &HashMap<String, i32>) -> String {
-        let mut outermap: HashMap<String, HashMap<String, i32>> = HashMap::new();
+    /// `plugin.key` is split at the first dot, names without a plugin or key (`foo`, `.foo`,
+    /// `foo.`) are grouped as `custom`, blank names are skipped.
+    pub fn create_plugin_map(map: &HashMap<String, f64>) -> PluginMap {
+        let mut outermap = PluginMap::new();
 
         for (k, v) in map {
             let len = k.len();
@@ -187,146 +311,392 @@ impl ServerDensityAggregator {
                     outermap
                         .entry(k[..index].to_string())
                         .or_default()
-                        .insert(k[index + 1..].to_string(), *v);
+                        .insert(k[index + 1..].to_string(), v.to_string());
                 }
                 _ if k.trim() != "" => {
                     outermap
                         .entry("custom".to_string())
                         .or_default()
-                        .insert(k.to_string(), *v);
+                        .insert(k.to_string(), v.to_string());
                 }
                 _ => {}
             };
         }
 
         outermap
-            .iter()
-            .map(|(k, v)| {
-                let mut buf = String::new();
-                buf.push('"');
-                buf.push_str(k);
-                buf.push_str("\":{");
-
-                buf.push_str(
-                    &v.iter()
-                        .map(|(k, v)| format!("\"{}\":\"{}\"", k, v))
-                        .collect::<Vec<String>>()
-                        .join(",")
-                        .to_string(),
-                );
+    }
 
-                buf.push('}');
-                buf
-            })
-            .collect::<Vec<String>>()
-            .join(",")
-            .to_string()
+    pub fn create_payload(&self, metricmap: &HashMap<String, f64>) -> String {
+        let payload = Payload {
+            agent_key: &self.config.agent_key,
+            plugins: Self::create_plugin_map(metricmap),
+        };
+
+        serde_json::to_string(&payload).expect("the payload only has string keys")
     }
 
-    pub async fn push_to_serverdensity(&self, metricmap: &mut HashMap<String, i32>) {
-        if metricmap.is_empty() {
-            return;
-        }
+    /// retries transient failures until `max_retries` or the deadline is reached.
+    pub async fn push_to_serverdensity(
+        &mut self,
+        payload: &str,
+        deadline: Instant,
+    ) -> Result<(), SendError> {
+        let retry = Retry {
+            max_retries: self.config.max_retries,
+            backoff: Duration::from_millis(self.config.retry_backoff_ms),
+            deadline: Some(deadline),
+        };
+
+        let result = retry
+            .run(|| self.send(payload, retry.timeout(REQUEST_TIMEOUT)))
+            .await;
 
-        let mut payload = "{\"agentKey\":\"".to_string();
-        payload.push_str(&self.config.agent_key);
-        payload.push_str("\",\"plugins\":{");
-        payload.push_str(&Self::create_plugin_map(metricmap));
-        payload.push_str("}}");
+        self.set_health(match &result {
+            Ok(()) => ServerDensityHealth::Ok,
+            Err(err) => err.into(),
+        });
 
-        *metricmap = HashMap::new();
+        result
+    }
 
-        let send_data_to_backend_time = SystemTime::now();
+    async fn send(&self, payload: &str, timeout: Duration) -> Result<(), SendError> {
+        let send_data_to_backend_time = Instant::now();
 
         let data = &[
-            ("payload", &payload),
-            ("hash", &format!("{:x}", md5::compute(&payload))),
+            ("payload", payload),
+            ("hash", &format!("{:x}", md5::compute(payload))),
         ];
 
-        println!("Data to send to ServerDensity Backend {:#?}", &data);
-
-        let res = self
+        let request = self
             .http_client
             .post(&self.api_postback_uri)
             .header("X-Forwarded-Host", self.config.account_url.clone())
             .form(data)
-            .timeout(Duration::from_secs(30))
-            .send()
-            .await;
-
-        let send_data_to_backend_tooked_in_ms = match send_data_to_backend_time.elapsed() {
-            Ok(duration) => {
-                (duration.as_secs() * 1000) + (duration.subsec_nanos() as u64 / 1000000)
+            .timeout(timeout);
+
+        match send_request(request, &METRIC_COUNTER_SERVERDENSITY_REQUESTS).await {
+            Ok(content) => {
+                println!(
+                    "submitted to serverdensity, took {}ms \n--- metrics --- \n{:#?} \n\n{} \n----\n",
+                    send_data_to_backend_time.elapsed().as_millis(),
+                    data,
+                    &content
+                );
+                Ok(())
             }
-            Err(_) => {
+            Err(err) => {
                 METRIC_COUNTER_ERRORS.inc();
-                println!("seems to have trouble with the clock, should never happen.");
-                return;
+                println!("failed to send to serverdensity: {}", err);
+                Err(err)
             }
-        };
+        }
+    }
 
-        match res {
-            Ok(r) => {
-                let response_status = r.status();
-                match r.text().await {
-                    Ok(content) => {
-                        println!("submitted to serverdensity, took {}ms \n--- metrics --- \n{:#?} \n\n{} \n----\n", &send_data_to_backend_tooked_in_ms, data, &content);
+    fn set_health(&mut self, health: ServerDensityHealth) {
+        if self.health != Some(health) {
+            match health {
+                ServerDensityHealth::Unauthorized | ServerDensityHealth::Rejected => eprintln!(
+                    "serverdensity health: {}, check the token and agent key",
+                    health.as_str()
+                ),
+                _ => println!("serverdensity health: {}", health.as_str()),
+            }
+        }
+
+        self.health = Some(health);
+        for state in ServerDensityHealth::ALL {
+            METRIC_GAUGE_SERVERDENSITY_HEALTH
+                .get_or_create(&vec![("state", state.as_str())])
+                .set((state == health) as i64);
+        }
+    }
+
+    fn back_off(&mut self) {
+        self.next_retry = Some(Instant::now() + sink::jitter(self.retry_backoff));
+        self.retry_backoff =
+            (self.retry_backoff * 2).min(Duration::from_secs(self.config.max_retry_backoff_secs));
+    }
+
+    fn reset_backoff(&mut self) {
+        self.next_retry = None;
+        self.retry_backoff = Duration::from_millis(self.config.retry_backoff_ms);
+    }
+
+    fn is_rejected(err: &SendError) -> bool {
+        ServerDensityHealth::from(err) == ServerDensityHealth::Rejected
+    }
+
+    fn count_rejected() {
+        METRIC_COUNTER_SERVERDENSITY_DROPPED_PAYLOADS
+            .get_or_create(&vec![("reason", "rejected")])
+            .inc();
+    }
+
+    /// a new payload is pushed directly if nothing is queued, otherwise it is queued behind the
+    /// older payloads to keep the order. Rejected payloads are dropped, they would be rejected
+    /// again, all others are kept until ServerDensity accepts them.
+    async fn push_queued(
+        &mut self,
+        queue: &mut DiskQueue,
+        payload: Option<String>,
+        deadline: Instant,
+    ) -> Result<(), String> {
+        if let Some(payload) = payload {
+            if queue.is_empty() {
+                return match self.push_to_serverdensity(&payload, deadline).await {
+                    Ok(()) => Ok(()),
+                    Err(err) if Self::is_rejected(&err) => {
+                        Self::count_rejected();
+                        Err(err.to_string())
                     }
                     Err(err) => {
-                        METRIC_COUNTER_ERRORS.inc();
-                        println!("submitted to serverdentity, status: {}, but could not read response: {}", response_status, err);
+                        self.back_off();
+                        queue.push(&payload, SystemTime::now()).await?;
+                        Err(err.to_string())
                     }
-                }
+                };
             }
-            Err(err) => {
-                METRIC_COUNTER_ERRORS.inc();
-                println!("failed to send to serverdensity, status {:?}", err.status());
-                println!("error: {:?}", err);
+
+            queue.push(&payload, SystemTime::now()).await?;
+        }
+
+        queue.drop_expired(SystemTime::now()).await;
+        if queue.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(next_retry) = self.next_retry {
+            if Instant::now() < next_retry {
+                return Err(format!(
+                    "{} payloads queued, next replay in {}ms",
+                    queue.len(),
+                    (next_retry - Instant::now()).as_millis()
+                ));
+            }
+        }
+
+        let mut result = Ok(());
+        while Instant::now() < deadline {
+            let Some(payload) = queue.front().await else {
+                break;
+            };
+
+            METRIC_COUNTER_SERVERDENSITY_RETRIES.inc();
+            match self.push_to_serverdensity(&payload, deadline).await {
+                Ok(()) => {
+                    queue.pop().await;
+                    self.reset_backoff();
+                }
+                Err(err) if Self::is_rejected(&err) => {
+                    queue.pop().await;
+                    Self::count_rejected();
+                    result = Err(err.to_string());
+                }
+                Err(err) => {
+                    self.back_off();
+                    return Err(format!("{} payloads queued: {}", queue.len(), err));
+                }
             }
+        }
+
+        result
+    }
+}
+
+impl Sink for ServerDensityAggregator {
+    fn name(&self) -> &'static str {
+        "serverdensity"
+    }
+
+    fn flush_interval(&self) -> Duration {
+        Duration::from_secs(self.config.flush_interval_secs)
+    }
+
+    fn handle(&mut self, metric: InboundMetric) {
+        self.aggregation.handle(&metric);
+    }
+
+    async fn flush(&mut self, window: Window) -> Result<(), String> {
+        // retries and replays must not delay the next flush
+        let deadline = Instant::now() + self.flush_interval();
+
+        let metricmap = self.aggregation.flush();
+        let payload = match metricmap.is_empty() {
+            true => None,
+            false => Some(self.create_payload(&metricmap)),
+        };
+
+        if let Some(payload) = &payload {
+            println!(
+                "Data to send to ServerDensity Backend for window {}-{} {}",
+                unix_seconds(window.start),
+                unix_seconds(window.end),
+                payload
+            );
+        }
+
+        let Some(mut queue) = self.queue.take() else {
+            // without a queue the payload of a failed push is lost
+            let Some(payload) = payload else {
+                return Ok(());
+            };
+
+            return match self.push_to_serverdensity(&payload, deadline).await {
+                Ok(()) => Ok(()),
+                Err(err) => {
+                    if Self::is_rejected(&err) {
+                        Self::count_rejected();
+                    }
+                    Err(err.to_string())
+                }
+            };
         };
+
+        let result = self.push_queued(&mut queue, payload, deadline).await;
+        self.queue = Some(queue);
+        result
+    }
+
+    /// the metrics of the unfinished window are pushed (or queued) as well.
+    async fn shutdown(&mut self, window: Window) -> Result<(), String> {
+        self.flush(window).await
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::serverdensity::aggregator::ServerDensityAggregator;
+    use crate::config::QuantilesConfig;
+    use crate::serverdensity::aggregator::{
+        ServerDensityAggregator, ServerDensityConfig, ServerDensityHealth,
+    };
+    use crate::sink::stub::Stub;
+    use crate::sink::SendError;
     use ::std::collections::HashMap;
+    use axum::http::StatusCode;
+    use std::time::{Duration, Instant};
+
+    async fn aggregator(stub: &Stub) -> ServerDensityAggregator {
+        let url = stub.serve("/alerts/postbacks").await;
+        let config = ServerDensityConfig {
+            token: "token".to_string(),
+            account_url: "example.serverdensity.io".to_string(),
+            agent_key: "key".to_string(),
+            serverdensity_endpoint: url.trim_end_matches("/alerts/postbacks").to_string(),
+            retry_backoff_ms: 1,
+            ..ServerDensityConfig::default()
+        };
+        ServerDensityAggregator::new(config, QuantilesConfig::default())
+            .await
+            .unwrap()
+    }
 
-    #[test]
-    fn it_works() {
-        let mut m = HashMap::new();
-        m.insert("foo".to_string(), 2);
-        m.insert("foo.".to_string(), 3);
-        m.insert(".foo.bar.barr".to_string(), 4);
+    #[tokio::test]
+    async fn it_retries_transient_failures() {
+        let stub = Stub::new(&[
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::SERVICE_UNAVAILABLE,
+        ]);
+        let mut aggregator = aggregator(&stub).await;
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let result = aggregator.push_to_serverdensity("{}", deadline).await;
+
+        assert_eq!(Ok(()), result);
+        assert_eq!(Some(ServerDensityHealth::Ok), aggregator.health);
+        let requests = stub.requests();
+        assert_eq!(3, requests.len());
+        assert_eq!(
+            "payload=%7B%7D&hash=99914b932bd37a50b983c5e7c90ae93b",
+            String::from_utf8_lossy(&requests[2].1)
+        );
+    }
 
-        m.insert("foo.bar".to_string(), 5);
-        m.insert("foo.bar.baz".to_string(), 6);
+    #[tokio::test]
+    async fn it_does_not_retry_permanent_failures() {
+        let stub = Stub::new(&[StatusCode::UNAUTHORIZED]);
+        let mut aggregator = aggregator(&stub).await;
 
-        let out = ServerDensityAggregator::create_plugin_map(&m);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let result = aggregator.push_to_serverdensity("{}", deadline).await;
 
-        println!("{}\n", &format!("{}", out));
+        assert!(
+            matches!(
+                result,
+                Err(SendError::Permanent(StatusCode::UNAUTHORIZED, _))
+            ),
+            "{:?}",
+            result
+        );
+        assert_eq!(Some(ServerDensityHealth::Unauthorized), aggregator.health);
+        assert_eq!(1, stub.requests().len());
+    }
 
-        let mut m = HashMap::new();
-        m.insert("foo".to_string(), 2);
-        let out = ServerDensityAggregator::create_plugin_map(&m);
-        println!("{}\n", &format!("{}", out));
+    fn metricmap(metrics: &[(&str, f64)]) -> HashMap<String, f64> {
+        metrics
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect()
+    }
 
-        let mut m = HashMap::new();
-        m.insert("foo.bar".to_string(), 2);
+    #[test]
+    fn it_works() {
+        let m = metricmap(&[
+            ("foo", 2.0),
+            ("foo.", 3.0),
+            (".foo.bar.barr", 4.0),
+            ("foo.bar", 5.0),
+            ("foo.bar.baz", 6.0),
+            (".", 7.0),
+            ("cpu.load", 0.25),
+            (" ", 8.0),
+            ("", 9.0),
+        ]);
         let out = ServerDensityAggregator::create_plugin_map(&m);
-        println!("{}\n", &format!("{}", out));
+        assert_eq!(
+            include_str!("testdata/plugin_map_grouping.json").trim_end(),
+            serde_json::to_string_pretty(&out).unwrap()
+        );
 
-        let mut m = HashMap::new();
-        let out = ServerDensityAggregator::create_plugin_map(&m);
-        println!("{}\n", &format!("{}", out));
+        let out = ServerDensityAggregator::create_plugin_map(&metricmap(&[("foo", 2.0)]));
+        assert_eq!(
+            r#"{"custom":{"foo":"2"}}"#,
+            serde_json::to_string(&out).unwrap()
+        );
+
+        let out = ServerDensityAggregator::create_plugin_map(&metricmap(&[("foo.bar", 2.0)]));
+        assert_eq!(
+            r#"{"foo":{"bar":"2"}}"#,
+            serde_json::to_string(&out).unwrap()
+        );
 
-        /*
-        the assert doesnt work because the map is not ordered.
+        let out = ServerDensityAggregator::create_plugin_map(&HashMap::new());
+        assert_eq!("{}", serde_json::to_string(&out).unwrap());
+    }
 
+    #[tokio::test]
+    async fn it_escapes_names_and_the_agent_key() {
+        let config = ServerDensityConfig {
+            agent_key: "k\"ey\\".to_string(),
+            ..ServerDensityConfig::default()
+        };
+        let aggregator = ServerDensityAggregator::new(config, QuantilesConfig::default())
+            .await
+            .unwrap();
+
+        let m = metricmap(&[
+            ("a\"b.c", 1.0),
+            ("back\\slash", 2.0),
+            ("line\nbreak.x", 3.0),
+            ("ümlaut.größe", 4.0),
+        ]);
+        let payload = aggregator.create_payload(&m);
         assert_eq!(
-            r#""foo":{"bar":"5","bar.baz":"6"},"custom":{".foo.bar.barr":"4","foo":"2","foo.":"3"}"#,
-            &format!("{}", out)
+            include_str!("testdata/payload_escaping.json").trim_end(),
+            payload
         );
-        */
+
+        let decoded: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!("k\"ey\\", decoded["agentKey"]);
+        assert_eq!("1", decoded["plugins"]["a\"b"]["c"]);
     }
 }
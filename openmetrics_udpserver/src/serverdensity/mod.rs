@@ -1,7 +1,99 @@
+use crate::aggregator::timing::TimingBucket;
+use crate::config::QuantilesConfig;
+use crate::ddsketch::DDSketch;
+use crate::hyperloglog::HyperLogLog;
 use crate::processor::InboundMetric;
+use once_cell::sync::Lazy;
+use openmetrics_udpserver_lib::MetricType;
+use regex::Regex;
 use std::collections::HashMap;
 
 pub mod aggregator;
+pub mod queue;
+
+static REGEX_ALLOWED_CHARS: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"[^0-9a-zA-ZäöüÄÖÜß\-()._]*").expect("failed to compile regex"));
+
+/// Aggregates the metrics of a window by name, every metric type has its handler. Used by all
+/// sinks which push a flat map of `plugin.key` names, e.g. ServerDensity and Graphite.
+pub struct WindowedAggregation {
+    metricmap: HashMap<String, f64>,
+    handler_sum: SumHandler,
+    handler_avg: AverageHandler,
+    handler_peak: PeakHandler,
+    handler_min: MinHandler,
+    handler_histogram: HistogramHandler,
+    handler_set: SetHandler,
+    handler_delta: DeltaHandler,
+    handler_unique: UniqueHandler,
+    handler_timing: TimingHandler,
+}
+
+impl WindowedAggregation {
+    pub fn new(quantiles: QuantilesConfig) -> WindowedAggregation {
+        WindowedAggregation {
+            metricmap: HashMap::new(),
+            handler_sum: SumHandler::new(),
+            handler_avg: AverageHandler::new(quantiles.clone()),
+            handler_peak: PeakHandler::new(),
+            handler_min: MinHandler::new(),
+            handler_histogram: HistogramHandler::new(quantiles.clone()),
+            handler_set: SetHandler::new(),
+            handler_delta: DeltaHandler::new(),
+            handler_unique: UniqueHandler::new(),
+            handler_timing: TimingHandler::new(quantiles),
+        }
+    }
+
+    /// the name without the characters ServerDensity does not accept, `None` if nothing is left.
+    pub fn normalize_name(name: &str) -> Option<String> {
+        let name = REGEX_ALLOWED_CHARS.replace_all(name, "").trim().to_string();
+        (!name.is_empty()).then_some(name)
+    }
+
+    pub fn handle(&mut self, metric: &InboundMetric) {
+        // these sinks have no concept of labels, labeled series are aggregated by their name
+        let Some(metric_name) = Self::normalize_name(&metric.name) else {
+            println!("got empty metric name.");
+            return;
+        };
+
+        let metricmap = &mut self.metricmap;
+        match metric.metric_type {
+            MetricType::Sum => self.handler_sum.handle(&metric_name, metric, metricmap),
+            MetricType::Average => self.handler_avg.handle(&metric_name, metric, metricmap),
+            MetricType::Peak => self.handler_peak.handle(&metric_name, metric, metricmap),
+            MetricType::Min => self.handler_min.handle(&metric_name, metric, metricmap),
+            MetricType::Histogram => self
+                .handler_histogram
+                .handle(&metric_name, metric, metricmap),
+            MetricType::Set => self.handler_set.handle(&metric_name, metric, metricmap),
+            MetricType::Delta => self.handler_delta.handle(&metric_name, metric, metricmap),
+            MetricType::Unique => self.handler_unique.handle(&metric_name, metric, metricmap),
+            MetricType::Timing => self.handler_timing.handle(&metric_name, metric, metricmap),
+        };
+    }
+
+    /// ends the window and returns the values of all metrics which had a value in it.
+    pub fn flush(&mut self) -> HashMap<String, f64> {
+        self.handler_sum.flush(&mut self.metricmap);
+        self.handler_avg.flush(&mut self.metricmap);
+        self.handler_peak.flush(&mut self.metricmap);
+        self.handler_min.flush(&mut self.metricmap);
+        self.handler_histogram.flush(&mut self.metricmap);
+        self.handler_set.flush(&mut self.metricmap);
+        self.handler_delta.flush(&mut self.metricmap);
+        self.handler_unique.flush(&mut self.metricmap);
+        self.handler_timing.flush(&mut self.metricmap);
+
+        ::std::mem::take(&mut self.metricmap)
+    }
+
+    /// whether anything is kept across windows, after a flush only delta gauges are.
+    pub fn has_state(&self) -> bool {
+        !self.handler_delta.values.is_empty()
+    }
+}
 
 pub struct SumHandler;
 
@@ -14,33 +106,39 @@ impl SumHandler {
         &self,
         metric_name: &str,
         metric: &InboundMetric,
-        metricmap: &mut HashMap<String, i32>,
+        metricmap: &mut HashMap<String, f64>,
     ) {
-        *metricmap.entry(metric_name.to_string()).or_insert(0) += metric.count;
+        *metricmap.entry(metric_name.to_string()).or_insert(0.0) += metric.value;
     }
 
-    pub fn flush(&self, _: &mut HashMap<String, i32>) {}
+    pub fn flush(&self, _: &mut HashMap<String, f64>) {}
 }
 
 pub struct AverageBucket {
-    sum: u64,
+    sum: f64,
     count: u64,
 }
 
 impl AverageBucket {
     fn new() -> AverageBucket {
-        AverageBucket { sum: 0, count: 0 }
+        AverageBucket { sum: 0.0, count: 0 }
     }
 }
 
+/// Publishes the average of a window, metrics matching a quantile prefix additionally publish the
+/// quantiles of the window as `<name>.p<percent>`, e.g. `foo.latency.p99`.
 pub struct AverageHandler {
     buffer: HashMap<String, AverageBucket>,
+    quantiles: QuantilesConfig,
+    sketches: HashMap<String, DDSketch>,
 }
 
 impl AverageHandler {
-    pub fn new() -> AverageHandler {
+    pub fn new(quantiles: QuantilesConfig) -> AverageHandler {
         AverageHandler {
             buffer: HashMap::new(),
+            quantiles,
+            sketches: HashMap::new(),
         }
     }
 
@@ -48,19 +146,35 @@ impl AverageHandler {
         &mut self,
         metric_name: &str,
         metric: &InboundMetric,
-        _: &mut HashMap<String, i32>,
+        _: &mut HashMap<String, f64>,
     ) {
         let bucket: &mut AverageBucket = self
             .buffer
             .entry(metric_name.to_string())
             .or_insert(AverageBucket::new());
-        bucket.sum += metric.count as u64;
+        bucket.sum += metric.value;
         bucket.count += 1;
+
+        if self.quantiles.is_enabled_for(metric_name) {
+            self.sketches
+                .entry(metric_name.to_string())
+                .or_insert_with(|| self.quantiles.sketch())
+                .insert(metric.value);
+        }
     }
 
-    pub fn flush(&mut self, metricmap: &mut HashMap<String, i32>) {
+    pub fn flush(&mut self, metricmap: &mut HashMap<String, f64>) {
         for (k, v) in &self.buffer {
-            metricmap.insert(k.to_string(), (v.sum / v.count) as i32);
+            metricmap.insert(k.to_string(), v.sum / v.count as f64);
+        }
+
+        for (k, sketch) in ::std::mem::take(&mut self.sketches) {
+            for quantile in &self.quantiles.quantiles {
+                if let Some(value) = sketch.quantile(*quantile) {
+                    let suffix = QuantilesConfig::percentile_suffix(*quantile);
+                    metricmap.insert(format!("{}.{}", k, suffix), value);
+                }
+            }
         }
 
         self.buffer = HashMap::new();
@@ -78,16 +192,18 @@ impl PeakHandler {
         &self,
         metric_name: &str,
         metric: &InboundMetric,
-        metricmap: &mut HashMap<String, i32>,
+        metricmap: &mut HashMap<String, f64>,
     ) {
-        let e: &mut i32 = metricmap.entry(metric_name.to_string()).or_insert(0);
+        let e: &mut f64 = metricmap
+            .entry(metric_name.to_string())
+            .or_insert(metric.value);
 
-        if *e < metric.count {
-            *e = metric.count;
+        if *e < metric.value {
+            *e = metric.value;
         }
     }
 
-    pub fn flush(&self, _: &mut HashMap<String, i32>) {}
+    pub fn flush(&self, _: &mut HashMap<String, f64>) {}
 }
 
 pub struct MinHandler;
@@ -101,14 +217,323 @@ impl MinHandler {
         &self,
         metric_name: &str,
         metric: &InboundMetric,
-        metricmap: &mut HashMap<String, i32>,
+        metricmap: &mut HashMap<String, f64>,
     ) {
-        let e: &mut i32 = metricmap.entry(metric_name.to_string()).or_insert(i32::MAX);
+        let e: &mut f64 = metricmap
+            .entry(metric_name.to_string())
+            .or_insert(metric.value);
 
-        if *e > metric.count {
-            *e = metric.count;
+        if *e > metric.value {
+            *e = metric.value;
         }
     }
 
-    pub fn flush(&self, _: &mut HashMap<String, i32>) {}
+    pub fn flush(&self, _: &mut HashMap<String, f64>) {}
+}
+
+pub struct SetHandler;
+
+impl SetHandler {
+    pub fn new() -> SetHandler {
+        SetHandler {}
+    }
+
+    pub fn handle(
+        &self,
+        metric_name: &str,
+        metric: &InboundMetric,
+        metricmap: &mut HashMap<String, f64>,
+    ) {
+        metricmap.insert(metric_name.to_string(), metric.value);
+    }
+
+    pub fn flush(&self, _: &mut HashMap<String, f64>) {}
+}
+
+/// Keeps the value of every delta gauge, which is published in every window, not only in the
+/// windows the gauge changed.
+pub struct DeltaHandler {
+    values: HashMap<String, f64>,
+}
+
+impl DeltaHandler {
+    pub fn new() -> DeltaHandler {
+        DeltaHandler {
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        metric_name: &str,
+        metric: &InboundMetric,
+        _: &mut HashMap<String, f64>,
+    ) {
+        *self.values.entry(metric_name.to_string()).or_insert(0.0) += metric.value;
+    }
+
+    pub fn flush(&self, metricmap: &mut HashMap<String, f64>) {
+        for (k, v) in &self.values {
+            metricmap.insert(k.to_string(), *v);
+        }
+    }
+}
+
+/// Keeps a sketch of the members of a window to publish the estimated number of distinct members.
+pub struct UniqueHandler {
+    buffer: HashMap<String, HyperLogLog>,
+}
+
+impl UniqueHandler {
+    pub fn new() -> UniqueHandler {
+        UniqueHandler {
+            buffer: HashMap::new(),
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        metric_name: &str,
+        metric: &InboundMetric,
+        _: &mut HashMap<String, f64>,
+    ) {
+        if let Some(member) = &metric.member {
+            self.buffer
+                .entry(metric_name.to_string())
+                .or_default()
+                .insert(member);
+        }
+    }
+
+    pub fn flush(&mut self, metricmap: &mut HashMap<String, f64>) {
+        for (k, v) in ::std::mem::take(&mut self.buffer) {
+            metricmap.insert(k, v.estimate().round());
+        }
+    }
+}
+
+/// Publishes the aggregates of the Timing samples of a window as `<name>.count`, `<name>.sum`,
+/// `<name>.min`, `<name>.max` and `<name>.mean`, plus the quantiles for a quantile prefix.
+pub struct TimingHandler {
+    buffer: HashMap<String, TimingBucket>,
+    quantiles: QuantilesConfig,
+}
+
+impl TimingHandler {
+    pub fn new(quantiles: QuantilesConfig) -> TimingHandler {
+        TimingHandler {
+            buffer: HashMap::new(),
+            quantiles,
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        metric_name: &str,
+        metric: &InboundMetric,
+        _: &mut HashMap<String, f64>,
+    ) {
+        self.buffer
+            .entry(metric_name.to_string())
+            .or_insert_with(|| {
+                let sketch = self.quantiles.is_enabled_for(metric_name);
+                TimingBucket::new(sketch.then(|| self.quantiles.sketch()))
+            })
+            .insert(metric.value);
+    }
+
+    pub fn flush(&mut self, metricmap: &mut HashMap<String, f64>) {
+        for (k, bucket) in ::std::mem::take(&mut self.buffer) {
+            for (suffix, value) in bucket.aggregates() {
+                metricmap.insert(format!("{}.{}", k, suffix), value);
+            }
+
+            let Some(sketch) = &bucket.sketch else {
+                continue;
+            };
+
+            for quantile in &self.quantiles.quantiles {
+                if let Some(value) = sketch.quantile(*quantile) {
+                    let suffix = QuantilesConfig::percentile_suffix(*quantile);
+                    metricmap.insert(format!("{}.{}", k, suffix), value);
+                }
+            }
+        }
+    }
+}
+
+/// Records the samples of a window into a sketch to publish the derived percentiles on flush.
+pub struct HistogramHandler {
+    buffer: HashMap<String, DDSketch>,
+    quantiles: QuantilesConfig,
+}
+
+impl HistogramHandler {
+    pub const PERCENTILES: [(&'static str, f64); 3] = [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)];
+
+    pub fn new(quantiles: QuantilesConfig) -> HistogramHandler {
+        HistogramHandler {
+            buffer: HashMap::new(),
+            quantiles,
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        metric_name: &str,
+        metric: &InboundMetric,
+        _: &mut HashMap<String, f64>,
+    ) {
+        self.buffer
+            .entry(metric_name.to_string())
+            .or_insert_with(|| self.quantiles.sketch())
+            .insert(metric.value);
+    }
+
+    pub fn flush(&mut self, metricmap: &mut HashMap<String, f64>) {
+        for (k, sketch) in ::std::mem::take(&mut self.buffer) {
+            for (suffix, percentile) in Self::PERCENTILES {
+                if let Some(value) = sketch.quantile(percentile) {
+                    metricmap.insert(format!("{}.{}", k, suffix), value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::QuantilesConfig;
+    use crate::processor::InboundMetric;
+    use crate::serverdensity::{
+        AverageHandler, DeltaHandler, HistogramHandler, MinHandler, PeakHandler, SetHandler,
+        TimingHandler,
+    };
+    use openmetrics_udpserver_lib::MetricType;
+    use std::collections::HashMap;
+
+    fn metric(metric_type: MetricType, value: f64) -> InboundMetric {
+        InboundMetric {
+            name: "foo".to_string(),
+            value,
+            labels: vec![],
+            metric_type,
+            member: None,
+        }
+    }
+
+    #[test]
+    fn it_publishes_delta_gauges_in_every_window() {
+        let mut metricmap = HashMap::new();
+        let set = SetHandler::new();
+        let mut delta = DeltaHandler::new();
+
+        set.handle("queue", &metric(MetricType::Set, 5.0), &mut metricmap);
+        set.handle("queue", &metric(MetricType::Set, 3.0), &mut metricmap);
+        delta.handle(
+            "connections",
+            &metric(MetricType::Delta, 2.0),
+            &mut metricmap,
+        );
+        delta.handle(
+            "connections",
+            &metric(MetricType::Delta, -1.0),
+            &mut metricmap,
+        );
+        delta.flush(&mut metricmap);
+        assert_eq!(Some(&3.0), metricmap.get("queue"));
+        assert_eq!(Some(&1.0), metricmap.get("connections"));
+
+        let mut metricmap = HashMap::new();
+        delta.flush(&mut metricmap);
+        assert_eq!(Some(&1.0), metricmap.get("connections"));
+    }
+
+    #[test]
+    fn it_aggregates_negative_values() {
+        let mut metricmap = HashMap::new();
+        let peak = PeakHandler::new();
+        let min = MinHandler::new();
+        let mut average = AverageHandler::new(QuantilesConfig::default());
+
+        for value in [-5.0, -3.0, -4.0] {
+            peak.handle("peak", &metric(MetricType::Peak, value), &mut metricmap);
+            min.handle("min", &metric(MetricType::Min, value), &mut metricmap);
+            average.handle("avg", &metric(MetricType::Average, value), &mut metricmap);
+        }
+        average.flush(&mut metricmap);
+
+        assert_eq!(Some(&-3.0), metricmap.get("peak"));
+        assert_eq!(Some(&-5.0), metricmap.get("min"));
+        assert_eq!(Some(&-4.0), metricmap.get("avg"));
+    }
+
+    #[test]
+    fn it_publishes_quantiles_of_averages() {
+        let mut metricmap = HashMap::new();
+        let mut average = AverageHandler::new(QuantilesConfig {
+            prefixes: vec!["foo.".to_string()],
+            quantiles: vec![0.5, 0.999],
+            ..QuantilesConfig::default()
+        });
+
+        for value in 1..=1000 {
+            let metric = metric(MetricType::Average, value as f64);
+            average.handle("foo.latency", &metric, &mut metricmap);
+            average.handle("bar.latency", &metric, &mut metricmap);
+        }
+        average.flush(&mut metricmap);
+
+        assert_eq!(Some(&500.5), metricmap.get("foo.latency"));
+        assert!((metricmap["foo.latency.p50"] - 500.0).abs() <= 5.0);
+        assert!((metricmap["foo.latency.p99.9"] - 999.0).abs() <= 10.0);
+        assert!(!metricmap.contains_key("bar.latency.p50"));
+
+        let mut metricmap = HashMap::new();
+        average.flush(&mut metricmap);
+        assert!(metricmap.is_empty());
+    }
+
+    #[test]
+    fn it_publishes_timing_aggregates() {
+        let mut metricmap = HashMap::new();
+        let mut timing = TimingHandler::new(QuantilesConfig {
+            prefixes: vec!["db.".to_string()],
+            quantiles: vec![0.5],
+            ..QuantilesConfig::default()
+        });
+
+        for value in [30.0, 10.0, 20.0] {
+            let metric = metric(MetricType::Timing, value);
+            timing.handle("db.query", &metric, &mut metricmap);
+            timing.handle("http.request", &metric, &mut metricmap);
+        }
+        timing.flush(&mut metricmap);
+
+        assert_eq!(Some(&3.0), metricmap.get("db.query.count"));
+        assert_eq!(Some(&60.0), metricmap.get("db.query.sum"));
+        assert_eq!(Some(&10.0), metricmap.get("db.query.min"));
+        assert_eq!(Some(&30.0), metricmap.get("db.query.max"));
+        assert_eq!(Some(&20.0), metricmap.get("db.query.mean"));
+        assert!((metricmap["db.query.p50"] - 20.0).abs() <= 0.2);
+        assert_eq!(Some(&20.0), metricmap.get("http.request.mean"));
+        assert!(!metricmap.contains_key("http.request.p50"));
+    }
+
+    #[test]
+    fn it_publishes_percentiles() {
+        let mut handler = HistogramHandler::new(QuantilesConfig::default());
+        let mut metricmap = HashMap::new();
+        for value in (1..=100).rev() {
+            let metric = metric(MetricType::Histogram, f64::from(value));
+            handler.handle("foo.latency", &metric, &mut metricmap);
+        }
+        handler.flush(&mut metricmap);
+
+        // within the relative accuracy of the sketch
+        assert!((metricmap["foo.latency.p50"] - 50.0).abs() <= 0.5);
+        assert!((metricmap["foo.latency.p95"] - 95.0).abs() <= 0.95);
+        assert!((metricmap["foo.latency.p99"] - 99.0).abs() <= 0.99);
+        assert!(handler.buffer.is_empty());
+    }
 }
@@ -0,0 +1,305 @@
+use crate::{
+    METRIC_COUNTER_SERVERDENSITY_DROPPED_PAYLOADS, METRIC_GAUGE_SERVERDENSITY_QUEUE_BYTES,
+    METRIC_GAUGE_SERVERDENSITY_QUEUE_PAYLOADS,
+};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+const EXTENSION: &str = "payload";
+const TMP_EXTENSION: &str = "tmp";
+
+struct QueueEntry {
+    path: PathBuf,
+    created: SystemTime,
+    bytes: u64,
+}
+
+/// Payloads which could not be pushed, one file per payload. The file name starts with the
+/// creation time in milliseconds, so the queue survives restarts and is replayed in order. The
+/// sequence after it continues from the files on disk, a payload never replaces an older one.
+pub struct DiskQueue {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    entries: VecDeque<QueueEntry>,
+    bytes: u64,
+    sequence: u64,
+}
+
+impl DiskQueue {
+    /// payloads left over from a crash while writing are removed.
+    pub async fn open(dir: &str, max_bytes: u64, max_age: Duration) -> Result<DiskQueue, String> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)
+            .await
+            .map_err(|err| format!("could not create {}: {}", dir.display(), err))?;
+
+        let mut entries = fs::read_dir(&dir)
+            .await
+            .map_err(|err| format!("could not read {}: {}", dir.display(), err))?;
+        let mut paths = vec![];
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            match path.extension() {
+                Some(extension) if extension == EXTENSION => paths.push(path),
+                Some(extension) if extension == TMP_EXTENSION => {
+                    if let Err(err) = fs::remove_file(&path).await {
+                        println!("could not remove {}: {}", path.display(), err);
+                    }
+                }
+                _ => {}
+            }
+        }
+        paths.sort();
+
+        let mut queue = DiskQueue {
+            dir,
+            max_bytes,
+            max_age,
+            entries: VecDeque::new(),
+            bytes: 0,
+            sequence: paths
+                .iter()
+                .filter_map(|path| Self::sequence(path))
+                .max()
+                .unwrap_or(0),
+        };
+
+        for path in paths {
+            let (Some(created), Ok(metadata)) = (Self::created(&path), fs::metadata(&path).await)
+            else {
+                continue;
+            };
+
+            queue.bytes += metadata.len();
+            queue.entries.push_back(QueueEntry {
+                path,
+                created,
+                bytes: metadata.len(),
+            });
+        }
+
+        queue.drop_expired(SystemTime::now()).await;
+        queue.trim().await;
+        queue.update_gauges();
+
+        Ok(queue)
+    }
+
+    fn created(path: &Path) -> Option<SystemTime> {
+        let millis = path.file_stem()?.to_str()?.split('-').next()?;
+        Some(UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?))
+    }
+
+    fn sequence(path: &Path) -> Option<u64> {
+        path.file_stem()?.to_str()?.split('-').nth(1)?.parse().ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// the oldest payloads are dropped if the queue exceeds `max_bytes` afterwards.
+    pub async fn push(&mut self, payload: &str, now: SystemTime) -> Result<(), String> {
+        let millis = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.sequence += 1;
+
+        let path = self
+            .dir
+            .join(format!("{:016}-{:06}.{}", millis, self.sequence, EXTENSION));
+
+        // written under another name first, a crash must not leave a partial payload behind
+        let tmp_path = path.with_extension(TMP_EXTENSION);
+        let written = match fs::write(&tmp_path, payload).await {
+            Ok(()) => fs::rename(&tmp_path, &path).await,
+            Err(err) => Err(err),
+        };
+        written.map_err(|err| format!("could not write {}: {}", path.display(), err))?;
+
+        self.bytes += payload.len() as u64;
+        self.entries.push_back(QueueEntry {
+            path,
+            created: now,
+            bytes: payload.len() as u64,
+        });
+
+        self.trim().await;
+        self.update_gauges();
+
+        Ok(())
+    }
+
+    /// the oldest payload, payloads which cannot be read are dropped.
+    pub async fn front(&mut self) -> Option<String> {
+        while let Some(entry) = self.entries.front() {
+            match fs::read_to_string(&entry.path).await {
+                Ok(payload) => return Some(payload),
+                Err(err) => {
+                    println!("dropping unreadable {}: {}", entry.path.display(), err);
+                    self.drop_front("unreadable").await;
+                }
+            }
+        }
+
+        None
+    }
+
+    /// removes the oldest payload after it was pushed.
+    pub async fn pop(&mut self) {
+        if let Some(entry) = self.entries.pop_front() {
+            self.remove(entry).await;
+            self.update_gauges();
+        }
+    }
+
+    /// payloads older than `max_age` are dropped, replaying them would be misleading.
+    pub async fn drop_expired(&mut self, now: SystemTime) {
+        while let Some(entry) = self.entries.front() {
+            match now.duration_since(entry.created) {
+                Ok(age) if age > self.max_age => self.drop_front("age").await,
+                _ => break,
+            }
+        }
+
+        self.update_gauges();
+    }
+
+    async fn trim(&mut self) {
+        while self.bytes > self.max_bytes && !self.entries.is_empty() {
+            self.drop_front("size").await;
+        }
+    }
+
+    async fn drop_front(&mut self, reason: &'static str) {
+        if let Some(entry) = self.entries.pop_front() {
+            self.remove(entry).await;
+            METRIC_COUNTER_SERVERDENSITY_DROPPED_PAYLOADS
+                .get_or_create(&vec![("reason", reason)])
+                .inc();
+        }
+    }
+
+    async fn remove(&mut self, entry: QueueEntry) {
+        self.bytes -= entry.bytes;
+        if let Err(err) = fs::remove_file(&entry.path).await {
+            println!("could not remove {}: {}", entry.path.display(), err);
+        }
+    }
+
+    fn update_gauges(&self) {
+        METRIC_GAUGE_SERVERDENSITY_QUEUE_PAYLOADS.set(self.entries.len() as i64);
+        METRIC_GAUGE_SERVERDENSITY_QUEUE_BYTES.set(self.bytes as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::serverdensity::queue::DiskQueue;
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn queue_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("udpagent-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.to_string_lossy().to_string()
+    }
+
+    #[tokio::test]
+    async fn it_replays_payloads_in_order_after_a_restart() {
+        let dir = queue_dir("restart");
+        let max_age = Duration::from_secs(3600 * 24 * 365 * 100);
+
+        let mut queue = DiskQueue::open(&dir, 1024, max_age).await.unwrap();
+        queue
+            .push("a", UNIX_EPOCH + Duration::from_secs(2))
+            .await
+            .unwrap();
+        queue
+            .push("b", UNIX_EPOCH + Duration::from_secs(1))
+            .await
+            .unwrap();
+        queue
+            .push("c", UNIX_EPOCH + Duration::from_secs(3))
+            .await
+            .unwrap();
+        drop(queue);
+
+        // left over from a crash while writing
+        let tmp_path = std::path::Path::new(&dir).join("0000000000000004-000009.tmp");
+        fs::write(&tmp_path, "d").unwrap();
+
+        // ordered by creation time
+        let mut queue = DiskQueue::open(&dir, 1024, max_age).await.unwrap();
+        let mut replayed = vec![];
+        while let Some(payload) = queue.front().await {
+            replayed.push(payload);
+            queue.pop().await;
+        }
+
+        assert_eq!(vec!["b", "a", "c"], replayed);
+        assert_eq!(0, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_drops_the_oldest_payloads_beyond_the_limits() {
+        let dir = queue_dir("limits");
+        let mut queue = DiskQueue::open(&dir, 6, Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        queue
+            .push("aa", now - Duration::from_secs(120))
+            .await
+            .unwrap();
+        queue
+            .push("bb", now - Duration::from_secs(90))
+            .await
+            .unwrap();
+        queue.push("cc", now).await.unwrap();
+        queue.push("dd", now).await.unwrap();
+        assert_eq!(3, queue.len());
+
+        queue.drop_expired(now).await;
+        assert_eq!(2, queue.len());
+        assert_eq!(Some("cc".to_string()), queue.front().await);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_continues_the_sequence_after_a_restart() {
+        let dir = queue_dir("sequence");
+        let max_age = Duration::from_secs(3600 * 24 * 365 * 100);
+        let now = UNIX_EPOCH + Duration::from_secs(1);
+
+        let mut queue = DiskQueue::open(&dir, 1024, max_age).await.unwrap();
+        queue.push("a", now).await.unwrap();
+        queue.push("b", now).await.unwrap();
+        drop(queue);
+
+        // pushed within the same millisecond as the payloads before the restart
+        let mut queue = DiskQueue::open(&dir, 1024, max_age).await.unwrap();
+        queue.push("c", now).await.unwrap();
+        assert_eq!(3, fs::read_dir(&dir).unwrap().count());
+
+        let mut replayed = vec![];
+        while let Some(payload) = queue.front().await {
+            replayed.push(payload);
+            queue.pop().await;
+        }
+        assert_eq!(vec!["a", "b", "c"], replayed);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
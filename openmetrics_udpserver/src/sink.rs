@@ -0,0 +1,447 @@
+use crate::processor::InboundMetric;
+use crate::window::{FlushClock, Window};
+use crate::{METRIC_COUNTER_ERRORS, METRIC_COUNTER_SINK_FLUSH_FAILURES, METRIC_GAUGE_SINK_UP};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use reqwest::{RequestBuilder, StatusCode};
+use std::collections::hash_map::RandomState;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+/// An output of the agent. Every sink receives all metrics which passed the value policy on its
+/// own subscription of the broadcast channel and is flushed at the end of each of its windows.
+pub trait Sink: Send + 'static {
+    /// identifies the sink in logs and self-metrics, the same as its config section.
+    fn name(&self) -> &'static str;
+
+    fn flush_interval(&self) -> Duration;
+
+    /// called once with the first window before any metric is handled.
+    fn start(&mut self, _window: Window) {}
+
+    fn handle(&mut self, metric: InboundMetric);
+
+    /// called at the end of every window, a failed flush marks the sink as down until the next
+    /// flush succeeds.
+    fn flush(&mut self, window: Window) -> impl Future<Output = Result<(), String>> + Send;
+
+    /// called once on shutdown with the window which did not end yet.
+    fn shutdown(&mut self, _window: Window) -> impl Future<Output = Result<(), String>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// The tasks of all enabled sinks, each sink runs in its own task.
+pub struct SinkTasks {
+    align_flush: bool,
+    tasks: JoinSet<(&'static str, i32)>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl SinkTasks {
+    pub fn new(align_flush: bool) -> Self {
+        Self {
+            align_flush,
+            tasks: JoinSet::new(),
+            shutdown: watch::channel(false).0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// spawns the sink, the agent exits with the exit code if the sink fails.
+    pub fn spawn<S: Sink>(&mut self, sink: S, exit_code: i32, sender: &Sender<InboundMetric>) {
+        let name = sink.name();
+        let receiver = sender.subscribe();
+        let shutdown = self.shutdown.subscribe();
+        let align_flush = self.align_flush;
+
+        METRIC_GAUGE_SINK_UP
+            .get_or_create(&vec![("sink", name)])
+            .set(1);
+
+        self.tasks.spawn(async move {
+            // the inner task turns a panic of the sink into a failure of this sink
+            let run = tokio::spawn(run(sink, receiver, shutdown, align_flush));
+            if let Err(e) = run.await {
+                eprintln!("sink {} failed: {}", name, e);
+            }
+
+            (name, exit_code)
+        });
+    }
+
+    /// waits until a sink stops before it was asked to, returns its name and exit code.
+    pub async fn failed(&mut self) -> Option<(&'static str, i32)> {
+        match self.tasks.join_next().await {
+            Some(Ok(failed)) => Some(failed),
+            Some(Err(_)) | None => None,
+        }
+    }
+
+    /// asks all sinks to shut down and waits for them, at most for the timeout.
+    pub async fn shutdown(mut self, timeout: Duration) {
+        let _ = self.shutdown.send(true);
+
+        let all_stopped = async { while self.tasks.join_next().await.is_some() {} };
+        if tokio::time::timeout(timeout, all_stopped).await.is_err() {
+            eprintln!("sinks did not shut down within {:?}", timeout);
+        }
+    }
+}
+
+async fn run<S: Sink>(
+    mut sink: S,
+    mut receiver: Receiver<InboundMetric>,
+    mut shutdown: watch::Receiver<bool>,
+    align_flush: bool,
+) {
+    let name = sink.name();
+    let mut flush_clock = FlushClock::new(name, sink.flush_interval(), align_flush);
+    sink.start(flush_clock.current_window());
+
+    loop {
+        ::tokio::select! {
+            window = flush_clock.tick() => {
+                let result = sink.flush(window).await;
+                report_flush(name, result);
+            },
+            msg = receiver.recv() => {
+                match msg {
+                    Ok(metric) => sink.handle(metric),
+                    Err(RecvError::Closed) => return,
+                    Err(e) => {
+                        METRIC_COUNTER_ERRORS.inc();
+                        eprintln!("{} recv error {:#?}, investigate!", name, e);
+                        ::tokio::time::sleep(Duration::from_millis(300)).await;
+                    }
+                }
+            },
+            _ = shutdown.changed() => break,
+        }
+    }
+
+    let window = Window {
+        start: flush_clock.current_window().start,
+        end: SystemTime::now(),
+    };
+    let result = sink.shutdown(window).await;
+    report_flush(name, result);
+}
+
+fn report_flush(name: &'static str, result: Result<(), String>) {
+    let up = METRIC_GAUGE_SINK_UP.get_or_create(&vec![("sink", name)]);
+    match result {
+        Ok(()) => {
+            up.set(1);
+        }
+        Err(e) => {
+            up.set(0);
+            METRIC_COUNTER_SINK_FLUSH_FAILURES
+                .get_or_create(&vec![("sink", name)])
+                .inc();
+            eprintln!("sink {} failed to flush: {}", name, e);
+        }
+    }
+}
+
+/// Why a request of a sink failed. Network errors, 408, 429 and 5xx may succeed on a retry,
+/// other responses (e.g. a 400 or 401) will not change.
+#[derive(Debug, PartialEq)]
+pub enum SendError {
+    Transient(String),
+    Permanent(StatusCode, String),
+}
+
+impl SendError {
+    pub fn from_response(status: StatusCode, body: &str) -> SendError {
+        let error = format!("{}: {}", status, body.trim());
+        match status {
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => {
+                SendError::Transient(error)
+            }
+            status if status.is_server_error() => SendError::Transient(error),
+            status => SendError::Permanent(status, error),
+        }
+    }
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Transient(e) | SendError::Permanent(_, e) => f.write_str(e),
+        }
+    }
+}
+
+/// Sends the request and counts it by status class (`2xx`, `4xx`, `5xx` or `error`), returns the
+/// body of a successful response.
+pub async fn send_request(
+    request: RequestBuilder,
+    requests: &Family<Vec<(&'static str, String)>, Counter>,
+) -> Result<String, SendError> {
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(err) => {
+            requests
+                .get_or_create(&vec![("status", "error".to_string())])
+                .inc();
+            return Err(SendError::Transient(err.to_string()));
+        }
+    };
+
+    let status = response.status();
+    requests
+        .get_or_create(&vec![("status", format!("{}xx", status.as_u16() / 100))])
+        .inc();
+
+    let body = response.text().await.unwrap_or_default();
+    match status.is_success() {
+        true => Ok(body),
+        false => Err(SendError::from_response(status, &body)),
+    }
+}
+
+/// Retries transient failures up to `max_retries` times. The delay starts at `backoff` and doubles
+/// on every retry, it is jittered so that agents failing at the same time do not retry in
+/// lockstep. No retry is started which would end after the `deadline`.
+pub struct Retry {
+    pub max_retries: u32,
+    pub backoff: Duration,
+    pub deadline: Option<Instant>,
+}
+
+impl Retry {
+    pub async fn run<F, Fut>(&self, mut send: F) -> Result<(), SendError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(), SendError>>,
+    {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let err = match send().await {
+                Err(SendError::Transient(err)) => err,
+                result => return result,
+            };
+
+            let delay = jitter(backoff);
+            let past_deadline = self
+                .deadline
+                .is_some_and(|deadline| Instant::now() + delay >= deadline);
+            if attempt >= self.max_retries || past_deadline {
+                return Err(SendError::Transient(format!(
+                    "giving up after {} attempts: {}",
+                    attempt + 1,
+                    err
+                )));
+            }
+
+            ::tokio::time::sleep(delay).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// the timeout of the next attempt, at most the time left until the deadline but at least a
+    /// second.
+    pub fn timeout(&self, timeout: Duration) -> Duration {
+        let Some(deadline) = self.deadline else {
+            return timeout;
+        };
+
+        let time_left = deadline.saturating_duration_since(Instant::now());
+        timeout.min(time_left).max(Duration::from_secs(1))
+    }
+}
+
+/// between half and the full delay.
+pub fn jitter(delay: Duration) -> Duration {
+    // every RandomState is seeded differently, good enough for spreading retries
+    let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    delay / 2 + delay.mul_f64(random / 2.0)
+}
+
+/// An http endpoint for the tests of the sinks, it answers with the given statuses in order and
+/// with `200` once all are used.
+#[cfg(test)]
+pub mod stub {
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    #[derive(Clone, Default)]
+    pub struct Stub {
+        statuses: Arc<Mutex<VecDeque<StatusCode>>>,
+        requests: Arc<Mutex<Vec<(HeaderMap, Bytes)>>>,
+    }
+
+    impl Stub {
+        pub fn new(statuses: &[StatusCode]) -> Self {
+            Self {
+                statuses: Arc::new(Mutex::new(statuses.iter().copied().collect())),
+                requests: Arc::default(),
+            }
+        }
+
+        /// serves `path` on a free port and returns the url.
+        pub async fn serve(&self, path: &str) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let app = Router::new()
+                .route(path, post(receive))
+                .with_state(self.clone());
+            tokio::spawn(async move { axum::serve(listener, app).await });
+
+            format!("http://{}{}", addr, path)
+        }
+
+        pub fn requests(&self) -> Vec<(HeaderMap, Bytes)> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    async fn receive(State(stub): State<Stub>, headers: HeaderMap, body: Bytes) -> StatusCode {
+        stub.requests.lock().unwrap().push((headers, body));
+        stub.statuses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or(StatusCode::OK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::processor::InboundMetric;
+    use crate::sink::{Retry, SendError, Sink, SinkTasks};
+    use crate::window::Window;
+    use openmetrics_udpserver_lib::MetricType;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Handled(f64),
+        Flushed(Window),
+        ShutDown(Window),
+    }
+
+    /// fails every flush and reports what it was called with.
+    struct FailingSink {
+        events: UnboundedSender<Event>,
+    }
+
+    impl Sink for FailingSink {
+        fn name(&self) -> &'static str {
+            "failing"
+        }
+
+        fn flush_interval(&self) -> Duration {
+            Duration::from_millis(10)
+        }
+
+        fn handle(&mut self, metric: InboundMetric) {
+            self.events.send(Event::Handled(metric.value)).unwrap();
+        }
+
+        async fn flush(&mut self, window: Window) -> Result<(), String> {
+            self.events.send(Event::Flushed(window)).unwrap();
+            Err("unavailable".to_string())
+        }
+
+        async fn shutdown(&mut self, window: Window) -> Result<(), String> {
+            self.events.send(Event::ShutDown(window)).unwrap();
+            Ok(())
+        }
+    }
+
+    async fn next(events: &mut UnboundedReceiver<Event>) -> Event {
+        tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("the sink did not report an event")
+            .expect("the sink stopped")
+    }
+
+    #[tokio::test]
+    async fn it_keeps_running_after_failed_flushes_and_shuts_down() {
+        let (sender, _) = tokio::sync::broadcast::channel(10);
+        let (events, mut receiver) = unbounded_channel();
+        let mut sinks = SinkTasks::new(false);
+        sinks.spawn(FailingSink { events }, 1, &sender);
+
+        sender
+            .send(InboundMetric {
+                name: "foo".to_string(),
+                value: 1.0,
+                labels: vec![],
+                metric_type: MetricType::Sum,
+                member: None,
+            })
+            .unwrap();
+        assert_eq!(Event::Handled(1.0), next(&mut receiver).await);
+
+        // a failed flush does not stop the sink, the next window is flushed as well
+        let Event::Flushed(first) = next(&mut receiver).await else {
+            panic!("expected a flush");
+        };
+        let Event::Flushed(second) = next(&mut receiver).await else {
+            panic!("expected a flush");
+        };
+        assert_eq!(first.end, second.start);
+
+        sinks.shutdown(Duration::from_secs(1)).await;
+        // shutting down ends the window which did not end yet
+        let mut last = second;
+        loop {
+            match next(&mut receiver).await {
+                Event::Flushed(window) => last = window,
+                Event::ShutDown(window) => {
+                    assert_eq!(last.end, window.start);
+                    break;
+                }
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn it_does_not_retry_past_the_deadline() {
+        let retry = Retry {
+            max_retries: 3,
+            backoff: Duration::from_secs(10),
+            deadline: Some(Instant::now() + Duration::from_secs(2)),
+        };
+
+        let attempts = Mutex::new(0);
+        let result = retry
+            .run(|| async {
+                *attempts.lock().unwrap() += 1;
+                Err(SendError::Transient("unavailable".to_string()))
+            })
+            .await;
+
+        assert!(
+            matches!(result, Err(SendError::Transient(_))),
+            "{:?}",
+            result
+        );
+        assert_eq!(1, *attempts.lock().unwrap());
+        assert!(retry.timeout(Duration::from_secs(30)) <= Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::processor::{publish_metric, InboundMetric, MetricLabels};
+use crate::{METRIC_COUNTER_ERRORS, METRIC_COUNTER_STATSD_METRICS};
+use openmetrics_udpserver_lib::MetricType;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast::Sender;
+
+/// The dogstatsd clients use 8k datagrams by default, everything beyond gets truncated.
+const MAX_STATSD_DATAGRAM_SIZE: usize = 8192;
+
+/// Receives the statsd line protocol (including dogstatsd tags) and maps it onto the native metric
+/// types, so that all consumers handle these metrics exactly like native packages.
+pub struct StatsdServer {
+    bind: String,
+    metric_sender: Sender<InboundMetric>,
+}
+
+impl StatsdServer {
+    pub fn new(bind: String, metric_sender: Sender<InboundMetric>) -> Self {
+        StatsdServer {
+            bind,
+            metric_sender,
+        }
+    }
+
+    pub async fn run(&self) {
+        let udp_socket = UdpSocket::bind(&self.bind)
+            .await
+            .expect("Unable to bind StatsD Server");
+        let mut buf = [0; MAX_STATSD_DATAGRAM_SIZE];
+        loop {
+            if let Ok(read_bytes) = udp_socket.recv(&mut buf).await {
+                let datagram = String::from_utf8_lossy(&buf[..read_bytes]);
+                for line in datagram.lines().filter(|line| !line.trim().is_empty()) {
+                    match Self::parse_line(line) {
+                        Ok(inbound_metrics) => {
+                            for inbound_metric in inbound_metrics {
+                                METRIC_COUNTER_STATSD_METRICS.inc();
+                                if let Err(err) =
+                                    publish_metric(&self.metric_sender, inbound_metric)
+                                {
+                                    eprintln!("{}", err);
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            METRIC_COUNTER_ERRORS.inc();
+                            eprintln!("could not parse statsd line '{}': {}", line, err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// parses `name:value[:value...]|type[|@sample_rate][|#tag:value,tag]`.
+    fn parse_line(line: &str) -> Result<Vec<InboundMetric>, String> {
+        let mut sections = line.trim().split('|');
+
+        let (name, values) = sections
+            .next()
+            .and_then(|section| section.split_once(':'))
+            .ok_or_else(|| "missing ':' between name and value".to_string())?;
+
+        if name.is_empty() {
+            return Err("got empty metric name".to_string());
+        }
+
+        let statsd_type = sections
+            .next()
+            .ok_or_else(|| "missing metric type".to_string())?;
+
+        let mut sample_rate = 1.0;
+        let mut labels = MetricLabels::new();
+        for section in sections {
+            if let Some(rate) = section.strip_prefix('@') {
+                sample_rate = rate
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|rate| *rate > 0.0 && *rate <= 1.0)
+                    .ok_or_else(|| format!("invalid sample rate '{}'", rate))?;
+            } else if let Some(tags) = section.strip_prefix('#') {
+                labels = Self::parse_tags(tags);
+            }
+            // other extensions (e.g. container ids, timestamps) are ignored
+        }
+
+        let metric_type = match statsd_type {
+            "c" => MetricType::Sum,
+            // a signed gauge value is relative to the current value
+            "g" if values.starts_with(['+', '-']) => MetricType::Delta,
+            "g" => MetricType::Set,
+            "ms" | "h" | "d" => MetricType::Histogram,
+            "s" => MetricType::Unique,
+            _ => return Err(format!("unsupported metric type '{}'", statsd_type)),
+        };
+
+        // the member of a set is not split, it may contain colons (e.g. an ip v6 address)
+        if metric_type == MetricType::Unique {
+            return Ok(vec![InboundMetric {
+                name: name.to_string(),
+                value: 1.0,
+                labels,
+                metric_type,
+                member: Some(values.to_string()),
+            }]);
+        }
+
+        values
+            .split(':')
+            .map(|value| {
+                let value = value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|value| value.is_finite())
+                    .ok_or_else(|| format!("invalid value '{}'", value))?;
+
+                // only counters can be scaled up, a sampled timing is still a single timing
+                let value = match metric_type {
+                    MetricType::Sum => value / sample_rate,
+                    _ => value,
+                };
+
+                Ok(InboundMetric {
+                    name: name.to_string(),
+                    value,
+                    labels: labels.clone(),
+                    metric_type,
+                    member: None,
+                })
+            })
+            .collect()
+    }
+
+    /// tags without a value (e.g. `#canary`) become labels with the value `true`.
+    fn parse_tags(tags: &str) -> MetricLabels {
+        tags.split(',')
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| match tag.split_once(':') {
+                Some((key, value)) => (key.to_string(), value.to_string()),
+                None => (tag.to_string(), "true".to_string()),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::statsd_server::StatsdServer;
+    use openmetrics_udpserver_lib::MetricType;
+
+    #[test]
+    fn it_parses_counters_with_sample_rate_and_tags() {
+        let metrics = StatsdServer::parse_line("checkout.orders:2|c|@0.5|#shop:de,canary").unwrap();
+
+        assert_eq!(1, metrics.len());
+        assert_eq!("checkout.orders", metrics[0].name);
+        assert_eq!(MetricType::Sum, metrics[0].metric_type);
+        assert_eq!(4.0, metrics[0].value);
+        assert_eq!(
+            vec![
+                ("shop".to_string(), "de".to_string()),
+                ("canary".to_string(), "true".to_string())
+            ],
+            metrics[0].labels
+        );
+    }
+
+    #[test]
+    fn it_parses_timings_and_gauges() {
+        let metrics = StatsdServer::parse_line("request.duration:320.6:10|ms").unwrap();
+        assert_eq!(2, metrics.len());
+        assert_eq!(MetricType::Histogram, metrics[0].metric_type);
+        assert_eq!(320.6, metrics[0].value);
+        assert_eq!(10.0, metrics[1].value);
+
+        let metrics = StatsdServer::parse_line("queue.depth:12|g").unwrap();
+        assert_eq!(MetricType::Set, metrics[0].metric_type);
+        assert_eq!(12.0, metrics[0].value);
+
+        let metrics = StatsdServer::parse_line("connections:-2|g").unwrap();
+        assert_eq!(MetricType::Delta, metrics[0].metric_type);
+        assert_eq!(-2.0, metrics[0].value);
+    }
+
+    #[test]
+    fn it_parses_sets() {
+        let metrics = StatsdServer::parse_line("visitors:2001:db8::1|s|#shop:de").unwrap();
+        assert_eq!(1, metrics.len());
+        assert_eq!(MetricType::Unique, metrics[0].metric_type);
+        assert_eq!(Some("2001:db8::1".to_string()), metrics[0].member);
+    }
+
+    #[test]
+    fn it_rejects_invalid_lines() {
+        assert!(StatsdServer::parse_line("foo").is_err());
+        assert!(StatsdServer::parse_line("foo:1").is_err());
+        assert!(StatsdServer::parse_line(":1|c").is_err());
+        assert!(StatsdServer::parse_line("foo:bar|c").is_err());
+        assert!(StatsdServer::parse_line("foo:1|x").is_err());
+        assert!(StatsdServer::parse_line("foo:1|c|@2").is_err());
+    }
+}
@@ -1,9 +1,11 @@
 use crate::config::Config;
-use crate::processor::InboundMetric;
-use crate::{METRIC_COUNTER_ERRORS, METRIC_COUNTER_UDP_PACKETS};
-use byteorder::BigEndian;
-use byteorder::ByteOrder;
-use openmetrics_udpserver_lib::MetricType;
+use crate::processor::{publish_metric, InboundMetric, MetricLabels};
+use crate::{METRIC_COUNTER_ERRORS, METRIC_COUNTER_UDP_METRICS, METRIC_COUNTER_UDP_PACKETS};
+use bytes::Buf;
+use openmetrics_udpserver_lib::{
+    MetricType, MAX_DATAGRAM_SIZE, PACKAGE_VERSION_BATCH, PACKAGE_VERSION_F64, PACKAGE_VERSION_I64,
+    PACKAGE_VERSION_LABELS, PACKAGE_VERSION_UNIQUE,
+};
 use tokio::net::UdpSocket;
 use tokio::sync::broadcast::Sender;
 
@@ -24,42 +26,273 @@ impl UdpServer {
         let udp_socket = UdpSocket::bind(&self.config.udp_bind)
             .await
             .expect("Unable to bind UDP Server");
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
         loop {
-            let mut buf = [0; 300];
             if let Ok(read_bytes) = udp_socket.recv(&mut buf).await {
-                match self.decode_buffer(&buf, read_bytes) {
-                    Ok(inbound_metric) => {
-                        if let Err(err) = self.metric_sender.send(inbound_metric) {
+                for decoded in self.decode_buffer(&buf, read_bytes) {
+                    match decoded {
+                        Ok(inbound_metric) => {
+                            if let Err(err) = publish_metric(&self.metric_sender, inbound_metric) {
+                                eprintln!("{}", err);
+                            }
+                        }
+                        Err(err) => {
                             METRIC_COUNTER_ERRORS.inc();
-                            eprintln!("Unable to process inbound metric: {}", err);
+                            // it could be, that we are so fast that we read a part of the message, may we need to improve this code.
+                            eprintln!("could not decode message from socket: {}", err);
                         }
                     }
-                    Err(err) => {
-                        METRIC_COUNTER_ERRORS.inc();
-                        // it could be, that we are so fast that we read a part of the message, may we need to improve this code.
-                        eprintln!("could not decode message from socket: {}", err);
-                    }
                 }
             }
         }
     }
 
-    fn decode_buffer(&self, data: &[u8], read_bytes: usize) -> Result<InboundMetric, String> {
-        let metric_type = match MetricType::from_u16(BigEndian::read_u16(&data[0..2])) {
-            Some(m) => m,
-            None => return Err("Got unsupported metric type".to_string()),
-        };
-
-        let count = BigEndian::read_i32(&data[2..6]);
-        let name = String::from_utf8_lossy(&data[6..read_bytes])
-            .to_string()
-            .replace('"', "");
+    /// counts the datagram once, however many packages it contains, and its decoded metrics.
+    fn decode_buffer(&self, data: &[u8], read_bytes: usize) -> Vec<Result<InboundMetric, String>> {
+        let decoded = Self::decode_datagram(&data[..read_bytes]);
 
         METRIC_COUNTER_UDP_PACKETS.inc();
+        METRIC_COUNTER_UDP_METRICS.inc_by(decoded.iter().filter(|d| d.is_ok()).count() as u64);
+        decoded
+    }
+
+    /// decodes a single package or a batch of packages.
+    pub fn decode_datagram(data: &[u8]) -> Vec<Result<InboundMetric, String>> {
+        if data.starts_with(&PACKAGE_VERSION_BATCH.to_be_bytes()) {
+            Self::decode_batch(&data[2..])
+        } else {
+            vec![Self::decode_package(data)]
+        }
+    }
+
+    /// decodes all packages of a batch. a malformed package is returned as error without
+    /// dropping the others, a broken length prefix stops decoding as nothing after it can be trusted.
+    fn decode_batch(mut data: &[u8]) -> Vec<Result<InboundMetric, String>> {
+        let mut decoded = vec![];
+        while data.has_remaining() {
+            if data.remaining() < 2 {
+                decoded.push(Err("Got batch with truncated package length".to_string()));
+                break;
+            }
+
+            let len = data.get_u16() as usize;
+            if data.remaining() < len {
+                decoded.push(Err("Got batch with truncated package".to_string()));
+                break;
+            }
+
+            decoded.push(Self::decode_package(&data[..len]));
+            data.advance(len);
+        }
+
+        decoded
+    }
+
+    fn decode_package(mut data: &[u8]) -> Result<InboundMetric, String> {
+        if data.remaining() < 6 {
+            return Err("Got package with less than 6 bytes".to_string());
+        }
+
+        match data.get_u16() {
+            PACKAGE_VERSION_LABELS => {
+                Self::decode_labeled_package(data, 4, |data| data.get_i32() as f64)
+            }
+            PACKAGE_VERSION_I64 => {
+                Self::decode_labeled_package(data, 8, |data| data.get_i64() as f64)
+            }
+            PACKAGE_VERSION_F64 => Self::decode_labeled_package(data, 8, |data| data.get_f64()),
+            PACKAGE_VERSION_UNIQUE => Self::decode_unique_package(data),
+            metric_type => {
+                let metric_type = Self::decode_metric_type(metric_type)?;
+                let value = data.get_i32() as f64;
+                let name = String::from_utf8_lossy(data).to_string();
+
+                Ok(InboundMetric {
+                    value,
+                    name,
+                    labels: vec![],
+                    metric_type,
+                    member: None,
+                })
+            }
+        }
+    }
+
+    /// decodes the labeled packages, they only differ in the size and encoding of the value.
+    fn decode_labeled_package(
+        mut data: &[u8],
+        value_size: usize,
+        decode_value: impl Fn(&mut &[u8]) -> f64,
+    ) -> Result<InboundMetric, String> {
+        if data.remaining() < 2 + value_size {
+            return Err("Got labeled package without type and value".to_string());
+        }
+
+        let metric_type = Self::decode_metric_type(data.get_u16())?;
+        let value = decode_value(&mut data);
+        if !value.is_finite() {
+            return Err("Got labeled package with non finite value".to_string());
+        }
+
+        let name = Self::decode_str(&mut data)?;
+        let labels = Self::decode_labels(&mut data)?;
+
         Ok(InboundMetric {
-            count,
+            value,
             name,
+            labels,
             metric_type,
+            member: None,
+        })
+    }
+
+    fn decode_unique_package(mut data: &[u8]) -> Result<InboundMetric, String> {
+        let name = Self::decode_str(&mut data)?;
+        let member = Self::decode_str(&mut data)?;
+        let labels = Self::decode_labels(&mut data)?;
+
+        Ok(InboundMetric {
+            value: 1.0,
+            name,
+            labels,
+            metric_type: MetricType::Unique,
+            member: Some(member),
         })
     }
+
+    fn decode_labels(data: &mut &[u8]) -> Result<MetricLabels, String> {
+        if !data.has_remaining() {
+            return Err("Got labeled package without label count".to_string());
+        }
+
+        let label_count = data.get_u8();
+        let mut labels = Vec::with_capacity(label_count as usize);
+        for _ in 0..label_count {
+            let key = Self::decode_str(data)?;
+            let value = Self::decode_str(data)?;
+            labels.push((key, value));
+        }
+
+        Ok(labels)
+    }
+
+    fn decode_metric_type(metric_type: u16) -> Result<MetricType, String> {
+        MetricType::from_u16(metric_type).ok_or_else(|| "Got unsupported metric type".to_string())
+    }
+
+    fn decode_str(data: &mut &[u8]) -> Result<String, String> {
+        if data.remaining() < 2 {
+            return Err("Got package with truncated string length".to_string());
+        }
+
+        let len = data.get_u16() as usize;
+        if data.remaining() < len {
+            return Err("Got package with truncated string".to_string());
+        }
+
+        let value = String::from_utf8_lossy(&data[..len]).to_string();
+        data.advance(len);
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::udp_server::UdpServer;
+    use openmetrics_udpserver_lib::{
+        create_package_f64, create_package_i64, create_package_peak,
+        create_package_sum_with_labels, create_package_unique, MetricType, PackageBatch,
+    };
+
+    #[test]
+    fn it_decodes_legacy_packages() {
+        let package = create_package_peak("foo.bar", -5).unwrap();
+        let metric = UdpServer::decode_package(&package).unwrap();
+
+        assert_eq!(MetricType::Peak, metric.metric_type);
+        assert_eq!("foo.bar", metric.name);
+        assert_eq!(-5.0, metric.value);
+        assert!(metric.labels.is_empty());
+    }
+
+    #[test]
+    fn it_decodes_labeled_packages() {
+        let package =
+            create_package_sum_with_labels("foo", 7, &[("tenant", "a\"b"), ("status", "200")])
+                .unwrap();
+        let metric = UdpServer::decode_package(&package).unwrap();
+
+        assert_eq!(MetricType::Sum, metric.metric_type);
+        assert_eq!("foo", metric.name);
+        assert_eq!(7.0, metric.value);
+        assert_eq!(
+            vec![
+                ("tenant".to_string(), "a\"b".to_string()),
+                ("status".to_string(), "200".to_string())
+            ],
+            metric.labels
+        );
+    }
+
+    #[test]
+    fn it_decodes_i64_and_f64_packages() {
+        let package =
+            create_package_i64(MetricType::Sum, "bytes", 5_000_000_000, &[("disk", "sda")])
+                .unwrap();
+        let metric = UdpServer::decode_package(&package).unwrap();
+        assert_eq!(MetricType::Sum, metric.metric_type);
+        assert_eq!(5_000_000_000.0, metric.value);
+        assert_eq!(vec![("disk".to_string(), "sda".to_string())], metric.labels);
+
+        let package =
+            create_package_f64::<_, &str, &str>(MetricType::Average, "ratio", 0.75, &[]).unwrap();
+        let metric = UdpServer::decode_package(&package).unwrap();
+        assert_eq!(MetricType::Average, metric.metric_type);
+        assert_eq!("ratio", metric.name);
+        assert_eq!(0.75, metric.value);
+
+        assert!(UdpServer::decode_package(&package[..9]).is_err());
+    }
+
+    #[test]
+    fn it_decodes_unique_packages() {
+        let package = create_package_unique("visitors", "user-42", &[("shop", "de")]).unwrap();
+        let metric = UdpServer::decode_package(&package).unwrap();
+
+        assert_eq!(MetricType::Unique, metric.metric_type);
+        assert_eq!("visitors", metric.name);
+        assert_eq!(Some("user-42".to_string()), metric.member);
+        assert_eq!(vec![("shop".to_string(), "de".to_string())], metric.labels);
+        assert!(metric.validate().is_ok());
+    }
+
+    #[test]
+    fn it_rejects_truncated_packages() {
+        let package = create_package_sum_with_labels("foo", 7, &[("tenant", "a")]).unwrap();
+
+        assert!(UdpServer::decode_package(&package[..package.len() - 1]).is_err());
+        assert!(UdpServer::decode_package(&package[..3]).is_err());
+    }
+
+    #[test]
+    fn it_decodes_batches_and_keeps_valid_packages() {
+        let mut batch = PackageBatch::new();
+        batch.add(MetricType::Sum, "foo", 1).unwrap();
+        batch
+            .add_with_labels(MetricType::Min, "bar", 2, &[("a", "b")])
+            .unwrap();
+        batch.add_package(&[0, 99, 0, 0, 0, 1, b'x']).unwrap();
+        batch.add(MetricType::Peak, "baz", 3).unwrap();
+        let mut datagram = batch.finish().remove(0);
+        // a trailing package which is cut off
+        datagram.extend_from_slice(&[0, 10, 0, 42]);
+
+        let decoded = UdpServer::decode_batch(&datagram[2..]);
+        assert_eq!(5, decoded.len());
+        assert_eq!("foo", decoded[0].as_ref().unwrap().name);
+        assert_eq!("bar", decoded[1].as_ref().unwrap().name);
+        assert!(decoded[2].is_err());
+        assert_eq!("baz", decoded[3].as_ref().unwrap().name);
+        assert!(decoded[4].is_err());
+    }
 }
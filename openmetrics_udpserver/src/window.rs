@@ -0,0 +1,142 @@
+use crate::{METRIC_GAUGE_WINDOW_END, METRIC_GAUGE_WINDOW_START};
+use std::pin::Pin;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{Instant, Sleep};
+
+/// A flush window, the values flushed at `end` were collected since `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+/// Decides when a pipeline flushes. Windows are contiguous, if aligned they end at multiples of the
+/// interval since the epoch, so that agents on different hosts produce the same windows.
+///
+/// The current window is exposed as `udpagent_window_start_seconds` / `udpagent_window_end_seconds`
+/// labeled with the pipeline.
+pub struct FlushClock {
+    pipeline: &'static str,
+    interval: Duration,
+    aligned: bool,
+    current: Window,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl FlushClock {
+    pub fn new(pipeline: &'static str, interval: Duration, aligned: bool) -> Self {
+        let now = SystemTime::now();
+        let mut clock = Self {
+            pipeline,
+            interval,
+            aligned,
+            current: Window {
+                start: now,
+                end: now,
+            },
+            sleep: Box::pin(tokio::time::sleep(Duration::ZERO)),
+        };
+
+        clock.start_window(now, now);
+        clock
+    }
+
+    pub fn current_window(&self) -> Window {
+        self.current
+    }
+
+    /// waits until the current window ends and returns it, the next window starts right away.
+    /// cancel safe, the clock only advances once the window is over.
+    pub async fn tick(&mut self) -> Window {
+        (&mut self.sleep).await;
+
+        let flushed = self.current;
+        self.start_window(flushed.end, SystemTime::now());
+        flushed
+    }
+
+    fn start_window(&mut self, start: SystemTime, now: SystemTime) {
+        // if we are late (e.g. the host was suspended) the next window is computed from now
+        let from = now.max(start);
+        let end = if self.aligned {
+            align_up(from, self.interval)
+        } else {
+            from + self.interval
+        };
+
+        self.current = Window { start, end };
+        self.sleep
+            .as_mut()
+            .reset(Instant::now() + end.duration_since(now).unwrap_or_default());
+
+        METRIC_GAUGE_WINDOW_START
+            .get_or_create(&vec![("pipeline", self.pipeline)])
+            .set(unix_seconds(start) as i64);
+        METRIC_GAUGE_WINDOW_END
+            .get_or_create(&vec![("pipeline", self.pipeline)])
+            .set(unix_seconds(end) as i64);
+    }
+}
+
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// the first multiple of the interval since the epoch which is strictly after the given time.
+fn align_up(time: SystemTime, interval: Duration) -> SystemTime {
+    let interval_nanos = interval.as_nanos().max(1);
+    let since_epoch = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let aligned = (since_epoch / interval_nanos + 1) * interval_nanos;
+
+    UNIX_EPOCH + Duration::from_nanos(aligned as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::window::{align_up, FlushClock};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn it_aligns_to_multiples_of_the_interval() {
+        let interval = Duration::from_secs(30);
+
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_secs(1_700_000_010),
+            align_up(
+                UNIX_EPOCH + Duration::from_millis(1_699_999_995_500),
+                interval
+            )
+        );
+        // a boundary itself belongs to the next window
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_secs(1_700_000_040),
+            align_up(UNIX_EPOCH + Duration::from_secs(1_700_000_010), interval)
+        );
+    }
+
+    #[tokio::test]
+    async fn it_produces_contiguous_windows() {
+        let mut clock = FlushClock::new("test", Duration::from_millis(20), true);
+        let first = clock.current_window();
+        assert_eq!(
+            0,
+            first.end.duration_since(UNIX_EPOCH).unwrap().as_millis() % 20
+        );
+
+        let flushed = clock.tick().await;
+        assert_eq!(first, flushed);
+
+        let second = clock.current_window();
+        assert_eq!(flushed.end, second.start);
+        assert!(second.end > second.start);
+        assert_eq!(
+            0,
+            second.end.duration_since(UNIX_EPOCH).unwrap().as_millis() % 20
+        );
+    }
+}
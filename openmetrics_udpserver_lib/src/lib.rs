@@ -1,18 +1,55 @@
 use bytes::{BufMut, BytesMut};
 use thiserror::Error;
 
+/// Marker in the first two bytes of a package which carries labels. It is chosen outside of the
+/// range of the [`MetricType`] ids, so that legacy packages keep being decoded as before.
+pub const PACKAGE_VERSION_LABELS: u16 = 1;
+
+/// Marker in the first two bytes of a datagram which carries multiple packages, see [`PackageBatch`].
+pub const PACKAGE_VERSION_BATCH: u16 = 2;
+
+/// Marker in the first two bytes of a labeled package which carries an `i64` value.
+pub const PACKAGE_VERSION_I64: u16 = 3;
+
+/// Marker in the first two bytes of a labeled package which carries an `f64` value.
+pub const PACKAGE_VERSION_F64: u16 = 4;
+
+/// Marker in the first two bytes of a package of the [`MetricType::Unique`] type, which carries a
+/// member instead of a value.
+pub const PACKAGE_VERSION_UNIQUE: u16 = 5;
+
+/// The maximum size of a single encoded package.
+pub const MAX_PACKAGE_SIZE: usize = 300;
+
+/// The maximum size of a datagram, the udp server does not read beyond it. It fits into the
+/// usual ethernet MTU to avoid fragmentation.
+pub const MAX_DATAGRAM_SIZE: usize = 1400;
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum MetricType {
     Sum,
     Average,
     Peak,
     Min,
+    Histogram,
+    /// the last value of a window wins, e.g. a queue depth.
+    Set,
+    /// a signed increment of a gauge which persists over windows, e.g. open connections.
+    Delta,
+    /// the number of distinct members of a window, e.g. unique users.
+    Unique,
+    /// a duration, each window derives count, sum, min, max and mean from the same samples.
+    Timing,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Error)]
 pub enum EncodeError {
     #[error("buffer size must be smaller than 300 bytes, got {0} bytes")]
     BufferTooLarge(usize),
+    #[error("a package can carry at most 255 labels, got {0} labels")]
+    TooManyLabels(usize),
+    #[error("values must be finite")]
+    NonFiniteValue,
 }
 
 impl MetricType {
@@ -22,6 +59,11 @@ impl MetricType {
             43 => Some(MetricType::Average),
             44 => Some(MetricType::Peak),
             45 => Some(MetricType::Min),
+            46 => Some(MetricType::Histogram),
+            47 => Some(MetricType::Set),
+            48 => Some(MetricType::Delta),
+            49 => Some(MetricType::Unique),
+            50 => Some(MetricType::Timing),
             _ => None,
         }
     }
@@ -32,6 +74,11 @@ impl MetricType {
             Self::Average => 43,
             Self::Peak => 44,
             Self::Min => 45,
+            Self::Histogram => 46,
+            Self::Set => 47,
+            Self::Delta => 48,
+            Self::Unique => 49,
+            Self::Timing => 50,
         }
     }
 }
@@ -49,13 +96,177 @@ where
     buf.put_i32(count);
     buf.put_slice(name.as_ref().as_bytes());
 
-    if buf.len() > 300 {
+    if buf.len() > MAX_PACKAGE_SIZE {
         return Err(EncodeError::BufferTooLarge(buf.len()));
     }
 
     Ok(buf.to_vec())
 }
 
+/// Creates a package which carries a set of label pairs next to the name.
+///
+/// Layout (big endian): `u16 marker | u16 type | i32 count | u16 len | name | u8 label count`,
+/// followed by `u16 len | key | u16 len | value` for each label.
+pub fn create_package_with_labels<S, K, V>(
+    metric_type: MetricType,
+    name: S,
+    count: i32,
+    labels: &[(K, V)],
+) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    encode_labeled_package(
+        PACKAGE_VERSION_LABELS,
+        metric_type,
+        &count.to_be_bytes(),
+        name.as_ref(),
+        labels,
+    )
+}
+
+/// Creates a labeled package with an `i64` value, for values which do not fit into an `i32`.
+///
+/// Layout (big endian): like [`create_package_with_labels`], but with its own marker and an `i64`
+/// value instead of the `i32` count.
+pub fn create_package_i64<S, K, V>(
+    metric_type: MetricType,
+    name: S,
+    value: i64,
+    labels: &[(K, V)],
+) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    encode_labeled_package(
+        PACKAGE_VERSION_I64,
+        metric_type,
+        &value.to_be_bytes(),
+        name.as_ref(),
+        labels,
+    )
+}
+
+/// Creates a labeled package with an `f64` value, e.g. for durations in fractional seconds or
+/// ratios.
+///
+/// Layout (big endian): like [`create_package_with_labels`], but with its own marker and an `f64`
+/// value instead of the `i32` count.
+pub fn create_package_f64<S, K, V>(
+    metric_type: MetricType,
+    name: S,
+    value: f64,
+    labels: &[(K, V)],
+) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    if !value.is_finite() {
+        return Err(EncodeError::NonFiniteValue);
+    }
+
+    encode_labeled_package(
+        PACKAGE_VERSION_F64,
+        metric_type,
+        &value.to_be_bytes(),
+        name.as_ref(),
+        labels,
+    )
+}
+
+/// Creates a package which counts the member (e.g. a user id) towards the distinct members of the
+/// metric. The member itself never leaves the agent.
+///
+/// Layout (big endian): `u16 marker | u16 len | name | u16 len | member | u8 label count`, followed
+/// by the labels like in [`create_package_with_labels`].
+pub fn create_package_unique<S, M, K, V>(
+    name: S,
+    member: M,
+    labels: &[(K, V)],
+) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+    M: AsRef<str>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    if labels.len() > u8::MAX as usize {
+        return Err(EncodeError::TooManyLabels(labels.len()));
+    }
+
+    let mut buf = BytesMut::new();
+    buf.put_u16(PACKAGE_VERSION_UNIQUE);
+    put_str(&mut buf, name.as_ref())?;
+    put_str(&mut buf, member.as_ref())?;
+    put_labels(&mut buf, labels)?;
+
+    if buf.len() > MAX_PACKAGE_SIZE {
+        return Err(EncodeError::BufferTooLarge(buf.len()));
+    }
+
+    Ok(buf.to_vec())
+}
+
+fn encode_labeled_package<K, V>(
+    marker: u16,
+    metric_type: MetricType,
+    value: &[u8],
+    name: &str,
+    labels: &[(K, V)],
+) -> Result<Vec<u8>, EncodeError>
+where
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    if labels.len() > u8::MAX as usize {
+        return Err(EncodeError::TooManyLabels(labels.len()));
+    }
+
+    let mut buf = BytesMut::new();
+    buf.put_u16(marker);
+    buf.put_u16(metric_type.to_u16());
+    buf.put_slice(value);
+    put_str(&mut buf, name)?;
+    put_labels(&mut buf, labels)?;
+
+    if buf.len() > MAX_PACKAGE_SIZE {
+        return Err(EncodeError::BufferTooLarge(buf.len()));
+    }
+
+    Ok(buf.to_vec())
+}
+
+fn put_labels<K, V>(buf: &mut BytesMut, labels: &[(K, V)]) -> Result<(), EncodeError>
+where
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    buf.put_u8(labels.len() as u8);
+    for (key, value) in labels {
+        put_str(buf, key.as_ref())?;
+        put_str(buf, value.as_ref())?;
+    }
+
+    Ok(())
+}
+
+fn put_str(buf: &mut BytesMut, value: &str) -> Result<(), EncodeError> {
+    // everything longer than the package limit is rejected anyway, so no need to encode it
+    if value.len() > MAX_PACKAGE_SIZE {
+        return Err(EncodeError::BufferTooLarge(buf.len() + value.len()));
+    }
+
+    buf.put_u16(value.len() as u16);
+    buf.put_slice(value.as_bytes());
+    Ok(())
+}
+
 pub fn create_package_sum<S>(name: S, count: i32) -> Result<Vec<u8>, EncodeError>
 where
     S: AsRef<str>,
@@ -83,3 +294,351 @@ where
 {
     create_package(MetricType::Average, name, count)
 }
+
+pub fn create_package_histogram<S>(name: S, count: i32) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+{
+    create_package(MetricType::Histogram, name, count)
+}
+
+pub fn create_package_set<S>(name: S, count: i32) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+{
+    create_package(MetricType::Set, name, count)
+}
+
+pub fn create_package_delta<S>(name: S, count: i32) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+{
+    create_package(MetricType::Delta, name, count)
+}
+
+pub fn create_package_timing<S>(name: S, count: i32) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+{
+    create_package(MetricType::Timing, name, count)
+}
+
+pub fn create_package_sum_with_labels<S, K, V>(
+    name: S,
+    count: i32,
+    labels: &[(K, V)],
+) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    create_package_with_labels(MetricType::Sum, name, count, labels)
+}
+
+pub fn create_package_min_with_labels<S, K, V>(
+    name: S,
+    count: i32,
+    labels: &[(K, V)],
+) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    create_package_with_labels(MetricType::Min, name, count, labels)
+}
+
+pub fn create_package_peak_with_labels<S, K, V>(
+    name: S,
+    count: i32,
+    labels: &[(K, V)],
+) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    create_package_with_labels(MetricType::Peak, name, count, labels)
+}
+
+pub fn create_package_average_with_labels<S, K, V>(
+    name: S,
+    count: i32,
+    labels: &[(K, V)],
+) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    create_package_with_labels(MetricType::Average, name, count, labels)
+}
+
+pub fn create_package_histogram_with_labels<S, K, V>(
+    name: S,
+    count: i32,
+    labels: &[(K, V)],
+) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    create_package_with_labels(MetricType::Histogram, name, count, labels)
+}
+
+pub fn create_package_set_with_labels<S, K, V>(
+    name: S,
+    count: i32,
+    labels: &[(K, V)],
+) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    create_package_with_labels(MetricType::Set, name, count, labels)
+}
+
+pub fn create_package_delta_with_labels<S, K, V>(
+    name: S,
+    count: i32,
+    labels: &[(K, V)],
+) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    create_package_with_labels(MetricType::Delta, name, count, labels)
+}
+
+pub fn create_package_timing_with_labels<S, K, V>(
+    name: S,
+    count: i32,
+    labels: &[(K, V)],
+) -> Result<Vec<u8>, EncodeError>
+where
+    S: AsRef<str>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    create_package_with_labels(MetricType::Timing, name, count, labels)
+}
+
+/// Collects packages into as few datagrams as possible.
+///
+/// Layout (big endian): `u16 marker`, followed by `u16 len | package` for each package, where each
+/// package is encoded exactly like a package which is sent on its own.
+pub struct PackageBatch {
+    max_size: usize,
+    current: BytesMut,
+    finished: Vec<Vec<u8>>,
+}
+
+impl Default for PackageBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PackageBatch {
+    pub fn new() -> Self {
+        Self::with_max_size(MAX_DATAGRAM_SIZE)
+    }
+
+    pub fn with_max_size(max_size: usize) -> Self {
+        Self {
+            max_size,
+            current: BytesMut::new(),
+            finished: vec![],
+        }
+    }
+
+    pub fn add<S>(
+        &mut self,
+        metric_type: MetricType,
+        name: S,
+        count: i32,
+    ) -> Result<(), EncodeError>
+    where
+        S: AsRef<str>,
+    {
+        self.add_package(&create_package(metric_type, name, count)?)
+    }
+
+    pub fn add_with_labels<S, K, V>(
+        &mut self,
+        metric_type: MetricType,
+        name: S,
+        count: i32,
+        labels: &[(K, V)],
+    ) -> Result<(), EncodeError>
+    where
+        S: AsRef<str>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.add_package(&create_package_with_labels(
+            metric_type,
+            name,
+            count,
+            labels,
+        )?)
+    }
+
+    pub fn add_i64<S, K, V>(
+        &mut self,
+        metric_type: MetricType,
+        name: S,
+        value: i64,
+        labels: &[(K, V)],
+    ) -> Result<(), EncodeError>
+    where
+        S: AsRef<str>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.add_package(&create_package_i64(metric_type, name, value, labels)?)
+    }
+
+    pub fn add_f64<S, K, V>(
+        &mut self,
+        metric_type: MetricType,
+        name: S,
+        value: f64,
+        labels: &[(K, V)],
+    ) -> Result<(), EncodeError>
+    where
+        S: AsRef<str>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.add_package(&create_package_f64(metric_type, name, value, labels)?)
+    }
+
+    pub fn add_unique<S, M, K, V>(
+        &mut self,
+        name: S,
+        member: M,
+        labels: &[(K, V)],
+    ) -> Result<(), EncodeError>
+    where
+        S: AsRef<str>,
+        M: AsRef<str>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.add_package(&create_package_unique(name, member, labels)?)
+    }
+
+    /// adds an already encoded package, a new datagram is started if it does not fit anymore.
+    pub fn add_package(&mut self, package: &[u8]) -> Result<(), EncodeError> {
+        // marker + length prefix
+        if package.len() + 4 > self.max_size {
+            return Err(EncodeError::BufferTooLarge(package.len()));
+        }
+
+        if self.current.len() + package.len() + 2 > self.max_size {
+            self.finished.push(self.current.split().to_vec());
+        }
+
+        if self.current.is_empty() {
+            self.current.put_u16(PACKAGE_VERSION_BATCH);
+        }
+
+        self.current.put_u16(package.len() as u16);
+        self.current.put_slice(package);
+        Ok(())
+    }
+
+    /// returns the encoded datagrams, each of them fits into `max_size`.
+    pub fn finish(mut self) -> Vec<Vec<u8>> {
+        if !self.current.is_empty() {
+            self.finished.push(self.current.to_vec());
+        }
+
+        self.finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        create_package_f64, create_package_sum, create_package_sum_with_labels, EncodeError,
+        MetricType, PackageBatch, PACKAGE_VERSION_BATCH, PACKAGE_VERSION_F64,
+        PACKAGE_VERSION_LABELS,
+    };
+
+    #[test]
+    fn it_encodes_labels() {
+        let package = create_package_sum_with_labels("foo", 3, &[("a", "bc")]).unwrap();
+
+        let mut expected = vec![];
+        expected.extend_from_slice(&PACKAGE_VERSION_LABELS.to_be_bytes());
+        expected.extend_from_slice(&42u16.to_be_bytes());
+        expected.extend_from_slice(&3i32.to_be_bytes());
+        expected.extend_from_slice(&[0, 3, b'f', b'o', b'o', 1]);
+        expected.extend_from_slice(&[0, 1, b'a', 0, 2, b'b', b'c']);
+        assert_eq!(expected, package);
+    }
+
+    #[test]
+    fn it_encodes_f64_values() {
+        let package = create_package_f64(MetricType::Average, "foo", 0.25, &[("a", "b")]).unwrap();
+
+        let mut expected = vec![];
+        expected.extend_from_slice(&PACKAGE_VERSION_F64.to_be_bytes());
+        expected.extend_from_slice(&43u16.to_be_bytes());
+        expected.extend_from_slice(&0.25f64.to_be_bytes());
+        expected.extend_from_slice(&[0, 3, b'f', b'o', b'o', 1]);
+        expected.extend_from_slice(&[0, 1, b'a', 0, 1, b'b']);
+        assert_eq!(expected, package);
+
+        assert_eq!(
+            Err(EncodeError::NonFiniteValue),
+            create_package_f64::<_, &str, &str>(MetricType::Average, "foo", f64::NAN, &[])
+        );
+    }
+
+    #[test]
+    fn it_rejects_oversized_packages() {
+        let value = "x".repeat(200);
+        let result = create_package_sum_with_labels("foo", 3, &[("a", &value), ("b", &value)]);
+        assert!(matches!(result, Err(EncodeError::BufferTooLarge(_))));
+    }
+
+    #[test]
+    fn it_batches_packages() {
+        let mut batch = PackageBatch::with_max_size(30);
+        for _ in 0..3 {
+            batch.add(MetricType::Sum, "foo", 1).unwrap();
+        }
+
+        let package = create_package_sum("foo", 1).unwrap();
+        let mut record = (package.len() as u16).to_be_bytes().to_vec();
+        record.extend_from_slice(&package);
+
+        let datagrams = batch.finish();
+        assert_eq!(2, datagrams.len());
+        assert_eq!(
+            [&PACKAGE_VERSION_BATCH.to_be_bytes()[..], &record, &record].concat(),
+            datagrams[0]
+        );
+        assert_eq!(
+            [&PACKAGE_VERSION_BATCH.to_be_bytes()[..], &record].concat(),
+            datagrams[1]
+        );
+    }
+
+    #[test]
+    fn it_rejects_packages_larger_than_the_batch() {
+        let mut batch = PackageBatch::with_max_size(10);
+        assert_eq!(
+            Err(EncodeError::BufferTooLarge(9)),
+            batch.add(MetricType::Sum, "foo", 1)
+        );
+        assert!(batch.finish().is_empty());
+    }
+}